@@ -0,0 +1,192 @@
+//! ACPI tables, found through the RSDP the bootloader hands over.
+
+use core::ops::Deref;
+
+use crate::{
+    arch::x86::{paging::MapError, pat::CacheMode},
+    memory::{self, io::{ioremap, IoMapping}},
+};
+
+pub mod madt;
+
+/// Root System Description Pointer. The fields after `rsdt_paddr` were
+/// added in ACPI 2.0 and are only there if `revision` is at least 2.
+#[repr(C, packed)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_paddr: u32,
+    pub length: u32,
+    pub xsdt_paddr: u64,
+    pub extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl Rsdp {
+    const SIGNATURE: &[u8; 8] = b"RSD PTR ";
+    /// Size of the ACPI 1.0 part.
+    const V1_SIZE: usize = 20;
+
+    fn is_valid(&self) -> bool {
+        if self.signature != *Self::SIGNATURE {
+            return false;
+        }
+
+        let len = if self.revision >= 2 { self.length as usize } else { Self::V1_SIZE };
+        let bytes = unsafe { core::slice::from_raw_parts((self as *const Self).cast::<u8>(), len) };
+        checksum(bytes)
+    }
+}
+
+/// The header every table but the RSDP starts with.
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NotInitialized,
+    NotFound,
+    BadChecksum,
+    Map(MapError),
+}
+
+/// Where the table of tables is, and how wide its entries are.
+#[derive(Copy, Clone)]
+struct Root {
+    paddr: u64,
+    /// 4 for the RSDT, 8 for the XSDT.
+    entry_size: usize,
+}
+
+static mut ROOT: Option<Root> = None;
+
+/// Somewhere to read [`len`] bytes at [`paddr`] from: the direct map if it
+/// covers them, as it does for tables in RAM, and otherwise a new mapping,
+/// which is returned too so that it lives long enough.
+fn access(paddr: u64, len: u64) -> Result<(*const u8, Option<IoMapping>), AcpiError> {
+    if let Some(ptr) = memory::phys_to_virt_mapped(paddr, len) {
+        return Ok((ptr, None));
+    }
+
+    let mapping = ioremap(paddr, len, CacheMode::WRITE_BACK).map_err(AcpiError::Map)?;
+    Ok((mapping.as_ptr(), Some(mapping)))
+}
+
+/// A copy of the header of the table at [`paddr`].
+fn peek(paddr: u64) -> Result<SdtHeader, AcpiError> {
+    let (header, _mapping) = access(paddr, size_of::<SdtHeader>() as u64)?;
+    Ok(unsafe { header.cast::<SdtHeader>().read_unaligned() })
+}
+
+/// A table, mapped for as long as this lives.
+pub struct AcpiTable {
+    ptr: *const u8,
+    len: usize,
+    /// Only for tables outside the direct map.
+    _mapping: Option<IoMapping>,
+}
+
+impl AcpiTable {
+    /// Maps the table at [`paddr`], checking its checksum.
+    fn map(paddr: u64) -> Result<Self, AcpiError> {
+        let len = peek(paddr)?.length;
+        let (ptr, mapping) = access(paddr, len as u64)?;
+        let table = Self { ptr, len: len as usize, _mapping: mapping };
+        if !checksum(table.bytes()) {
+            return Err(AcpiError::BadChecksum);
+        }
+
+        Ok(table)
+    }
+
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// What follows the header.
+    pub fn body(&self) -> &[u8] {
+        &self.bytes()[size_of::<SdtHeader>()..]
+    }
+}
+
+impl Deref for AcpiTable {
+    type Target = SdtHeader;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr.cast::<SdtHeader>() }
+    }
+}
+
+/// Every byte of an ACPI structure, checksum included, sums to 0.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Little-endian integers at byte offsets into a table, which are rarely
+/// aligned.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Remembers where the tables are. Prefers the XSDT, which has 64-bit
+/// addresses, when the RSDP is new enough to have one.
+///
+/// Safety: must be called once, before [`find_table`].
+pub unsafe fn init(rsdp: &Rsdp) -> Result<(), AcpiError> {
+    if !rsdp.is_valid() {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_paddr != 0 {
+        Root { paddr: rsdp.xsdt_paddr, entry_size: 8 }
+    } else {
+        Root { paddr: rsdp.rsdt_paddr as u64, entry_size: 4 }
+    };
+
+    unsafe { ROOT = Some(root) };
+    Ok(())
+}
+
+/// Finds and maps the first table with [`signature`]. Needs the kernel's
+/// address space to be set up, since tables are mapped on demand.
+pub fn find_table(signature: &[u8; 4]) -> Result<AcpiTable, AcpiError> {
+    let root = unsafe { ROOT }.ok_or(AcpiError::NotInitialized)?;
+    let root_table = AcpiTable::map(root.paddr)?;
+
+    for entry in root_table.body().chunks_exact(root.entry_size) {
+        let paddr = match root.entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0) as u64,
+        };
+
+        // peek at the header before mapping and checksumming the whole thing
+        if peek(paddr)?.signature != *signature {
+            continue;
+        }
+
+        return AcpiTable::map(paddr);
+    }
+
+    Err(AcpiError::NotFound)
+}
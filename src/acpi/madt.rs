@@ -0,0 +1,132 @@
+//! The Multiple APIC Description Table, which lists the CPUs and interrupt
+//! controllers.
+
+use super::{find_table, read_u16, read_u32, read_u64, AcpiError, AcpiTable};
+
+const SIGNATURE: &[u8; 4] = b"APIC";
+
+/// Local APIC address and flags come before the entries.
+const ENTRIES_OFFSET: usize = 8;
+
+const TYPE_LOCAL_APIC: u8 = 0;
+const TYPE_IO_APIC: u8 = 1;
+const TYPE_INTERRUPT_OVERRIDE: u8 = 2;
+const TYPE_LOCAL_APIC_NMI: u8 = 4;
+const TYPE_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const TYPE_LOCAL_X2APIC: u8 = 9;
+
+/// The CPU can be started.
+pub const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+/// The CPU is off, but could be hot-plugged and started later.
+pub const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+pub enum MadtEntry {
+    LocalApic {
+        processor_uid: u32,
+        apic_id: u32,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        paddr: u64,
+        gsi_base: u32,
+    },
+    /// ISA IRQ [`source`] is wired to GSI [`gsi`] instead of the IRQ of the
+    /// same number.
+    InterruptOverride {
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicNmi {
+        /// 0xFF for all processors.
+        processor_uid: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalApicAddressOverride(u64),
+    Unimplemented(u8),
+}
+
+pub struct Madt {
+    table: AcpiTable,
+}
+
+impl Madt {
+    pub fn find() -> Result<Self, AcpiError> {
+        Ok(Self { table: find_table(SIGNATURE)? })
+    }
+
+    /// Physical address of every CPU's local APIC, unless overridden by a
+    /// [`MadtEntry::LocalApicAddressOverride`].
+    pub fn local_apic_paddr(&self) -> u64 {
+        read_u32(self.table.body(), 0) as u64
+    }
+
+    pub fn entries(&self) -> MadtIter<'_> {
+        MadtIter { bytes: &self.table.body()[ENTRIES_OFFSET..] }
+    }
+
+    /// Local APIC IDs of the CPUs that can be started, in table order,
+    /// whether they're listed as xAPICs or x2APICs.
+    pub fn cpus(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. } if flags & LOCAL_APIC_ENABLED != 0 => Some(apic_id),
+            _ => None,
+        })
+    }
+}
+
+pub struct MadtIter<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for MadtIter<'_> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let [type_, len, ..] = *self.bytes else {
+            return None;
+        };
+
+        let len = len as usize;
+        if len < 2 || len > self.bytes.len() {
+            return None;
+        }
+
+        let e = &self.bytes[..len];
+        self.bytes = &self.bytes[len..];
+
+        let entry = match type_ {
+            TYPE_LOCAL_APIC if len >= 8 => MadtEntry::LocalApic {
+                processor_uid: e[2] as u32,
+                apic_id: e[3] as u32,
+                flags: read_u32(e, 4),
+            },
+            TYPE_IO_APIC if len >= 12 => MadtEntry::IoApic {
+                id: e[2],
+                paddr: read_u32(e, 4) as u64,
+                gsi_base: read_u32(e, 8),
+            },
+            TYPE_INTERRUPT_OVERRIDE if len >= 10 => MadtEntry::InterruptOverride {
+                source: e[3],
+                gsi: read_u32(e, 4),
+                flags: read_u16(e, 8),
+            },
+            TYPE_LOCAL_APIC_NMI if len >= 6 => MadtEntry::LocalApicNmi {
+                processor_uid: e[2],
+                flags: read_u16(e, 3),
+                lint: e[5],
+            },
+            TYPE_LOCAL_APIC_ADDRESS_OVERRIDE if len >= 12 => MadtEntry::LocalApicAddressOverride(read_u64(e, 4)),
+            TYPE_LOCAL_X2APIC if len >= 16 => MadtEntry::LocalApic {
+                processor_uid: read_u32(e, 12),
+                apic_id: read_u32(e, 4),
+                flags: read_u32(e, 8),
+            },
+            other => MadtEntry::Unimplemented(other),
+        };
+
+        Some(entry)
+    }
+}
@@ -1,5 +1,23 @@
+pub mod apic;
+pub mod context;
+pub mod cpu;
+pub mod exceptions;
+pub mod fpu;
 pub mod gdt;
+pub mod idt;
+pub mod interrupts;
+pub mod ipi;
 pub mod pages;
+pub mod paging;
+pub mod pat;
+pub mod percpu;
+pub mod pic;
+pub mod pit;
 pub mod ports;
+pub mod ps2;
+pub mod random;
+pub mod registers;
 pub mod serial;
+pub mod smp;
+pub mod tlb;
 pub mod vga;
\ No newline at end of file
@@ -0,0 +1,249 @@
+//! The local APIC, each CPU's own interrupt controller. Used for starting
+//! the other CPUs, sending them interrupts and giving each a timer of its
+//! own; device IRQs still come through the [`super::pic`], passed along by
+//! the boot CPU's LINT0.
+//!
+//! x2APIC mode is used when the CPU has it, with registers as MSRs rather
+//! than memory-mapped.
+
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+};
+
+use bitfield_struct::bitfield;
+
+use crate::{clock, memory::io::ioremap, sched};
+
+use super::{
+    cpu::{self, CpuFeatures},
+    idt::InterruptFrame,
+    interrupts,
+    pat::CacheMode,
+    percpu::KernelEntry,
+    registers::{Msr, RegisterRead, RegisterWrite, IA32_APIC_BASE},
+};
+
+const REG_ID: u32 = 0x020;
+const REG_TPR: u32 = 0x080;
+const REG_EOI: u32 = 0x0B0;
+const REG_SVR: u32 = 0x0F0;
+const REG_ESR: u32 = 0x280;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3E0;
+
+/// x2APIC registers are MSRs at this base plus the xAPIC offset / 16.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// The timer counts down at the bus clock divided by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+/// Clock ticks the timer is measured against in [`calibrate_timer`].
+const CALIBRATION_TICKS: u64 = 10;
+
+/// Delivered when an interrupt goes away before the CPU accepts it. Needs
+/// no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Each CPU's timer, which drives its scheduler.
+pub const TIMER_VECTOR: u8 = 0xEF;
+
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum DeliveryMode {
+    FIXED = 0,
+    LOWEST_PRIORITY = 1,
+    SMI = 2,
+    NMI = 4,
+    INIT = 5,
+    STARTUP = 6,
+    EXTINT = 7,
+}
+
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum Shorthand {
+    NONE = 0,
+    SELF = 1,
+    ALL = 2,
+    ALL_BUT_SELF = 3,
+}
+
+/// Interrupt command register, for sending interrupts to other CPUs. The
+/// destination is in the top 32 bits in x2APIC mode, and the top 8 in
+/// xAPIC mode, which [`send_ipi`] takes care of.
+#[bitfield(u64)]
+pub struct Icr {
+    pub vector: u8,
+    #[bits(3)]
+    pub delivery_mode: u8,
+    pub logical: bool,
+    /// Set while the last interrupt hasn't been accepted yet. xAPIC only.
+    pub pending: bool,
+    __: bool,
+    pub assert: bool,
+    pub level_triggered: bool,
+    #[bits(2)]
+    __: u8,
+    #[bits(2)]
+    pub shorthand: u8,
+    #[bits(12)]
+    __: u16,
+    pub destination: u32,
+}
+
+impl Icr {
+    pub const fn ipi(mode: DeliveryMode, vector: u8, destination: u32) -> Self {
+        Self::new()
+            .with_vector(vector)
+            .with_delivery_mode(mode as u8)
+            .with_assert(true)
+            .with_destination(destination)
+    }
+}
+
+/// The local APIC's LVT entry layout, for LINT0 and LINT1.
+const fn lvt(mode: DeliveryMode, masked: bool) -> u32 {
+    (mode as u32) << 8 | if masked { LVT_MASKED } else { 0 }
+}
+
+/// The xAPIC's registers, or null in x2APIC mode.
+static XAPIC: AtomicPtr<u32> = AtomicPtr::new(null_mut());
+/// What the timer counts down from for one [`clock`] tick. The same on
+/// every CPU.
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn read(reg: u32) -> u32 {
+    let xapic = XAPIC.load(Ordering::Relaxed);
+    if xapic.is_null() {
+        unsafe { Msr::new(X2APIC_MSR_BASE + reg / 16).read_raw() as u32 }
+    } else {
+        unsafe { xapic.byte_add(reg as usize).read_volatile() }
+    }
+}
+
+fn write(reg: u32, value: u32) {
+    let xapic = XAPIC.load(Ordering::Relaxed);
+    if xapic.is_null() {
+        unsafe { Msr::new(X2APIC_MSR_BASE + reg / 16).write_raw(value as u64) };
+    } else {
+        unsafe { xapic.byte_add(reg as usize).write_volatile(value) };
+    }
+}
+
+/// Turns on this CPU's local APIC, in x2APIC mode if that's what the boot
+/// CPU picked, with nothing below the spurious vector blocked.
+fn enable_local() {
+    unsafe {
+        // x2APIC mode can only be entered from xAPIC mode
+        let base = IA32_APIC_BASE.read().with_global_enable(true);
+        IA32_APIC_BASE.write(base);
+        if XAPIC.load(Ordering::Relaxed).is_null() {
+            IA32_APIC_BASE.write(base.with_x2apic_enable(true));
+        }
+    }
+
+    write(REG_TPR, 0);
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    // clear any errors from before, which takes a write first
+    write(REG_ESR, 0);
+    let _ = read(REG_ESR);
+}
+
+/// Sets up the boot CPU's local APIC. PIC interrupts keep arriving through
+/// LINT0 in virtual wire mode, as the firmware left it.
+///
+/// Safety: must be called once, on the boot CPU, before any other function
+/// here.
+pub unsafe fn init() {
+    if !cpu::has(CpuFeatures::X2APIC) {
+        let paddr = unsafe { IA32_APIC_BASE.read() }.base_pfn() << 12;
+        let regs = ioremap(paddr, 0x1000, CacheMode::UNCACHED).unwrap();
+        XAPIC.store(regs.leak(), Ordering::Relaxed);
+    }
+
+    enable_local();
+    write(REG_LVT_LINT0, lvt(DeliveryMode::EXTINT, false));
+    write(REG_LVT_LINT1, lvt(DeliveryMode::NMI, false));
+}
+
+/// Sets up the local APIC of a CPU other than the boot CPU. Only the boot
+/// CPU takes PIC interrupts.
+pub fn init_ap() {
+    enable_local();
+    write(REG_LVT_LINT0, lvt(DeliveryMode::EXTINT, true));
+    write(REG_LVT_LINT1, lvt(DeliveryMode::NMI, false));
+}
+
+/// This CPU's local APIC ID.
+pub fn id() -> u32 {
+    let id = read(REG_ID);
+    if XAPIC.load(Ordering::Relaxed).is_null() { id } else { id >> 24 }
+}
+
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// Sends [`icr`], waiting until the local APIC has handed it off.
+pub fn send_ipi(icr: Icr) {
+    let xapic = XAPIC.load(Ordering::Relaxed);
+    if xapic.is_null() {
+        unsafe { Msr::new(X2APIC_MSR_BASE + REG_ICR_LOW / 16).write_raw(icr.into_bits()) };
+        return;
+    }
+
+    // writing the low half sends it
+    write(REG_ICR_HIGH, icr.destination() << 24);
+    write(REG_ICR_LOW, icr.into_bits() as u32);
+    while Icr::from_bits(read(REG_ICR_LOW) as u64).pending() {
+        core::hint::spin_loop();
+    }
+}
+
+/// Measures how fast the timer counts against the [`clock`], which has to
+/// be running. Leaves interrupts enabled.
+pub fn calibrate_timer() {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+
+    // start on a tick, so that the whole of every tick is counted
+    let start = clock::ticks() + 1;
+    while clock::ticks() < start {
+        interrupts::enable_and_hlt();
+    }
+
+    write(REG_TIMER_INITIAL, u32::MAX);
+    while clock::ticks() < start + CALIBRATION_TICKS {
+        interrupts::enable_and_hlt();
+    }
+
+    let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+    write(REG_TIMER_INITIAL, 0);
+    TIMER_COUNT.store(elapsed / CALIBRATION_TICKS as u32, Ordering::Relaxed);
+}
+
+/// Starts this CPU's timer, interrupting at [`TIMER_VECTOR`] once per
+/// [`clock`] tick. [`calibrate_timer`] has to have run on some CPU first.
+pub fn start_timer() {
+    let count = TIMER_COUNT.load(Ordering::Relaxed);
+    assert!(count != 0, "the local APIC timer hasn't been calibrated");
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+    write(REG_TIMER_INITIAL, count);
+}
+
+pub extern "x86-interrupt" fn timer_handler(frame: InterruptFrame) {
+    let _entry = KernelEntry::new(&frame);
+    eoi();
+    sched::tick();
+}
+
+pub extern "x86-interrupt" fn spurious_handler(_frame: InterruptFrame) {}
@@ -0,0 +1,56 @@
+//! Switching between kernel stacks, each of which holds a thread's
+//! registers while it isn't running.
+
+use core::arch::global_asm;
+
+global_asm!(include_str!("context.s"));
+
+unsafe extern "C" {
+    /// Saves the callee-saved registers and the stack pointer in
+    /// [`prev_rsp`], then switches to the stack at [`next_rsp`] and returns
+    /// wherever it last called this, or into its entry point if it's new.
+    ///
+    /// Safety: [`next_rsp`] must come from an earlier switch away from its
+    /// stack or from [`new_stack_frame`], and that stack must still be
+    /// mapped.
+    pub fn switch_stacks(prev_rsp: *mut u64, next_rsp: u64);
+
+    fn thread_start();
+}
+
+/// Registers [`switch_stacks`] pops, lowest address first, then the
+/// address it returns to.
+#[repr(C)]
+struct InitialFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    ret: u64,
+}
+
+/// Lays out a frame at the top of the stack ending at [`top`] for
+/// [`switch_stacks`] to start [`entry`] on, with [`arg`] as its argument.
+/// Returns the stack pointer to switch to.
+///
+/// Safety: [`top`] must be the 16-byte aligned top of a mapped stack with
+/// nothing on it.
+pub unsafe fn new_stack_frame(top: u64, entry: extern "C" fn(u64) -> !, arg: u64) -> u64 {
+    // once the frame is popped rsp is back at top, so entry sees the
+    // aligned stack a call would leave
+    let rsp = top - size_of::<InitialFrame>() as u64;
+    let frame = InitialFrame {
+        r15: 0,
+        r14: 0,
+        r13: entry as *const () as u64,
+        r12: arg,
+        rbx: 0,
+        rbp: 0,
+        ret: thread_start as *const () as u64,
+    };
+
+    unsafe { (rsp as *mut InitialFrame).write(frame) };
+    rsp
+}
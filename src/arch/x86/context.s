@@ -0,0 +1,31 @@
+.section .text
+
+// switch_stacks(prev_rsp: *mut u64, next_rsp: u64)
+//
+// saves the callee-saved registers on the current stack and its rsp in
+// [rdi], then picks up the stack in rsi where it left off
+.global switch_stacks
+switch_stacks:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+// the first return from switch_stacks on a new stack lands here, with the
+// entry point in r13 and its argument in r12
+.global thread_start
+thread_start:
+    mov rdi, r12
+    call r13
+    ud2
@@ -0,0 +1,438 @@
+//! What the CPU is and what it can do, according to CPUID. Read once at
+//! boot by [`init`]; everything else should ask [`has`] rather than assume.
+
+use core::{arch::x86_64::{__cpuid, __cpuid_count, CpuidResult}, fmt};
+
+use bitflags::bitflags;
+
+const CPUID_VENDOR: u32 = 0x0000_0000;
+const CPUID_FEATURES: u32 = 0x0000_0001;
+const CPUID_CACHE_PARAMS: u32 = 0x0000_0004;
+const CPUID_EXT_FEATURES_7: u32 = 0x0000_0007;
+const CPUID_TLB_PARAMS: u32 = 0x0000_0018;
+const CPUID_EXT_MAX: u32 = 0x8000_0000;
+const CPUID_EXT_FEATURES: u32 = 0x8000_0001;
+const CPUID_BRAND: u32 = 0x8000_0002;
+const CPUID_AMD_L1_TLB: u32 = 0x8000_0005;
+const CPUID_AMD_L2_TLB: u32 = 0x8000_0006;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_AMD_CACHE_PARAMS: u32 = 0x8000_001D;
+
+const MAX_CACHES: usize = 8;
+const MAX_TLBS: usize = 8;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct CpuFeatures: u64 {
+        const FPU = 1 << 0;
+        const TSC = 1 << 1;
+        const MSR = 1 << 2;
+        const PAE = 1 << 3;
+        const APIC = 1 << 4;
+        const PGE = 1 << 5;
+        const PAT = 1 << 6;
+        const FXSR = 1 << 7;
+        const SSE = 1 << 8;
+        const SSE2 = 1 << 9;
+        const PCID = 1 << 10;
+        const X2APIC = 1 << 11;
+        const TSC_DEADLINE = 1 << 12;
+        const XSAVE = 1 << 13;
+        const AVX = 1 << 14;
+        const RDRAND = 1 << 15;
+        const FSGSBASE = 1 << 16;
+        const AVX2 = 1 << 17;
+        const SMEP = 1 << 18;
+        const INVPCID = 1 << 19;
+        const RDSEED = 1 << 20;
+        const SMAP = 1 << 21;
+        const UMIP = 1 << 22;
+        const LA57 = 1 << 23;
+        const NX = 1 << 24;
+        const PDPE1GB = 1 << 25;
+        const LONG_MODE = 1 << 26;
+        const INVARIANT_TSC = 1 << 27;
+    }
+}
+
+impl CpuFeatures {
+    /// What the kernel can't run without. The boot code already relies on
+    /// most of these before it can check, so this is only a sanity check.
+    const REQUIRED: Self = Self::LONG_MODE
+        .union(Self::PAE)
+        .union(Self::PGE)
+        .union(Self::PAT)
+        .union(Self::MSR)
+        .union(Self::TSC);
+}
+
+/// Feature bits and the CPUID leaf, register and bit that report them.
+const FEATURE_BITS: [(CpuFeatures, u32, Register, u32); 28] = [
+    (CpuFeatures::FPU, CPUID_FEATURES, Register::EDX, 0),
+    (CpuFeatures::TSC, CPUID_FEATURES, Register::EDX, 4),
+    (CpuFeatures::MSR, CPUID_FEATURES, Register::EDX, 5),
+    (CpuFeatures::PAE, CPUID_FEATURES, Register::EDX, 6),
+    (CpuFeatures::APIC, CPUID_FEATURES, Register::EDX, 9),
+    (CpuFeatures::PGE, CPUID_FEATURES, Register::EDX, 13),
+    (CpuFeatures::PAT, CPUID_FEATURES, Register::EDX, 16),
+    (CpuFeatures::FXSR, CPUID_FEATURES, Register::EDX, 24),
+    (CpuFeatures::SSE, CPUID_FEATURES, Register::EDX, 25),
+    (CpuFeatures::SSE2, CPUID_FEATURES, Register::EDX, 26),
+    (CpuFeatures::PCID, CPUID_FEATURES, Register::ECX, 17),
+    (CpuFeatures::X2APIC, CPUID_FEATURES, Register::ECX, 21),
+    (CpuFeatures::TSC_DEADLINE, CPUID_FEATURES, Register::ECX, 24),
+    (CpuFeatures::XSAVE, CPUID_FEATURES, Register::ECX, 26),
+    (CpuFeatures::AVX, CPUID_FEATURES, Register::ECX, 28),
+    (CpuFeatures::RDRAND, CPUID_FEATURES, Register::ECX, 30),
+    (CpuFeatures::FSGSBASE, CPUID_EXT_FEATURES_7, Register::EBX, 0),
+    (CpuFeatures::AVX2, CPUID_EXT_FEATURES_7, Register::EBX, 5),
+    (CpuFeatures::SMEP, CPUID_EXT_FEATURES_7, Register::EBX, 7),
+    (CpuFeatures::INVPCID, CPUID_EXT_FEATURES_7, Register::EBX, 10),
+    (CpuFeatures::RDSEED, CPUID_EXT_FEATURES_7, Register::EBX, 18),
+    (CpuFeatures::SMAP, CPUID_EXT_FEATURES_7, Register::EBX, 20),
+    (CpuFeatures::UMIP, CPUID_EXT_FEATURES_7, Register::ECX, 2),
+    (CpuFeatures::LA57, CPUID_EXT_FEATURES_7, Register::ECX, 16),
+    (CpuFeatures::NX, CPUID_EXT_FEATURES, Register::EDX, 20),
+    (CpuFeatures::PDPE1GB, CPUID_EXT_FEATURES, Register::EDX, 26),
+    (CpuFeatures::LONG_MODE, CPUID_EXT_FEATURES, Register::EDX, 29),
+    (CpuFeatures::INVARIANT_TSC, CPUID_POWER_MANAGEMENT, Register::EDX, 8),
+];
+
+#[derive(Copy, Clone)]
+enum Register {
+    EBX,
+    ECX,
+    EDX,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    DATA,
+    INSTRUCTION,
+    UNIFIED,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CacheInfo {
+    pub kind: CacheKind,
+    pub level: u8,
+    pub size: u32,
+    /// 0 if fully associative.
+    pub ways: u16,
+    pub line_size: u16,
+    /// How many logical CPUs share it.
+    pub shared_by: u16,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct TlbPageSizes: u8 {
+        const SIZE_4K = 1 << 0;
+        const SIZE_2M = 1 << 1;
+        const SIZE_4M = 1 << 2;
+        const SIZE_1G = 1 << 3;
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TlbInfo {
+    pub kind: CacheKind,
+    pub level: u8,
+    pub entries: u32,
+    /// 0 if fully associative.
+    pub ways: u16,
+    pub page_sizes: TlbPageSizes,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CpuVendor {
+    INTEL,
+    AMD,
+    OTHER,
+}
+
+pub struct CpuInfo {
+    vendor_id: [u8; 12],
+    brand: [u8; 48],
+    pub vendor: CpuVendor,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: CpuFeatures,
+    caches: [Option<CacheInfo>; MAX_CACHES],
+    tlbs: [Option<TlbInfo>; MAX_TLBS],
+}
+
+fn cpuid(leaf: u32, max: u32) -> Option<CpuidResult> {
+    // leaves past the maximum return garbage rather than zeroes
+    if leaf > max { None } else { Some(__cpuid_count(leaf, 0)) }
+}
+
+impl CpuInfo {
+    fn read() -> Self {
+        let leaf0 = __cpuid(CPUID_VENDOR);
+        let max = leaf0.eax;
+        let ext_max = __cpuid(CPUID_EXT_MAX).eax;
+        let max_for = |leaf: u32| if leaf >= CPUID_EXT_MAX { ext_max } else { max };
+
+        let mut vendor_id = [0u8; 12];
+        vendor_id[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor_id[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor_id[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+        let vendor = match &vendor_id {
+            b"GenuineIntel" => CpuVendor::INTEL,
+            b"AuthenticAMD" => CpuVendor::AMD,
+            _ => CpuVendor::OTHER,
+        };
+
+        let signature = __cpuid(CPUID_FEATURES).eax;
+        let base_family = (signature >> 8) & 0xF;
+        let base_model = (signature >> 4) & 0xF;
+        let family = if base_family == 0xF { base_family + ((signature >> 20) & 0xFF) } else { base_family };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            base_model | ((signature >> 16) & 0xF) << 4
+        } else {
+            base_model
+        };
+
+        let mut features = CpuFeatures::empty();
+        for (feature, leaf, register, bit) in FEATURE_BITS {
+            let Some(regs) = cpuid(leaf, max_for(leaf)) else {
+                continue;
+            };
+
+            let value = match register {
+                Register::EBX => regs.ebx,
+                Register::ECX => regs.ecx,
+                Register::EDX => regs.edx,
+            };
+
+            features.set(feature, value & (1 << bit) != 0);
+        }
+
+        let mut brand = [0u8; 48];
+        if ext_max >= CPUID_BRAND + 2 {
+            for (i, chunk) in brand.chunks_exact_mut(16).enumerate() {
+                let regs = __cpuid(CPUID_BRAND + i as u32);
+                for (j, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].into_iter().enumerate() {
+                    chunk[j * 4..j * 4 + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+
+        let mut info = Self {
+            vendor_id,
+            brand,
+            vendor,
+            family,
+            model,
+            stepping: signature & 0xF,
+            features,
+            caches: [None; MAX_CACHES],
+            tlbs: [None; MAX_TLBS],
+        };
+
+        let cache_leaf = if vendor == CpuVendor::AMD { CPUID_AMD_CACHE_PARAMS } else { CPUID_CACHE_PARAMS };
+        if cache_leaf <= max_for(cache_leaf) {
+            info.read_caches(cache_leaf);
+        }
+
+        if vendor == CpuVendor::AMD {
+            info.read_amd_tlbs(ext_max);
+        } else if CPUID_TLB_PARAMS <= max {
+            info.read_tlbs();
+        }
+
+        info
+    }
+
+    /// Both vendors describe caches the same way, one subleaf each, just
+    /// under different leaves.
+    fn read_caches(&mut self, leaf: u32) {
+        for (subleaf, slot) in (0..).zip(self.caches.iter_mut()) {
+            let regs = __cpuid_count(leaf, subleaf);
+            let kind = match regs.eax & 0x1F {
+                1 => CacheKind::DATA,
+                2 => CacheKind::INSTRUCTION,
+                3 => CacheKind::UNIFIED,
+                _ => break,
+            };
+
+            let ways = (regs.ebx >> 22) + 1;
+            let partitions = ((regs.ebx >> 12) & 0x3FF) + 1;
+            let line_size = (regs.ebx & 0xFFF) + 1;
+            let sets = regs.ecx + 1;
+            let fully_associative = regs.eax & (1 << 9) != 0;
+
+            *slot = Some(CacheInfo {
+                kind,
+                level: ((regs.eax >> 5) & 0x7) as u8,
+                size: ways * partitions * line_size * sets,
+                ways: if fully_associative { 0 } else { ways as u16 },
+                line_size: line_size as u16,
+                shared_by: (((regs.eax >> 14) & 0xFFF) + 1) as u16,
+            });
+        }
+    }
+
+    fn read_tlbs(&mut self) {
+        let max_subleaf = __cpuid_count(CPUID_TLB_PARAMS, 0).eax;
+        let mut slots = self.tlbs.iter_mut();
+        for subleaf in 0..=max_subleaf {
+            let regs = __cpuid_count(CPUID_TLB_PARAMS, subleaf);
+            let kind = match regs.edx & 0x1F {
+                1 => CacheKind::DATA,
+                2 => CacheKind::INSTRUCTION,
+                3 => CacheKind::UNIFIED,
+                // invalid, or load- or store-only, which are rare enough to skip
+                _ => continue,
+            };
+
+            let Some(slot) = slots.next() else {
+                break;
+            };
+
+            let ways = (regs.ebx >> 16) as u16;
+            *slot = Some(TlbInfo {
+                kind,
+                level: ((regs.edx >> 5) & 0x7) as u8,
+                entries: ways as u32 * regs.ecx,
+                ways: if regs.edx & (1 << 8) != 0 { 0 } else { ways },
+                page_sizes: TlbPageSizes::from_bits_truncate(regs.ebx as u8),
+            });
+        }
+    }
+
+    /// Only the 4 KiB page TLBs, which are what matter for shootdowns.
+    fn read_amd_tlbs(&mut self, ext_max: u32) {
+        fn l2_ways(code: u32) -> u16 {
+            match code {
+                0x1 => 1,
+                0x2 => 2,
+                0x3 => 3,
+                0x4 => 4,
+                0x5 => 6,
+                0x6 => 8,
+                0x8 => 16,
+                0xA => 32,
+                0xB => 48,
+                0xC => 64,
+                0xD => 96,
+                0xE => 128,
+                _ => 0,
+            }
+        }
+
+        let mut slots = self.tlbs.iter_mut();
+        let mut push = |kind, level, entries: u32, ways: u16| {
+            if entries != 0 && let Some(slot) = slots.next() {
+                *slot = Some(TlbInfo { kind, level, entries, ways, page_sizes: TlbPageSizes::SIZE_4K });
+            }
+        };
+
+        if ext_max >= CPUID_AMD_L1_TLB {
+            let ebx = __cpuid(CPUID_AMD_L1_TLB).ebx;
+            let ways = |w: u32| if w == 0xFF { 0 } else { w as u16 };
+            push(CacheKind::DATA, 1, (ebx >> 16) & 0xFF, ways(ebx >> 24));
+            push(CacheKind::INSTRUCTION, 1, ebx & 0xFF, ways((ebx >> 8) & 0xFF));
+        }
+
+        if ext_max >= CPUID_AMD_L2_TLB {
+            let ebx = __cpuid(CPUID_AMD_L2_TLB).ebx;
+            push(CacheKind::DATA, 2, (ebx >> 16) & 0xFFF, l2_ways(ebx >> 28));
+            push(CacheKind::INSTRUCTION, 2, ebx & 0xFFF, l2_ways((ebx >> 12) & 0xF));
+        }
+    }
+
+    pub fn vendor_id(&self) -> &str {
+        core::str::from_utf8(&self.vendor_id).unwrap_or("unknown")
+    }
+
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(self.brand.len());
+        core::str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &CacheInfo> {
+        self.caches.iter().flatten()
+    }
+
+    pub fn tlbs(&self) -> impl Iterator<Item = &TlbInfo> {
+        self.tlbs.iter().flatten()
+    }
+}
+
+impl fmt::Display for CacheKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::DATA => "d",
+            Self::INSTRUCTION => "i",
+            Self::UNIFIED => "",
+        })
+    }
+}
+
+fn write_ways(f: &mut fmt::Formatter<'_>, ways: u16) -> fmt::Result {
+    if ways == 0 { write!(f, "fully associative") } else { write!(f, "{ways}-way") }
+}
+
+/// A multi-line boot summary.
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "cpu: {} family {:#x} model {:#x} stepping {}: {}",
+            self.vendor_id(),
+            self.family,
+            self.model,
+            self.stepping,
+            self.brand(),
+        )?;
+
+        write!(f, "cpu: features:")?;
+        for (name, _) in self.features.iter_names() {
+            write!(f, " ")?;
+            for c in name.chars() {
+                write!(f, "{}", c.to_ascii_lowercase())?;
+            }
+        }
+        writeln!(f)?;
+
+        for cache in self.caches() {
+            write!(f, "cpu: L{}{} cache: {} KiB, ", cache.level, cache.kind, cache.size / 1024)?;
+            write_ways(f, cache.ways)?;
+            writeln!(f, ", {} byte lines, shared by {}", cache.line_size, cache.shared_by)?;
+        }
+
+        for tlb in self.tlbs() {
+            write!(f, "cpu: L{}{} TLB: {} entries, ", tlb.level, tlb.kind, tlb.entries)?;
+            write_ways(f, tlb.ways)?;
+            writeln!(f, ", pages {:?}", tlb.page_sizes)?;
+        }
+
+        Ok(())
+    }
+}
+
+static mut INFO: Option<CpuInfo> = None;
+
+/// Reads the CPU's description. Panics if it lacks something the kernel
+/// needs.
+///
+/// Safety: must be called once, before anything uses [`info`] or [`has`],
+/// while only one CPU is running.
+pub unsafe fn init() {
+    let info = CpuInfo::read();
+    let missing = CpuFeatures::REQUIRED - info.features;
+    assert!(missing.is_empty(), "CPU is missing required features: {missing:?}");
+    unsafe { INFO = Some(info) };
+}
+
+pub fn info() -> &'static CpuInfo {
+    let info = &raw const INFO;
+    unsafe { (*info).as_ref() }.expect("cpu::init hasn't been called")
+}
+
+pub fn has(features: CpuFeatures) -> bool {
+    info().features.contains(features)
+}
@@ -0,0 +1,100 @@
+//! Handlers for CPU exceptions.
+
+use bitfield_struct::bitfield;
+
+use crate::memory::{stack::{self, KernelStack, StackError}, vma::{self, Access}};
+
+use super::{gdt::Tss, idt::{Idt, InterruptFrame}, percpu::KernelEntry, registers::{RegisterRead, CR2}};
+
+/// IST slot for double faults. Page faults stay on the stack they happen
+/// on, since resolving one can fault again and a fresh IST stack would be
+/// reused from the top. Overflowing a kernel stack faults while pushing the
+/// page fault's frame, which turns it into a double fault, so that's where
+/// overflows get reported from.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+#[bitfield(u64)]
+pub struct PageFaultError {
+    /// Set if the page was present and this is a protection violation.
+    pub present: bool,
+    pub write: bool,
+    pub user: bool,
+    pub reserved_bit: bool,
+    pub instruction_fetch: bool,
+    pub protection_key: bool,
+    pub shadow_stack: bool,
+    #[bits(57)]
+    __: u64,
+}
+
+fn cr2() -> u64 {
+    unsafe { CR2.read() }
+}
+
+fn check_stack_overflow(addr: u64, frame: &InterruptFrame) {
+    if let Some(owner) = stack::guard_page_owner(addr) {
+        panic!("kernel stack overflow in {owner}: access to {addr:#x} at rip {:#x}", frame.rip);
+    }
+}
+
+pub extern "x86-interrupt" fn page_fault_handler(frame: InterruptFrame, error_code: u64) {
+    let _entry = KernelEntry::new(&frame);
+    let addr = cr2();
+    check_stack_overflow(addr, &frame);
+
+    let error = PageFaultError::from_bits(error_code);
+    if !error.present() || error.write() {
+        let access = Access {
+            present: error.present(),
+            write: error.write(),
+            execute: error.instruction_fetch(),
+            user: error.user(),
+        };
+
+        match vma::handle_fault(addr, access) {
+            Ok(()) => return,
+            Err(vma::VmError::NotFound | vma::VmError::AccessViolation) => {},
+            Err(e) => panic!("page fault: couldn't resolve {addr:#x} at rip {:#x} ({error:?}): {e:?}", frame.rip),
+        }
+    }
+
+    panic!("page fault: access to {addr:#x} at rip {:#x} ({error:?})", frame.rip);
+}
+
+/// Also catches page faults that happen while pushing the frame for
+/// another exception, as happens once a stack has overflowed into its guard
+/// page, which CR2 then points into.
+pub extern "x86-interrupt" fn double_fault_handler(frame: InterruptFrame, _error_code: u64) {
+    check_stack_overflow(cr2(), &frame);
+    panic!("double fault at rip {:#x}", frame.rip);
+}
+
+/// Unmasked x87 errors, which [`super::fpu::init`] routes here instead of
+/// to IRQ 13.
+pub extern "x86-interrupt" fn x87_floating_point_handler(frame: InterruptFrame) {
+    panic!("x87 floating point exception at rip {:#x}", frame.rip);
+}
+
+pub extern "x86-interrupt" fn simd_floating_point_handler(frame: InterruptFrame) {
+    panic!("SIMD floating point exception at rip {:#x}", frame.rip);
+}
+
+/// Gives the double fault handler its own stack in [`tss`]. Every CPU
+/// needs its own.
+pub fn init_tss(tss: &mut Tss) -> Result<(), StackError> {
+    tss.set_ist(DOUBLE_FAULT_IST, KernelStack::new("double fault handler")?.leak());
+    Ok(())
+}
+
+/// Installs the exception handlers and gives the double fault handler its
+/// own stack.
+pub fn init(idt: &mut Idt, tss: &mut Tss) -> Result<(), StackError> {
+    init_tss(tss)?;
+
+    idt.set_handler_with_error_code(Idt::PAGE_FAULT, page_fault_handler);
+    idt.set_handler_with_error_code(Idt::DOUBLE_FAULT, double_fault_handler);
+    idt.set_ist(Idt::DOUBLE_FAULT, DOUBLE_FAULT_IST);
+    idt.set_handler(Idt::X87_FLOATING_POINT, x87_floating_point_handler);
+    idt.set_handler(Idt::SIMD_FLOATING_POINT, simd_floating_point_handler);
+    Ok(())
+}
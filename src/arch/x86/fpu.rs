@@ -0,0 +1,238 @@
+//! The x87 FPU, SSE and AVX. The kernel itself is built without them, so
+//! their state only has to be kept for tasks that use them and around
+//! kernel code that asks for them with [`kernel_fpu_begin`].
+//!
+//! That doesn't make the compiler use them in the kernel: the target is
+//! soft-float, which wins even over `#[target_feature(enable = "sse2")]`,
+//! so SIMD in kernel code is written in `asm!`, like [`KernelFpu::copy`].
+//!
+//! State is switched eagerly: whoever switches tasks saves the old task's
+//! [`FpuState`] and restores the new one's, rather than trapping on first
+//! use.
+
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid_count, _fxrstor64, _fxsave64, _xrstor64, _xsave64},
+    },
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use super::{
+    cpu::{self, CpuFeatures},
+    interrupts,
+    registers::{RegisterRead, RegisterWrite, Xcr0, CR0, CR4, XCR0},
+};
+
+const CPUID_XSAVE: u32 = 0x0000_000D;
+
+/// Enough for everything up to AVX-512.
+const MAX_STATE_SIZE: usize = 4096;
+const FXSAVE_SIZE: usize = 512;
+
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// All x87 exceptions masked, 64-bit precision, round to nearest.
+const DEFAULT_FCW: u16 = 0x037F;
+/// All SSE exceptions masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+/// The components in XCR0, which XSAVE is asked to save.
+static XSAVE_MASK: AtomicU64 = AtomicU64::new(0);
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// Saved FPU, SSE and AVX registers, in whichever format the CPU uses.
+#[repr(C, align(64))]
+pub struct FpuState([u8; MAX_STATE_SIZE]);
+
+impl FpuState {
+    /// The state after reset, with all exceptions masked. The XSAVE header
+    /// is zeroed, which makes XRSTOR load every component's initial state.
+    pub const fn new() -> Self {
+        let mut area = [0u8; MAX_STATE_SIZE];
+        let fcw = DEFAULT_FCW.to_le_bytes();
+        area[FCW_OFFSET] = fcw[0];
+        area[FCW_OFFSET + 1] = fcw[1];
+
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        let mut i = 0;
+        while i < mxcsr.len() {
+            area[MXCSR_OFFSET + i] = mxcsr[i];
+            i += 1;
+        }
+
+        Self(area)
+    }
+
+    /// Saves the current CPU's registers here.
+    pub fn save(&mut self) {
+        let area = self.0.as_mut_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                xsave(area, XSAVE_MASK.load(Ordering::Relaxed));
+            } else {
+                fxsave(area);
+            }
+        }
+    }
+
+    /// Loads the registers from here.
+    ///
+    /// Safety: must hold a state from [`FpuState::new`] or
+    /// [`FpuState::save`], or XRSTOR faults.
+    pub unsafe fn restore(&self) {
+        let area = self.0.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                xrstor(area, XSAVE_MASK.load(Ordering::Relaxed));
+            } else {
+                fxrstor(area);
+            }
+        }
+    }
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xsave(area: *mut u8, mask: u64) {
+    unsafe { _xsave64(area, mask) };
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xrstor(area: *const u8, mask: u64) {
+    unsafe { _xrstor64(area, mask) };
+}
+
+#[target_feature(enable = "fxsr")]
+unsafe fn fxsave(area: *mut u8) {
+    unsafe { _fxsave64(area) };
+}
+
+#[target_feature(enable = "fxsr")]
+unsafe fn fxrstor(area: *const u8) {
+    unsafe { _fxrstor64(area) };
+}
+
+/// Turns on the FPU and SSE, plus AVX where there's XSAVE to manage it.
+/// Every CPU needs this done before anything touches those registers.
+pub fn init() {
+    unsafe {
+        // no emulation, and report errors as exceptions rather than through
+        // the legacy IRQ 13
+        CR0.write(CR0.read().with_em(false).with_mp(true).with_ne(true).with_ts(false));
+
+        let has_xsave = cpu::has(CpuFeatures::XSAVE);
+        CR4.write(CR4.read().with_osfxsr(true).with_osxmmexcpt(true).with_osxsave(has_xsave));
+
+        let size = if has_xsave {
+            let leaf = __cpuid_count(CPUID_XSAVE, 0);
+            let supported = Xcr0::from_bits(leaf.eax as u64 | (leaf.edx as u64) << 32);
+            let xcr0 = Xcr0::new()
+                .with_x87(true)
+                .with_sse(true)
+                .with_avx(supported.avx() && cpu::has(CpuFeatures::AVX));
+            XCR0.write(xcr0);
+            XSAVE_MASK.store(xcr0.into_bits(), Ordering::Relaxed);
+
+            // EBX is the size for what's enabled in XCR0 right now
+            __cpuid_count(CPUID_XSAVE, 0).ebx as usize
+        } else {
+            FXSAVE_SIZE
+        };
+
+        assert!(size <= MAX_STATE_SIZE, "FPU state is {size} bytes, more than the {MAX_STATE_SIZE} reserved");
+        USE_XSAVE.store(has_xsave, Ordering::Relaxed);
+        STATE_SIZE.store(size, Ordering::Relaxed);
+
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+/// Bytes of [`FpuState`] actually used on this CPU.
+pub fn state_size() -> usize {
+    STATE_SIZE.load(Ordering::Relaxed)
+}
+
+static INIT_STATE: FpuState = FpuState::new();
+
+crate::per_cpu! {
+    /// Whatever this CPU's registers held before [`kernel_fpu_begin`].
+    static KERNEL_SAVED: FpuState = FpuState::new();
+    static IN_KERNEL_FPU: bool = false;
+}
+
+/// Lets the kernel use the FPU, SSE and AVX on this CPU until dropped,
+/// without disturbing whatever state the interrupted task had in them.
+/// Interrupts are off meanwhile, so keep it short, and the code using them
+/// has to be `asm!`, as the module documentation explains.
+#[must_use]
+pub struct KernelFpu {
+    interrupts_were_enabled: bool,
+}
+
+pub fn kernel_fpu_begin() -> KernelFpu {
+    let interrupts_were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    assert!(!IN_KERNEL_FPU.get(), "nested kernel_fpu_begin");
+    IN_KERNEL_FPU.set(true);
+
+    unsafe {
+        (*KERNEL_SAVED.as_ptr()).save();
+        // start clean rather than with the task's rounding modes and masks
+        INIT_STATE.restore();
+    }
+
+    KernelFpu { interrupts_were_enabled }
+}
+
+pub fn kernel_fpu_end(guard: KernelFpu) {
+    drop(guard);
+}
+
+impl KernelFpu {
+    /// Copies [`len`] bytes, a multiple of 64, with SSE2.
+    ///
+    /// Safety: [`src`] and [`dst`] must be 16-byte aligned, valid for
+    /// [`len`] bytes and not overlap.
+    pub unsafe fn copy(&self, dst: *mut u8, src: *const u8, len: usize) {
+        assert!(len.is_multiple_of(64));
+        if len == 0 {
+            return;
+        }
+
+        // the XMM registers can't be named as clobbers without SSE in the
+        // target, but the compiler never uses them, and the guard restores
+        // whatever they held
+        unsafe {
+            asm!(
+                "2:",
+                "movdqa xmm0, [{src}]",
+                "movdqa xmm1, [{src} + 16]",
+                "movdqa xmm2, [{src} + 32]",
+                "movdqa xmm3, [{src} + 48]",
+                "movdqa [{dst}], xmm0",
+                "movdqa [{dst} + 16], xmm1",
+                "movdqa [{dst} + 32], xmm2",
+                "movdqa [{dst} + 48], xmm3",
+                "add {src}, 64",
+                "add {dst}, 64",
+                "sub {len}, 64",
+                "jnz 2b",
+                src = inout(reg) src => _,
+                dst = inout(reg) dst => _,
+                len = inout(reg) len => _,
+                options(nostack),
+            );
+        }
+    }
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        unsafe { (*KERNEL_SAVED.as_ptr()).restore() };
+        IN_KERNEL_FPU.set(false);
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
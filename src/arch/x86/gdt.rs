@@ -1,6 +1,6 @@
 #![allow(non_upper_case_globals, non_snake_case)]
 
-use core::mem::offset_of;
+use core::{arch::asm, mem::offset_of};
 
 use bitfield_struct::bitfield;
 
@@ -48,6 +48,90 @@ impl GdtSegmentSelector {
     const USER_DATA: Self = Self::DATA.with_dpl(3);
 }
 
+/// Descriptor for a TSS, which takes up two GDT slots in long mode.
+#[bitfield(u128)]
+struct GdtSystemDescriptor {
+    pub limit_low: u16,
+    pub base_low: u16,
+    pub base_mid: u8,
+    #[bits(4)]
+    pub type_: u8,
+    __: bool,
+    #[bits(2)]
+    pub dpl: u8,
+    pub present: bool,
+    #[bits(4)]
+    pub limit_high: u8,
+    pub available: bool,
+    #[bits(2)]
+    __: u8,
+    pub granularity: bool,
+    pub base_high: u8,
+    pub base_upper: u32,
+    __: u32,
+}
+
+impl GdtSystemDescriptor {
+    const NULL: Self = GdtSystemDescriptor::from_bits(0);
+
+    const TYPE_TSS_AVAILABLE: u8 = 0x9;
+
+    fn tss(tss: &'static Tss) -> Self {
+        let base = tss as *const Tss as u64;
+        let limit = size_of::<Tss>() - 1;
+        Self::NULL
+            .with_limit_low(limit as u16)
+            .with_limit_high((limit >> 16) as u8)
+            .with_base_low(base as u16)
+            .with_base_mid((base >> 16) as u8)
+            .with_base_high((base >> 24) as u8)
+            .with_base_upper((base >> 32) as u32)
+            .with_type_(Self::TYPE_TSS_AVAILABLE)
+            .with_dpl(0)
+            .with_present(true)
+    }
+}
+
+/// The 64-bit task state segment. All that's left of hardware task switching
+/// in long mode is the stacks the CPU switches to on interrupts.
+#[repr(C, packed)]
+pub struct Tss {
+    reserved_0: u32,
+    /// Stacks loaded when an interrupt raises the privilege level to ring 0,
+    /// 1, or 2.
+    pub rsp: [u64; 3],
+    reserved_1: u64,
+    /// Interrupt stack table. A gate with IST index n switches to
+    /// `ist[n - 1]` no matter what ring it came from.
+    pub ist: [u64; 7],
+    reserved_2: u64,
+    reserved_3: u16,
+    pub iomap_base: u16,
+}
+
+impl Tss {
+    pub const fn new() -> Self {
+        Self {
+            reserved_0: 0,
+            rsp: [0; 3],
+            reserved_1: 0,
+            ist: [0; 7],
+            reserved_2: 0,
+            reserved_3: 0,
+            // no I/O permission bitmap
+            iomap_base: size_of::<Tss>() as u16,
+        }
+    }
+
+    /// Sets the stack for IST index [`index`], which starts at 1.
+    pub fn set_ist(&mut self, index: u8, stack_top: u64) {
+        assert!((1..=7).contains(&index));
+        let mut ist = self.ist;
+        ist[index as usize - 1] = stack_top;
+        self.ist = ist;
+    }
+}
+
 #[repr(C, packed)]
 pub struct Gdt {
     null: GdtSegmentSelector,
@@ -55,6 +139,7 @@ pub struct Gdt {
     kdata: GdtSegmentSelector,
     ucode: GdtSegmentSelector,
     udata: GdtSegmentSelector,
+    tss: GdtSystemDescriptor,
 }
 
 impl Gdt {
@@ -64,6 +149,7 @@ impl Gdt {
     pub const USER_CODE_SELECTOR: usize = offset_of!(Gdt, ucode);
     #[allow(unused)]
     pub const USER_DATA_SELECTOR: usize = offset_of!(Gdt, udata);
+    pub const TSS_SELECTOR: usize = offset_of!(Gdt, tss);
 
     pub const fn new() -> Self {
         Self {
@@ -72,8 +158,19 @@ impl Gdt {
             kdata: GdtSegmentSelector::KERNEL_DATA,
             ucode: GdtSegmentSelector::USER_CODE,
             udata: GdtSegmentSelector::USER_DATA,
+            tss: GdtSystemDescriptor::NULL,
         }
     }
+
+    /// Points the TSS descriptor at [`tss`] and loads it into the task
+    /// register.
+    ///
+    /// Safety: this must be the loaded GDT, and the TSS can only be loaded
+    /// once, since loading marks the descriptor busy.
+    pub unsafe fn load_tss(&mut self, tss: &'static Tss) {
+        self.tss = GdtSystemDescriptor::tss(tss);
+        unsafe { asm!("ltr {:x}", in(reg) Self::TSS_SELECTOR as u16, options(nostack, preserves_flags)) };
+    }
 }
 
 #[repr(C, packed)]
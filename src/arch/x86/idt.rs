@@ -0,0 +1,121 @@
+use core::{arch::asm, mem::offset_of};
+
+use bitfield_struct::bitfield;
+
+use super::gdt::Gdt;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+pub type Handler = extern "x86-interrupt" fn(InterruptFrame);
+pub type HandlerWithErrorCode = extern "x86-interrupt" fn(InterruptFrame, u64);
+
+#[repr(u8)]
+enum IdtGateType {
+    INTERRUPT = 0xE,
+    #[allow(unused)]
+    TRAP = 0xF,
+}
+
+#[bitfield(u128)]
+struct IdtGate {
+    pub offset_low: u16,
+    pub selector: u16,
+    #[bits(3)]
+    pub ist: u8,
+    #[bits(5)]
+    __: u8,
+    #[bits(4)]
+    pub gate_type: u8,
+    __: bool,
+    #[bits(2)]
+    pub dpl: u8,
+    pub present: bool,
+    #[bits(48)]
+    pub offset_high: u64,
+    __: u32,
+}
+
+impl IdtGate {
+    const NULL: Self = IdtGate::from_bits(0);
+
+    fn interrupt(addr: u64) -> Self {
+        Self::NULL
+            .with_offset_low(addr as u16)
+            .with_offset_high(addr >> 16)
+            .with_selector(Gdt::KERNEL_CODE_SELECTOR as u16)
+            .with_gate_type(IdtGateType::INTERRUPT as u8)
+            .with_dpl(0)
+            .with_present(true)
+    }
+}
+
+#[repr(C, align(16))]
+pub struct Idt([IdtGate; 256]);
+
+impl Idt {
+    pub const DIVIDE_ERROR: u8 = 0;
+    pub const DEBUG: u8 = 1;
+    pub const NMI: u8 = 2;
+    pub const BREAKPOINT: u8 = 3;
+    pub const OVERFLOW: u8 = 4;
+    pub const BOUND_RANGE: u8 = 5;
+    pub const INVALID_OPCODE: u8 = 6;
+    pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+    pub const DOUBLE_FAULT: u8 = 8;
+    pub const INVALID_TSS: u8 = 10;
+    pub const SEGMENT_NOT_PRESENT: u8 = 11;
+    pub const STACK_SEGMENT_FAULT: u8 = 12;
+    pub const GENERAL_PROTECTION: u8 = 13;
+    pub const PAGE_FAULT: u8 = 14;
+    pub const X87_FLOATING_POINT: u8 = 16;
+    pub const ALIGNMENT_CHECK: u8 = 17;
+    pub const MACHINE_CHECK: u8 = 18;
+    pub const SIMD_FLOATING_POINT: u8 = 19;
+
+    pub const fn new() -> Self {
+        Self([IdtGate::NULL; 256])
+    }
+
+    pub fn set_handler(&mut self, vector: u8, handler: Handler) {
+        self.0[vector as usize] = IdtGate::interrupt(handler as usize as u64);
+    }
+
+    pub fn set_handler_with_error_code(&mut self, vector: u8, handler: HandlerWithErrorCode) {
+        self.0[vector as usize] = IdtGate::interrupt(handler as usize as u64);
+    }
+
+    /// Makes [`vector`] switch to the stack in IST slot [`ist`] of the TSS,
+    /// or keep the current stack if it's 0.
+    pub fn set_ist(&mut self, vector: u8, ist: u8) {
+        self.0[vector as usize].set_ist(ist);
+    }
+
+    /// Safety: every present gate must point at a valid handler.
+    pub unsafe fn load(&'static self) {
+        let idtr = Idtr64 {
+            size: size_of::<Idt>() as u16 - 1,
+            offset: self as *const Self as u64,
+        };
+
+        unsafe { asm!("lidt [{}]", in(reg) &idtr, options(readonly, nostack, preserves_flags)) };
+    }
+}
+
+#[repr(C, packed)]
+pub struct Idtr64 {
+    pub size: u16,
+    pub offset: u64,
+}
+
+impl Idtr64 {
+    #[allow(unused)]
+    pub const IDTR_OFFSET: usize = offset_of!(Idtr64, offset);
+}
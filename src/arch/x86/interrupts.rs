@@ -0,0 +1,56 @@
+use core::arch::asm;
+
+use super::{idt::InterruptFrame, pic::{self, Pic8259}, registers::{RegisterRead, RFLAGS}};
+
+// no `nomem` on these, they double as compiler barriers for critical sections
+
+pub fn enable() {
+    unsafe { asm!("sti", options(nostack)) };
+}
+
+pub fn disable() {
+    unsafe { asm!("cli", options(nostack)) };
+}
+
+/// Enables interrupts and halts until the next one arrives. `sti` only takes
+/// effect after the following instruction, so an interrupt can't sneak in
+/// between the two and leave the CPU halted with work pending.
+pub fn enable_and_hlt() {
+    unsafe { asm!("sti", "hlt", options(nostack)) };
+}
+
+pub fn are_enabled() -> bool {
+    unsafe { RFLAGS.read() }.interrupt_enable()
+}
+
+/// Runs [`f`] with interrupts disabled, restoring the previous state after.
+pub fn without<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+
+    let r = f();
+
+    if enabled {
+        enable();
+    }
+
+    r
+}
+
+pub extern "x86-interrupt" fn spurious_master_handler(_frame: InterruptFrame) {
+    let pic = unsafe { pic::pic() };
+    if pic.is_in_service(Pic8259::IRQ_SPURIOUS_MASTER) {
+        pic.eoi(Pic8259::IRQ_SPURIOUS_MASTER);
+    }
+}
+
+pub extern "x86-interrupt" fn spurious_slave_handler(_frame: InterruptFrame) {
+    let pic = unsafe { pic::pic() };
+    if pic.is_in_service(Pic8259::IRQ_SPURIOUS_SLAVE) {
+        pic.eoi(Pic8259::IRQ_SPURIOUS_SLAVE);
+    } else {
+        pic.eoi(0);
+    }
+}
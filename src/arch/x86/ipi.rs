@@ -0,0 +1,154 @@
+//! Interrupts sent from one CPU to others, and running functions on other
+//! CPUs with them.
+//!
+//! Each CPU has a mailbox that one caller at a time can leave a function
+//! in before sending [`CALL_VECTOR`]. Callers wait for the functions to
+//! finish with interrupts off, emptying their own mailbox as they go, so
+//! two CPUs calling each other at once don't wait on each other forever.
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::sched;
+
+use super::{
+    apic::{self, DeliveryMode, Icr, Shorthand},
+    idt::InterruptFrame,
+    interrupts,
+    percpu::KernelEntry,
+    smp::{self, CpuMask, MAX_CPUS},
+};
+
+/// Tells a CPU to run what's in its mailbox.
+pub const CALL_VECTOR: u8 = 0xF0;
+/// Gets a CPU out of `hlt` to see whether what it was waiting for happened.
+pub const WAKE_VECTOR: u8 = 0xF1;
+
+/// Sends [`vector`] to CPU [`cpu`].
+pub fn send(cpu: usize, vector: u8) {
+    apic::send_ipi(Icr::ipi(DeliveryMode::FIXED, vector, smp::apic_id(cpu)));
+}
+
+/// Sends [`vector`] to every CPU, this one included.
+pub fn send_all(vector: u8) {
+    apic::send_ipi(Icr::ipi(DeliveryMode::FIXED, vector, 0).with_shorthand(Shorthand::ALL as u8));
+}
+
+/// Sends [`vector`] to every CPU but this one.
+pub fn send_all_but_self(vector: u8) {
+    apic::send_ipi(Icr::ipi(DeliveryMode::FIXED, vector, 0).with_shorthand(Shorthand::ALL_BUT_SELF as u8));
+}
+
+/// A function left in a mailbox, which points into the caller's stack.
+struct Call {
+    func: unsafe fn(*const ()),
+    arg: *const (),
+    /// Counts down as the CPUs finish.
+    remaining: *const AtomicUsize,
+}
+
+struct Mailbox {
+    /// Held by the caller filling the mailbox, until the call is done.
+    claimed: AtomicBool,
+    /// Set once [`Mailbox::call`] is filled in.
+    full: AtomicBool,
+    call: UnsafeCell<Option<Call>>,
+}
+
+/// [`Mailbox::call`] only changes hands through [`Mailbox::claimed`] and
+/// [`Mailbox::full`].
+unsafe impl Sync for Mailbox {}
+
+impl Mailbox {
+    const fn new() -> Self {
+        Self { claimed: AtomicBool::new(false), full: AtomicBool::new(false), call: UnsafeCell::new(None) }
+    }
+}
+
+static MAILBOXES: [Mailbox; MAX_CPUS] = [const { Mailbox::new() }; MAX_CPUS];
+
+/// Runs whatever's in this CPU's mailbox, if anything. Interrupts must be
+/// off.
+pub fn handle_mailbox() {
+    let mailbox = &MAILBOXES[smp::current_cpu()];
+    if !mailbox.full.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    let call = unsafe { (*mailbox.call.get()).take() }.unwrap();
+    unsafe {
+        (call.func)(call.arg);
+        // the caller can return as soon as this reaches 0, taking arg and
+        // remaining with it
+        (*call.remaining).fetch_sub(1, Ordering::Release);
+    }
+
+    mailbox.claimed.store(false, Ordering::Release);
+}
+
+unsafe fn call_closure<F: Fn()>(arg: *const ()) {
+    unsafe { (*arg.cast::<F>())() }
+}
+
+/// Runs [`f`] on every running CPU in [`cpus`], this one too if it's in
+/// there, and waits until they've all returned. [`f`] runs with interrupts
+/// off, in interrupt context on the other CPUs.
+pub fn call_on<F: Fn() + Sync>(cpus: CpuMask, f: F) {
+    interrupts::without(|| {
+        let this = smp::current_cpu();
+        let others = cpus.intersection(smp::online()).without(this);
+        let remaining = AtomicUsize::new(others.count());
+
+        for cpu in others.iter() {
+            let mailbox = &MAILBOXES[cpu];
+            while mailbox.claimed.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                handle_mailbox();
+                spin_loop();
+            }
+
+            let call = Call { func: call_closure::<F>, arg: &raw const f as *const (), remaining: &raw const remaining };
+            unsafe { *mailbox.call.get() = Some(call) };
+            mailbox.full.store(true, Ordering::Release);
+            send(cpu, CALL_VECTOR);
+        }
+
+        if cpus.contains(this) {
+            f();
+        }
+
+        while remaining.load(Ordering::Acquire) != 0 {
+            handle_mailbox();
+            spin_loop();
+        }
+    });
+}
+
+/// Runs [`f`] on CPU [`cpu`] and waits for it.
+pub fn call_on_cpu<F: Fn() + Sync>(cpu: usize, f: F) {
+    call_on(CpuMask::single(cpu), f);
+}
+
+/// Runs [`f`] on every CPU but this one and waits for them.
+pub fn call_on_others<F: Fn() + Sync>(f: F) {
+    interrupts::without(|| call_on(CpuMask::ALL.without(smp::current_cpu()), f));
+}
+
+/// Runs [`f`] on every CPU and waits for them.
+pub fn call_on_all<F: Fn() + Sync>(f: F) {
+    call_on(CpuMask::ALL, f);
+}
+
+pub extern "x86-interrupt" fn call_handler(frame: InterruptFrame) {
+    let _entry = KernelEntry::new(&frame);
+    handle_mailbox();
+    apic::eoi();
+}
+
+pub extern "x86-interrupt" fn wake_handler(frame: InterruptFrame) {
+    let _entry = KernelEntry::new(&frame);
+    apic::eoi();
+    sched::preempt_if_needed();
+}
@@ -1,8 +1,9 @@
-// TODO: 2m, 4m, and 1g pages
-
 #![allow(unused)]
 
 use bitfield_struct::bitfield;
+
+use super::cpu::{self, CpuFeatures};
+
 #[bitfield(u64)]
 pub struct Pml5te4k {
     pub present: bool,
@@ -75,8 +76,10 @@ pub struct Pdpte4k {
     pub write_through: bool,
     pub cache_disable: bool,
     pub accessed: bool,
-    #[bits(3)]
-    __: u64,
+    __: bool,
+    /// Set if this entry maps a huge page rather than pointing to a table.
+    pub page_size: bool,
+    __: bool,
     #[bits(3)]
     pub available_2: u64,
     #[bits(40)]
@@ -99,16 +102,73 @@ impl Pdpte4k {
     }
 }
 
+/// A PDPT entry mapping a 1 GiB page directly. Only valid if the CPU
+/// supports it; see [`PageSize::SIZE_1G`].
 #[bitfield(u64)]
-pub struct Pdte4k {
+pub struct Pdpte1g {
     pub present: bool,
     pub rw: bool,
     pub user: bool,
     pub write_through: bool,
     pub cache_disable: bool,
     pub accessed: bool,
+    pub dirty: bool,
+    pub page_size: bool,
+    pub global: bool,
     #[bits(3)]
+    pub available_2: u64,
+    pub page_attribute: bool,
+    #[bits(17)]
     __: u64,
+    #[bits(22)]
+    pub page_paddr: u64,
+    #[bits(7)]
+    pub available_3: u64,
+    #[bits(4)]
+    pub mkp: u64,
+    pub nx: bool,
+}
+
+impl Pdpte1g {
+    pub const fn init() -> Self {
+        Self::from_bits(0)
+            .with_present(true)
+            .with_page_size(true)
+            .with_rw(false)
+            .with_user(false)
+            .with_write_through(false)
+            .with_cache_disable(false)
+            .with_accessed(false)
+            .with_page_attribute(false)
+            .with_nx(false)
+    }
+}
+
+impl From<Pdpte1g> for Pdpte4k {
+    fn from(value: Pdpte1g) -> Self {
+        Self::from_bits(value.into_bits())
+    }
+}
+
+impl Pdpte4k {
+    /// Returns [`None`] if this entry points to a page directory instead.
+    pub const fn huge(self) -> Option<Pdpte1g> {
+        if self.page_size() { Some(Pdpte1g::from_bits(self.into_bits())) } else { None }
+    }
+}
+
+#[bitfield(u64)]
+pub struct Pdte4k {
+    pub present: bool,
+    pub rw: bool,
+    pub user: bool,
+    pub write_through: bool,
+    pub cache_disable: bool,
+    pub accessed: bool,
+    __: bool,
+    /// Set if this entry maps a huge page rather than pointing to a table.
+    pub page_size: bool,
+    __: bool,
     #[bits(3)]
     pub available_2: u64,
     #[bits(40)]
@@ -119,7 +179,7 @@ pub struct Pdte4k {
 }
 
 impl Pdte4k {
-    const fn init() -> Self {
+    pub const fn init() -> Self {
         Self::from_bits(0)
             .with_present(true)
             .with_rw(false)
@@ -131,6 +191,60 @@ impl Pdte4k {
     }
 }
 
+/// A page directory entry mapping a 2 MiB page directly.
+#[bitfield(u64)]
+pub struct Pdte2m {
+    pub present: bool,
+    pub rw: bool,
+    pub user: bool,
+    pub write_through: bool,
+    pub cache_disable: bool,
+    pub accessed: bool,
+    pub dirty: bool,
+    pub page_size: bool,
+    pub global: bool,
+    #[bits(3)]
+    pub available_2: u64,
+    pub page_attribute: bool,
+    #[bits(8)]
+    __: u64,
+    #[bits(31)]
+    pub page_paddr: u64,
+    #[bits(7)]
+    pub available_3: u64,
+    #[bits(4)]
+    pub mkp: u64,
+    pub nx: bool,
+}
+
+impl Pdte2m {
+    pub const fn init() -> Self {
+        Self::from_bits(0)
+            .with_present(true)
+            .with_page_size(true)
+            .with_rw(false)
+            .with_user(false)
+            .with_write_through(false)
+            .with_cache_disable(false)
+            .with_accessed(false)
+            .with_page_attribute(false)
+            .with_nx(false)
+    }
+}
+
+impl From<Pdte2m> for Pdte4k {
+    fn from(value: Pdte2m) -> Self {
+        Self::from_bits(value.into_bits())
+    }
+}
+
+impl Pdte4k {
+    /// Returns [`None`] if this entry points to a page table instead.
+    pub const fn huge(self) -> Option<Pdte2m> {
+        if self.page_size() { Some(Pdte2m::from_bits(self.into_bits())) } else { None }
+    }
+}
+
 #[bitfield(u64)]
 pub struct Pte {
     pub present: bool,
@@ -178,6 +292,22 @@ pub struct PageDirectoryTable4k(pub [Pdte4k; 512]);
 #[repr(align(4096))]
 pub struct PageTable(pub [Pte; 512]);
 
+impl PageDirectoryTable4k {
+    /// Identity maps the first 1 GiB with 2 MiB pages, except the first
+    /// entry, which is left for a [`PageTable`] so that page 0 can stay
+    /// unmapped.
+    pub const fn identity() -> Self {
+        let mut pd = [Pdte4k::from_bits(0); 512];
+        let mut i = 1;
+        while i < pd.len() {
+            pd[i] = Pdte4k::from_bits(Pdte2m::init().with_rw(true).with_page_paddr(i as _).into_bits());
+            i += 1;
+        }
+
+        Self(pd)
+    }
+}
+
 impl PageTable {
     pub const fn identity() -> Self {
         let mut pt = [Pte::init(); 512];
@@ -192,3 +322,49 @@ impl PageTable {
         Self(pt)
     }
 }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    SIZE_4K,
+    SIZE_2M,
+    SIZE_1G,
+}
+
+impl PageSize {
+    /// Largest first.
+    pub const ALL: [Self; 3] = [Self::SIZE_1G, Self::SIZE_2M, Self::SIZE_4K];
+
+    pub const fn shift(self) -> u32 {
+        match self {
+            Self::SIZE_4K => 12,
+            Self::SIZE_2M => 21,
+            Self::SIZE_1G => 30,
+        }
+    }
+
+    pub const fn bytes(self) -> u64 {
+        1 << self.shift()
+    }
+
+    /// Whether the CPU can map pages of this size. 2 MiB pages are
+    /// architectural in long mode, but 1 GiB pages need `PDPE1GB`.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Self::SIZE_4K | Self::SIZE_2M => true,
+            Self::SIZE_1G => cpu::has(CpuFeatures::PDPE1GB),
+        }
+    }
+    /// Picks the largest supported page size that can map the start of a
+    /// region of [`len`] bytes from [`vaddr`] to [`paddr`]. Both addresses
+    /// must be aligned to it and the region must be at least that long.
+    pub fn largest_fitting(vaddr: u64, paddr: u64, len: u64) -> Self {
+        for size in Self::ALL {
+            let mask = size.bytes() - 1;
+            if vaddr & mask == 0 && paddr & mask == 0 && len >= size.bytes() && size.is_supported() {
+                return size;
+            }
+        }
+
+        Self::SIZE_4K
+    }
+}
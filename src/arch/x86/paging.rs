@@ -0,0 +1,648 @@
+//! Mapping virtual memory through the page tables.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use bitflags::bitflags;
+
+use crate::memory::{frame, phys_to_virt, PAGE_SIZE};
+
+use super::{
+    cpu::{self, CpuFeatures},
+    pages::{
+        PageDirectoryPointerTable4k, PageDirectoryTable4k, PageSize, PageTable, Pdpte4k, Pdte4k, Pml4Table4k, Pml4te4k,
+        Pml5Table4k, Pml5te4k, Pte,
+    },
+    pat::CacheMode,
+    registers::{Cr3, RegisterRead, RegisterWrite, CR0, CR3, CR4, IA32_EFER},
+    smp::{self, CpuMask},
+    tlb::{self, Flush},
+};
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const ENTRY_PAGE_SIZE: u64 = 1 << 7;
+/// PAT bit of a 4 KiB page. In a huge page, bit 7 is the page size bit and
+/// the PAT bit moves to bit 12.
+const ENTRY_PAT_4K: u64 = 1 << 7;
+const ENTRY_PAT_HUGE: u64 = 1 << 12;
+/// Physical address bits of an entry, for any page size.
+const ENTRY_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+bitflags! {
+    /// Permissions and caching of a mapping. The bits are where they sit in
+    /// a leaf entry of a huge page; for 4 KiB pages [`PageFlags::PAT`] moves
+    /// to bit 7. The caching bits are easier set with
+    /// [`PageFlags::with_cache_mode`].
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct PageFlags: u64 {
+        const WRITABLE = 1 << 1;
+        const USER = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const CACHE_DISABLE = 1 << 4;
+        const GLOBAL = 1 << 8;
+        /// Software bit, the first of [`Pte::available_2`]: the page is
+        /// shared and read-only until written, when it gets its own copy.
+        const COPY_ON_WRITE = 1 << 9;
+        const PAT = 1 << 12;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+impl PageFlags {
+    const CACHE_BITS: Self = Self::WRITE_THROUGH.union(Self::CACHE_DISABLE).union(Self::PAT);
+
+    pub const fn with_cache_mode(self, mode: CacheMode) -> Self {
+        let index = mode.pat_index();
+        let mut flags = self.difference(Self::CACHE_BITS);
+        if index & 1 != 0 {
+            flags = flags.union(Self::WRITE_THROUGH);
+        }
+
+        if index & 2 != 0 {
+            flags = flags.union(Self::CACHE_DISABLE);
+        }
+
+        if index & 4 != 0 {
+            flags = flags.union(Self::PAT);
+        }
+
+        flags
+    }
+
+    pub fn cache_mode(self) -> CacheMode {
+        let index = self.contains(Self::WRITE_THROUGH) as u8
+            | (self.contains(Self::CACHE_DISABLE) as u8) << 1
+            | (self.contains(Self::PAT) as u8) << 2;
+        CacheMode::from_pat_index(index)
+    }
+
+    /// The flags as they're stored in a leaf entry for a page of [`size`].
+    fn entry_bits(self, size: PageSize) -> u64 {
+        let bits = supported(self).bits();
+        if size == PageSize::SIZE_4K && bits & ENTRY_PAT_HUGE != 0 {
+            bits & !ENTRY_PAT_HUGE | ENTRY_PAT_4K
+        } else {
+            bits
+        }
+    }
+
+    fn from_entry_bits(bits: u64, size: PageSize) -> Self {
+        if size == PageSize::SIZE_4K {
+            let pat = if bits & ENTRY_PAT_4K != 0 { Self::PAT } else { Self::empty() };
+            Self::from_bits_truncate(bits & !ENTRY_ADDR_MASK) | pat
+        } else {
+            Self::from_bits_truncate(bits)
+        }
+    }
+
+    /// Bits of a leaf entry for a page of [`size`] that hold flags.
+    fn entry_mask(size: PageSize) -> u64 {
+        Self::all().entry_bits(size)
+    }
+}
+
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on no-execute pages if the CPU has them, and makes ring 0 respect
+/// read-only pages. Until then [`PageFlags::NO_EXECUTE`] is dropped, since
+/// the bit is reserved while EFER.NXE is clear. Global pages and PCIDs are
+/// turned on here too, through [`tlb::init`].
+pub fn enable_protection() {
+    let has_nx = cpu::has(CpuFeatures::NX);
+
+    unsafe {
+        if has_nx {
+            IA32_EFER.write(IA32_EFER.read().with_nxe(true));
+        }
+
+        CR0.write(CR0.read().with_wp(true));
+        tlb::init();
+    }
+
+    NX_ENABLED.store(has_nx, Ordering::Relaxed);
+}
+
+fn supported(flags: PageFlags) -> PageFlags {
+    if NX_ENABLED.load(Ordering::Relaxed) { flags } else { flags - PageFlags::NO_EXECUTE }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagingLevels {
+    FOUR,
+    FIVE,
+}
+
+impl PagingLevels {
+    /// What the boot code picked, going by CR4.LA57.
+    pub fn current() -> Self {
+        if unsafe { CR4.read() }.la57() { Self::FIVE } else { Self::FOUR }
+    }
+
+    pub const fn depth(self) -> u32 {
+        match self {
+            Self::FOUR => 4,
+            Self::FIVE => 5,
+        }
+    }
+
+    /// Number of significant bits in a virtual address.
+    pub const fn vaddr_bits(self) -> u32 {
+        12 + 9 * self.depth()
+    }
+
+    /// Sign-extends the significant bits of [`vaddr`].
+    pub const fn canonicalize(self, vaddr: u64) -> u64 {
+        let shift = 64 - self.vaddr_bits();
+        ((vaddr << shift) as i64 >> shift) as u64
+    }
+
+    pub const fn is_canonical(self, vaddr: u64) -> bool {
+        self.canonicalize(vaddr) == vaddr
+    }
+
+    /// First address past the lower half.
+    pub const fn lower_half_end(self) -> u64 {
+        1 << (self.vaddr_bits() - 1)
+    }
+
+    /// First address of the higher half.
+    pub const fn higher_half_start(self) -> u64 {
+        self.canonicalize(self.lower_half_end())
+    }
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    NonCanonical,
+    Misaligned,
+    UnsupportedPageSize,
+    AlreadyMapped,
+    NotMapped,
+    OutOfFrames,
+    OutOfVirtualMemory,
+}
+
+/// A single mapped page.
+#[derive(Copy, Clone, Debug)]
+pub struct Mapping {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub size: PageSize,
+    pub flags: PageFlags,
+}
+
+/// An entry pointing to the next level of the page tables.
+trait TableEntry: Copy {
+    /// Returns [`None`] if the entry isn't present.
+    fn table_paddr(self) -> Option<u64>;
+    fn table(paddr: u64) -> Self;
+}
+
+// Permissions are left wide open above the leaves, so that the leaf entry
+// alone decides them.
+
+impl TableEntry for Pml5te4k {
+    fn table_paddr(self) -> Option<u64> {
+        if self.present() { Some(self.pml4_paddr() << 12) } else { None }
+    }
+
+    fn table(paddr: u64) -> Self {
+        Self::init().with_rw(true).with_user(true).with_pml4_paddr(paddr >> 12)
+    }
+}
+
+impl TableEntry for Pml4te4k {
+    fn table_paddr(self) -> Option<u64> {
+        if self.present() { Some(self.pdp_paddr() << 12) } else { None }
+    }
+
+    fn table(paddr: u64) -> Self {
+        Self::init().with_rw(true).with_user(true).with_pdp_paddr(paddr >> 12)
+    }
+}
+
+impl TableEntry for Pdpte4k {
+    fn table_paddr(self) -> Option<u64> {
+        if self.present() && !self.page_size() { Some(self.pd_paddr() << 12) } else { None }
+    }
+
+    fn table(paddr: u64) -> Self {
+        Self::init().with_rw(true).with_user(true).with_pd_paddr(paddr >> 12)
+    }
+}
+
+impl TableEntry for Pdte4k {
+    fn table_paddr(self) -> Option<u64> {
+        if self.present() && !self.page_size() { Some(self.pt_paddr() << 12) } else { None }
+    }
+
+    fn table(paddr: u64) -> Self {
+        Self::init().with_rw(true).with_user(true).with_pt_paddr(paddr >> 12)
+    }
+}
+
+fn table<T>(paddr: u64) -> &'static mut T {
+    unsafe { &mut *phys_to_virt::<T>(paddr) }
+}
+
+/// Index into the table at [`level`] for [`vaddr`], where level 1 is the
+/// page table and level 5 the PML5.
+pub const fn index(vaddr: u64, level: u32) -> usize {
+    (vaddr >> (12 + 9 * (level - 1))) as usize & 511
+}
+
+/// Follows [`entry`] to the table it points to, first allocating an empty
+/// one if it isn't present and [`create`] is set.
+fn next<E: TableEntry, T>(entry: &mut E, create: bool) -> Result<&'static mut T, MapError> {
+    if let Some(paddr) = entry.table_paddr() {
+        return Ok(table(paddr));
+    }
+
+    if !create {
+        return Err(MapError::NotMapped);
+    }
+
+    let paddr = frame::alloc().ok_or(MapError::OutOfFrames)?;
+    unsafe { core::ptr::write_bytes(phys_to_virt::<u8>(paddr), 0, PAGE_SIZE as usize) };
+    *entry = E::table(paddr);
+    Ok(table(paddr))
+}
+
+/// The entry that maps a page, at whichever level it lives.
+enum Leaf {
+    Huge1g(&'static mut Pdpte4k),
+    Huge2m(&'static mut Pdte4k),
+    Page(&'static mut Pte),
+}
+
+impl Leaf {
+    fn size(&self) -> PageSize {
+        match self {
+            Self::Huge1g(_) => PageSize::SIZE_1G,
+            Self::Huge2m(_) => PageSize::SIZE_2M,
+            Self::Page(_) => PageSize::SIZE_4K,
+        }
+    }
+
+    fn bits(&self) -> u64 {
+        match self {
+            Self::Huge1g(e) => e.into_bits(),
+            Self::Huge2m(e) => e.into_bits(),
+            Self::Page(e) => e.into_bits(),
+        }
+    }
+
+    fn set_bits(&mut self, bits: u64) {
+        match self {
+            Self::Huge1g(e) => **e = Pdpte4k::from_bits(bits),
+            Self::Huge2m(e) => **e = Pdte4k::from_bits(bits),
+            Self::Page(e) => **e = Pte::from_bits(bits),
+        }
+    }
+
+    fn mapping(&self, vaddr: u64) -> Mapping {
+        let size = self.size();
+        let bits = self.bits();
+        Mapping {
+            vaddr: vaddr & !(size.bytes() - 1),
+            paddr: bits & ENTRY_ADDR_MASK & !(size.bytes() - 1),
+            size,
+            flags: PageFlags::from_entry_bits(bits, size),
+        }
+    }
+}
+
+pub struct AddressSpace {
+    /// Physical address of the top-level table.
+    root: u64,
+    levels: PagingLevels,
+    /// Tags this address space's TLB entries while PCIDs are on.
+    pcid: u16,
+    /// The CPUs that have loaded this address space, whose TLBs might hold
+    /// its entries.
+    cpus: AtomicU64,
+}
+
+impl AddressSpace {
+    /// Creates an empty address space.
+    pub fn new(levels: PagingLevels) -> Result<Self, MapError> {
+        let root = frame::alloc().ok_or(MapError::OutOfFrames)?;
+        unsafe { core::ptr::write_bytes(phys_to_virt::<u8>(root), 0, PAGE_SIZE as usize) };
+        Ok(Self { root, levels, pcid: tlb::alloc_pcid(), cpus: AtomicU64::new(0) })
+    }
+
+    /// Safety: the returned [`AddressSpace`] aliases the active page tables,
+    /// so only one should be modifying them at a time.
+    pub unsafe fn current() -> Self {
+        let cr3 = unsafe { CR3.read() };
+        let pcid = if tlb::pcids_enabled() { cr3.pcid() } else { 0 };
+        // no telling where else it's loaded
+        Self { root: cr3.root_paddr() << 12, levels: PagingLevels::current(), pcid, cpus: AtomicU64::new(CpuMask::ALL.0) }
+    }
+
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    pub fn levels(&self) -> PagingLevels {
+        self.levels
+    }
+
+    pub fn is_active(&self) -> bool {
+        unsafe { CR3.read() }.root_paddr() << 12 == self.root
+    }
+
+    /// Allocates every table missing from the higher half of the root
+    /// table. Done for the kernel's address space, since
+    /// [`share_higher_half`](Self::share_higher_half) only copies root
+    /// entries, and kernel mappings made later have to land in tables every
+    /// address space already points to.
+    pub fn populate_higher_half(&mut self) -> Result<(), MapError> {
+        match self.levels {
+            PagingLevels::FOUR => {
+                let pml4: &mut Pml4Table4k = table(self.root);
+                for entry in &mut pml4.0[256..] {
+                    next::<_, PageDirectoryPointerTable4k>(entry, true)?;
+                }
+            },
+            PagingLevels::FIVE => {
+                let pml5: &mut Pml5Table4k = table(self.root);
+                for entry in &mut pml5.0[256..] {
+                    next::<_, Pml4Table4k>(entry, true)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Points the higher half of this address space at the same tables as
+    /// [`from`], so that both see the same kernel mappings.
+    pub fn share_higher_half(&mut self, from: &Self) {
+        let src: &mut [u64; 512] = table(from.root);
+        let dst: &mut [u64; 512] = table(self.root);
+        dst[256..].copy_from_slice(&src[256..]);
+    }
+
+    /// Safety: everything the kernel is using, including the code doing
+    /// the switch, must be mapped the same way in this address space.
+    pub unsafe fn activate(&self) {
+        self.cpus.fetch_or(CpuMask::single(smp::current_cpu()).0, Ordering::Relaxed);
+
+        let cr3 = Cr3::new().with_root_paddr(self.root >> 12);
+        let cr3 = if tlb::pcids_enabled() && self.pcid != 0 {
+            // whatever this CPU still has under the PCID was kept up to date
+            // by shootdowns
+            cr3.with_pcid(self.pcid).with_no_flush(true)
+        } else {
+            cr3
+        };
+
+        unsafe { CR3.write(cr3) };
+    }
+
+    /// The higher half is in every address space, so its pages are global
+    /// and stay cached across switches.
+    fn leaf_flags(&self, vaddr: u64, flags: PageFlags) -> PageFlags {
+        if vaddr >= self.levels.higher_half_start() { flags | PageFlags::GLOBAL } else { flags }
+    }
+
+    /// Flushes [`pages`] pages from [`vaddr`] out of every TLB that might
+    /// have them.
+    fn invalidate(&self, vaddr: u64, pages: u64) {
+        let flush = Flush {
+            root: self.root,
+            pcid: self.pcid,
+            vaddr,
+            pages,
+            global: vaddr >= self.levels.higher_half_start(),
+        };
+
+        tlb::shootdown(CpuMask(self.cpus.load(Ordering::Relaxed)), flush);
+    }
+
+    fn pdpt(&self, vaddr: u64, create: bool) -> Result<&'static mut PageDirectoryPointerTable4k, MapError> {
+        if !self.levels.is_canonical(vaddr) {
+            return Err(MapError::NonCanonical);
+        }
+
+        let pml4: &mut Pml4Table4k = match self.levels {
+            PagingLevels::FOUR => table(self.root),
+            PagingLevels::FIVE => {
+                let pml5: &mut Pml5Table4k = table(self.root);
+                next(&mut pml5.0[index(vaddr, 5)], create)?
+            },
+        };
+
+        next(&mut pml4.0[index(vaddr, 4)], create)
+    }
+
+    fn leaf(&self, vaddr: u64) -> Result<Leaf, MapError> {
+        let pdpte = &mut self.pdpt(vaddr, false)?.0[index(vaddr, 3)];
+        if pdpte.present() && pdpte.page_size() {
+            return Ok(Leaf::Huge1g(pdpte));
+        }
+
+        let pd: &mut PageDirectoryTable4k = next(pdpte, false)?;
+        let pdte = &mut pd.0[index(vaddr, 2)];
+        if pdte.present() && pdte.page_size() {
+            return Ok(Leaf::Huge2m(pdte));
+        }
+
+        let pt: &mut PageTable = next(pdte, false)?;
+        let pte = &mut pt.0[index(vaddr, 1)];
+        if !pte.present() {
+            return Err(MapError::NotMapped);
+        }
+
+        Ok(Leaf::Page(pte))
+    }
+
+    /// Maps one page of [`size`] at [`vaddr`] to [`paddr`], allocating any
+    /// missing tables. Fails if anything is already mapped there.
+    pub fn map(&mut self, vaddr: u64, paddr: u64, size: PageSize, flags: PageFlags) -> Result<(), MapError> {
+        if (vaddr | paddr) & (size.bytes() - 1) != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        if !size.is_supported() {
+            return Err(MapError::UnsupportedPageSize);
+        }
+
+        let huge = if size == PageSize::SIZE_4K { 0 } else { ENTRY_PAGE_SIZE };
+        let bits = paddr | self.leaf_flags(vaddr, flags).entry_bits(size) | huge | ENTRY_PRESENT;
+
+        let pdpte = &mut self.pdpt(vaddr, true)?.0[index(vaddr, 3)];
+        if size == PageSize::SIZE_1G {
+            if pdpte.present() {
+                return Err(MapError::AlreadyMapped);
+            }
+
+            *pdpte = Pdpte4k::from_bits(bits);
+            return Ok(());
+        }
+
+        if pdpte.present() && pdpte.page_size() {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        let pd: &mut PageDirectoryTable4k = next(pdpte, true)?;
+        let pdte = &mut pd.0[index(vaddr, 2)];
+        if size == PageSize::SIZE_2M {
+            if pdte.present() {
+                return Err(MapError::AlreadyMapped);
+            }
+
+            *pdte = Pdte4k::from_bits(bits);
+            return Ok(());
+        }
+
+        if pdte.present() && pdte.page_size() {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        let pt: &mut PageTable = next(pdte, true)?;
+        let pte = &mut pt.0[index(vaddr, 1)];
+        if pte.present() {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        *pte = Pte::from_bits(bits);
+        Ok(())
+    }
+
+    /// Unmaps the page containing [`vaddr`] and returns what was mapped. The
+    /// frame isn't freed.
+    pub fn unmap(&mut self, vaddr: u64) -> Result<Mapping, MapError> {
+        let mut leaf = self.leaf(vaddr)?;
+        let mapping = leaf.mapping(vaddr);
+        leaf.set_bits(0);
+        self.invalidate(mapping.vaddr, mapping.size.bytes() / PAGE_SIZE);
+        Ok(mapping)
+    }
+
+    /// Changes the flags of the page containing [`vaddr`].
+    pub fn protect(&mut self, vaddr: u64, flags: PageFlags) -> Result<Mapping, MapError> {
+        let mut leaf = self.leaf(vaddr)?;
+        let size = leaf.size();
+        let flags = self.leaf_flags(vaddr, flags);
+        leaf.set_bits(leaf.bits() & !PageFlags::entry_mask(size) | flags.entry_bits(size));
+        let mapping = leaf.mapping(vaddr);
+        self.invalidate(mapping.vaddr, size.bytes() / PAGE_SIZE);
+        Ok(mapping)
+    }
+
+    /// Returns the page containing [`vaddr`].
+    pub fn translate_page(&self, vaddr: u64) -> Result<Mapping, MapError> {
+        Ok(self.leaf(vaddr)?.mapping(vaddr))
+    }
+
+    pub fn translate(&self, vaddr: u64) -> Option<u64> {
+        let mapping = self.translate_page(vaddr).ok()?;
+        Some(mapping.paddr + (vaddr - mapping.vaddr))
+    }
+
+    /// Maps [`len`] bytes from [`vaddr`] to [`paddr`] with the largest pages
+    /// that fit. On failure nothing stays mapped.
+    pub fn map_range(&mut self, vaddr: u64, paddr: u64, len: u64, flags: PageFlags) -> Result<(), MapError> {
+        if (vaddr | paddr | len) & (PAGE_SIZE - 1) != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let mut done = 0;
+        while done < len {
+            let size = PageSize::largest_fitting(vaddr + done, paddr + done, len - done);
+            if let Err(e) = self.map(vaddr + done, paddr + done, size, flags) {
+                let _ = self.unmap_range(vaddr, done);
+                return Err(e);
+            }
+
+            done += size.bytes();
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps everything in [`len`] bytes from [`vaddr`]. Holes are skipped.
+    pub fn unmap_range(&mut self, vaddr: u64, len: u64) -> Result<(), MapError> {
+        self.update_range(vaddr, len, |_, _| 0)
+    }
+
+    /// Changes the flags of everything mapped in [`len`] bytes from
+    /// [`vaddr`]. Holes are skipped.
+    pub fn protect_range(&mut self, vaddr: u64, len: u64, flags: PageFlags) -> Result<(), MapError> {
+        let flags = self.leaf_flags(vaddr, flags);
+        self.update_range(vaddr, len, |bits, size| bits & !PageFlags::entry_mask(size) | flags.entry_bits(size))
+    }
+
+    /// Replaces the leaf entry of each mapped page in the range with the
+    /// result of [`f`], splitting huge pages that stick out of the range.
+    /// The TLBs are flushed once, at the end.
+    fn update_range(&mut self, vaddr: u64, len: u64, f: impl Fn(u64, PageSize) -> u64) -> Result<(), MapError> {
+        if (vaddr | len) & (PAGE_SIZE - 1) != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let mut done = 0;
+        while done < len {
+            let v = vaddr.wrapping_add(done);
+            let mut leaf = match self.leaf(v) {
+                Ok(leaf) => leaf,
+                Err(MapError::NotMapped) => {
+                    done += PAGE_SIZE;
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+
+            let size = leaf.size();
+            if v & (size.bytes() - 1) != 0 || len - done < size.bytes() {
+                self.split(v)?;
+                continue;
+            }
+
+            leaf.set_bits(f(leaf.bits(), size));
+            done += size.bytes();
+        }
+
+        self.invalidate(vaddr, len / PAGE_SIZE);
+        Ok(())
+    }
+
+    /// Breaks the huge page containing [`vaddr`] into a table of pages of the
+    /// next size down, mapped the same way.
+    fn split(&mut self, vaddr: u64) -> Result<(), MapError> {
+        let leaf = self.leaf(vaddr)?;
+        let mapping = leaf.mapping(vaddr);
+        let bits = leaf.bits();
+
+        let paddr = frame::alloc().ok_or(MapError::OutOfFrames)?;
+        match leaf {
+            Leaf::Huge1g(pdpte) => {
+                let pd: &mut PageDirectoryTable4k = table(paddr);
+                let attrs = bits & !ENTRY_ADDR_MASK | bits & ENTRY_PAT_HUGE;
+                for (i, pdte) in pd.0.iter_mut().enumerate() {
+                    *pdte = Pdte4k::from_bits((mapping.paddr + i as u64 * PageSize::SIZE_2M.bytes()) | attrs);
+                }
+
+                *pdpte = Pdpte4k::table(paddr);
+            },
+            Leaf::Huge2m(pdte) => {
+                let pt: &mut PageTable = table(paddr);
+                let pat = if bits & ENTRY_PAT_HUGE != 0 { ENTRY_PAT_4K } else { 0 };
+                let attrs = bits & !ENTRY_ADDR_MASK & !ENTRY_PAGE_SIZE | pat;
+                for (i, pte) in pt.0.iter_mut().enumerate() {
+                    *pte = Pte::from_bits((mapping.paddr + i as u64 * PAGE_SIZE) | attrs);
+                }
+
+                *pdte = Pdte4k::table(paddr);
+            },
+            Leaf::Page(_) => {
+                unsafe { frame::free(paddr) };
+                return Ok(());
+            },
+        }
+
+        self.invalidate(mapping.vaddr, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! The page attribute table, which decides what the cache bits of a page
+//! table entry mean.
+
+use core::arch::asm;
+
+use super::registers::{RegisterWrite, IA32_PAT};
+
+const PAT_UNCACHED: u8 = 0x00;
+const PAT_WRITE_COMBINING: u8 = 0x01;
+const PAT_WRITE_THROUGH: u8 = 0x04;
+const PAT_WRITE_PROTECT: u8 = 0x05;
+const PAT_WRITE_BACK: u8 = 0x06;
+const PAT_UNCACHED_MINUS: u8 = 0x07;
+
+/// The first four entries are the power-on defaults, so that entries built
+/// before [`init`] keep their meaning.
+const PAT: [u8; 8] = [
+    PAT_WRITE_BACK,
+    PAT_WRITE_THROUGH,
+    PAT_UNCACHED_MINUS,
+    PAT_UNCACHED,
+    PAT_WRITE_COMBINING,
+    PAT_WRITE_PROTECT,
+    PAT_UNCACHED_MINUS,
+    PAT_UNCACHED,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    WRITE_BACK,
+    WRITE_THROUGH,
+    /// Uncached, but an MTRR can still make it write-combining.
+    UNCACHED_MINUS,
+    UNCACHED,
+    /// Uncached, but writes are buffered and may be combined. Best for
+    /// framebuffers.
+    WRITE_COMBINING,
+    WRITE_PROTECT,
+}
+
+impl CacheMode {
+    /// Index of this mode in the PAT, which is what a page table entry's
+    /// PAT, PCD, and PWT bits spell out.
+    pub const fn pat_index(self) -> u8 {
+        match self {
+            Self::WRITE_BACK => 0,
+            Self::WRITE_THROUGH => 1,
+            Self::UNCACHED_MINUS => 2,
+            Self::UNCACHED => 3,
+            Self::WRITE_COMBINING => 4,
+            Self::WRITE_PROTECT => 5,
+        }
+    }
+
+    pub const fn from_pat_index(index: u8) -> Self {
+        match PAT[index as usize & 7] {
+            PAT_WRITE_BACK => Self::WRITE_BACK,
+            PAT_WRITE_THROUGH => Self::WRITE_THROUGH,
+            PAT_UNCACHED_MINUS => Self::UNCACHED_MINUS,
+            PAT_WRITE_COMBINING => Self::WRITE_COMBINING,
+            PAT_WRITE_PROTECT => Self::WRITE_PROTECT,
+            _ => Self::UNCACHED,
+        }
+    }
+}
+
+/// Programs the PAT. Every CPU needs this done before it uses mappings with
+/// the PAT bit set.
+pub fn init() {
+    unsafe {
+        IA32_PAT.write(u64::from_le_bytes(PAT));
+        // cached lines may have been filled under the old attributes
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+}
@@ -0,0 +1,272 @@
+//! Per-CPU variables. [`per_cpu!`](crate::per_cpu) puts them in the
+//! `.percpu` section, which only holds their initial values: every CPU gets
+//! its own copy of the section with its GS base pointing at it, and reaches
+//! a variable by its offset into the section.
+//!
+//! The kernel runs with its per-CPU area in GS base. Coming from user mode,
+//! GS base is still the user's and IA32_KERNEL_GS_BASE holds the kernel's,
+//! until a [`KernelEntry`] swaps them.
+
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    mem::transmute_copy,
+    ptr::{addr_of, null_mut},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    common::LinkerSymbol,
+    memory::vma::{self, VmError},
+};
+
+use super::{
+    cpu::{self, CpuFeatures},
+    gdt::Tss,
+    idt::InterruptFrame,
+    registers::{RegisterRead, RegisterWrite, CR4, IA32_GS_BASE, IA32_KERNEL_GS_BASE},
+    smp::MAX_CPUS,
+};
+
+unsafe extern "C" {
+    static PERCPU_START: LinkerSymbol;
+    static PERCPU_END: LinkerSymbol;
+    static BOOT_PERCPU: LinkerSymbol;
+}
+
+/// A variable declared with [`per_cpu!`](crate::per_cpu). The static itself
+/// is only the template; use [`PerCpu::get`], [`PerCpu::set`] or
+/// [`PerCpu::as_ptr`] for this CPU's copy.
+#[repr(transparent)]
+pub struct PerCpu<T>(UnsafeCell<T>);
+
+/// Each CPU only touches its own copy, unless it goes through
+/// [`PerCpu::for_cpu`].
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    fn offset(&self) -> u64 {
+        self.0.get() as u64 - addr_of!(PERCPU_START) as u64
+    }
+
+    /// This CPU's copy. It only stays this CPU's for as long as the caller
+    /// can't be moved to another, with interrupts or preemption off.
+    pub fn as_ptr(&self) -> *mut T {
+        (AREA_BASE.get() + self.offset()) as *mut T
+    }
+
+    /// CPU [`cpu`]'s copy.
+    pub fn for_cpu(&self, cpu: usize) -> *mut T {
+        let base = AREAS[cpu].load(Ordering::Acquire);
+        assert!(base != 0, "CPU {cpu} has no per-CPU area");
+        (base + self.offset()) as *mut T
+    }
+
+    /// Reads this CPU's copy. Anything register-sized takes a single
+    /// GS-relative load, which moving to another CPU can't tear.
+    pub fn get(&self) -> T
+    where
+        T: Copy,
+    {
+        let offset = self.offset();
+        unsafe {
+            match size_of::<T>() {
+                1 => {
+                    let v: u8;
+                    asm!("mov {}, byte ptr gs:[{}]", out(reg_byte) v, in(reg) offset, options(readonly, nostack, preserves_flags));
+                    transmute_copy(&v)
+                },
+                2 => {
+                    let v: u16;
+                    asm!("mov {:x}, word ptr gs:[{}]", out(reg) v, in(reg) offset, options(readonly, nostack, preserves_flags));
+                    transmute_copy(&v)
+                },
+                4 => {
+                    let v: u32;
+                    asm!("mov {:e}, dword ptr gs:[{}]", out(reg) v, in(reg) offset, options(readonly, nostack, preserves_flags));
+                    transmute_copy(&v)
+                },
+                8 => {
+                    let v: u64;
+                    asm!("mov {}, qword ptr gs:[{}]", out(reg) v, in(reg) offset, options(readonly, nostack, preserves_flags));
+                    transmute_copy(&v)
+                },
+                _ => self.as_ptr().read(),
+            }
+        }
+    }
+
+    /// Writes this CPU's copy, with a single GS-relative store like
+    /// [`PerCpu::get`] where it can.
+    pub fn set(&self, value: T)
+    where
+        T: Copy,
+    {
+        let offset = self.offset();
+        unsafe {
+            match size_of::<T>() {
+                1 => {
+                    let v: u8 = transmute_copy(&value);
+                    asm!("mov byte ptr gs:[{}], {}", in(reg) offset, in(reg_byte) v, options(nostack, preserves_flags));
+                },
+                2 => {
+                    let v: u16 = transmute_copy(&value);
+                    asm!("mov word ptr gs:[{}], {:x}", in(reg) offset, in(reg) v, options(nostack, preserves_flags));
+                },
+                4 => {
+                    let v: u32 = transmute_copy(&value);
+                    asm!("mov dword ptr gs:[{}], {:e}", in(reg) offset, in(reg) v, options(nostack, preserves_flags));
+                },
+                8 => {
+                    let v: u64 = transmute_copy(&value);
+                    asm!("mov qword ptr gs:[{}], {}", in(reg) offset, in(reg) v, options(nostack, preserves_flags));
+                },
+                _ => self.as_ptr().write(value),
+            }
+        }
+    }
+}
+
+crate::per_cpu! {
+    /// Start of this CPU's area, for turning offsets into pointers.
+    static AREA_BASE: u64 = 0;
+    /// Which CPU this is, in the order they were started. The boot CPU is 0.
+    pub static CPU_INDEX: usize = 0;
+    /// This CPU's TSS, whose stack for entering ring 0 changes with every
+    /// task.
+    pub static TSS: *mut Tss = null_mut();
+    /// How many interrupt and exception handlers deep this CPU is.
+    pub static INTERRUPT_DEPTH: u32 = 0;
+}
+
+/// Each CPU's area, by index.
+static AREAS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static FSGSBASE: AtomicBool = AtomicBool::new(false);
+/// Set once the boot CPU's GS points at its area. APs set theirs up before
+/// doing anything else.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Fills in the area at [`base`] for CPU [`cpu`], starting from the
+/// template.
+///
+/// Safety: [`base`] must be writable and as big as the template.
+unsafe fn init_area(cpu: usize, base: u64, tss: *mut Tss) {
+    let template = addr_of!(PERCPU_START) as u64;
+    let len = addr_of!(PERCPU_END) as u64 - template;
+    unsafe {
+        core::ptr::copy_nonoverlapping(template as *const u8, base as *mut u8, len as usize);
+        AREAS[cpu].store(base, Ordering::Release);
+        *AREA_BASE.for_cpu(cpu) = base;
+        *CPU_INDEX.for_cpu(cpu) = cpu;
+        *TSS.for_cpu(cpu) = tss;
+    }
+}
+
+/// Sets up the boot CPU's area, which the linker script sets aside, and
+/// points GS at it.
+///
+/// Safety: must be called once, on the boot CPU, before anything per-CPU
+/// is used.
+pub unsafe fn init_boot_cpu(tss: *mut Tss) {
+    unsafe {
+        init_area(0, addr_of!(BOOT_PERCPU) as u64, tss);
+        init_cpu(0);
+    }
+
+    READY.store(true, Ordering::Release);
+}
+
+/// Whether per-CPU variables can be used yet. Only code that can run
+/// before [`init_boot_cpu`] needs to check.
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Gives CPU [`cpu`] an area of its own, from the initial values rather
+/// than from whatever the running CPU has in its copy. Done by a CPU that's
+/// already running, before [`init_cpu`] on [`cpu`].
+pub fn alloc(cpu: usize, tss: *mut Tss) -> Result<(), VmError> {
+    let len = addr_of!(PERCPU_END) as u64 - addr_of!(PERCPU_START) as u64;
+    let base = vma::vmalloc(len)?;
+    // copying touches every page, so per-CPU accesses never fault later
+    unsafe { init_area(cpu, base, tss) };
+    Ok(())
+}
+
+/// Points GS at CPU [`cpu`]'s area, turning on FSGSBASE first where there
+/// is one.
+///
+/// Safety: must run on [`cpu`], once its area is set up.
+pub unsafe fn init_cpu(cpu: usize) {
+    let has_fsgsbase = cpu::has(CpuFeatures::FSGSBASE);
+    unsafe {
+        if has_fsgsbase {
+            CR4.write(CR4.read().with_fsgsbase(true));
+        }
+
+        FSGSBASE.store(has_fsgsbase, Ordering::Relaxed);
+        set_gs_base(AREAS[cpu].load(Ordering::Acquire));
+        IA32_KERNEL_GS_BASE.write(0);
+    }
+}
+
+/// Safety: in the kernel, GS base has to point at this CPU's area.
+pub unsafe fn set_gs_base(base: u64) {
+    unsafe {
+        if FSGSBASE.load(Ordering::Relaxed) {
+            asm!("wrgsbase {}", in(reg) base, options(nostack, preserves_flags));
+        } else {
+            IA32_GS_BASE.write(base);
+        }
+    }
+}
+
+pub fn gs_base() -> u64 {
+    unsafe {
+        if FSGSBASE.load(Ordering::Relaxed) {
+            let base: u64;
+            asm!("rdgsbase {}", out(reg) base, options(nomem, nostack, preserves_flags));
+            base
+        } else {
+            IA32_GS_BASE.read()
+        }
+    }
+}
+
+/// Whether this CPU is running an interrupt or exception handler.
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.get() > 0
+}
+
+/// Held for the length of an interrupt or exception handler. Switches to
+/// the kernel's GS if the CPU came from user mode, and counts
+/// [`INTERRUPT_DEPTH`]; dropping it undoes both. It has to be the handler's
+/// first local, so that it's dropped last.
+pub struct KernelEntry {
+    from_user: bool,
+}
+
+impl KernelEntry {
+    pub fn new(frame: &InterruptFrame) -> Self {
+        let from_user = frame.cs & 3 != 0;
+        if from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+
+        INTERRUPT_DEPTH.set(INTERRUPT_DEPTH.get() + 1);
+        Self { from_user }
+    }
+}
+
+impl Drop for KernelEntry {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH.set(INTERRUPT_DEPTH.get() - 1);
+        if self.from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! The legacy 8259 programmable interrupt controller pair.
+
+use super::ports::{PortRW, PortRead, PortWO, PortWrite};
+
+const PIC1_PORT_BASE: u16 = 0x0020;
+const PIC2_PORT_BASE: u16 = 0x00A0;
+
+/// Writes to an unused port take roughly a microsecond, which is long enough
+/// for the PIC to settle between initialization words.
+const IO_WAIT_PORT: u16 = 0x0080;
+
+const ICW1_ICW4: u8 = 0x01;
+const ICW1_INIT: u8 = 0x10;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// IRQ on the master that the slave is cascaded through.
+const CASCADE_IRQ: u8 = 2;
+
+pub struct Pic8259 {}
+
+impl Pic8259 {
+    const PIC1_COMMAND: PortRW = PortRW::new(PIC1_PORT_BASE);
+    const PIC1_DATA: PortRW = PortRW::new(PIC1_PORT_BASE + 1);
+    const PIC2_COMMAND: PortRW = PortRW::new(PIC2_PORT_BASE);
+    const PIC2_DATA: PortRW = PortRW::new(PIC2_PORT_BASE + 1);
+    const IO_WAIT: PortWO = PortWO::new(IO_WAIT_PORT);
+
+    /// First vector the IRQs are remapped to, clear of the CPU exceptions.
+    pub const VECTOR_BASE: u8 = 0x20;
+
+    pub const IRQ_TIMER: u8 = 0;
+    pub const IRQ_KEYBOARD: u8 = 1;
+    pub const IRQ_COM2: u8 = 3;
+    pub const IRQ_COM1: u8 = 4;
+    pub const IRQ_SPURIOUS_MASTER: u8 = 7;
+    pub const IRQ_MOUSE: u8 = 12;
+    pub const IRQ_SPURIOUS_SLAVE: u8 = 15;
+
+    /// Safety: there must only ever be one [`Pic8259`], and the PICs must
+    /// actually be present.
+    pub const unsafe fn new() -> Self {
+        Self {}
+    }
+
+    pub const fn vector(irq: u8) -> u8 {
+        Self::VECTOR_BASE + irq
+    }
+
+    fn io_wait() {
+        unsafe { Self::IO_WAIT.write_byte(0) };
+    }
+
+    /// Remaps both PICs to [`Pic8259::VECTOR_BASE`] and masks every IRQ.
+    pub fn init(&self) {
+        unsafe {
+            Self::PIC1_COMMAND.write_byte(ICW1_INIT | ICW1_ICW4);
+            Self::io_wait();
+            Self::PIC2_COMMAND.write_byte(ICW1_INIT | ICW1_ICW4);
+            Self::io_wait();
+            Self::PIC1_DATA.write_byte(Self::VECTOR_BASE);
+            Self::io_wait();
+            Self::PIC2_DATA.write_byte(Self::VECTOR_BASE + 8);
+            Self::io_wait();
+            Self::PIC1_DATA.write_byte(1 << CASCADE_IRQ);
+            Self::io_wait();
+            Self::PIC2_DATA.write_byte(CASCADE_IRQ);
+            Self::io_wait();
+            Self::PIC1_DATA.write_byte(ICW4_8086);
+            Self::io_wait();
+            Self::PIC2_DATA.write_byte(ICW4_8086);
+            Self::io_wait();
+
+            Self::PIC1_DATA.write_byte(!(1 << CASCADE_IRQ));
+            Self::PIC2_DATA.write_byte(0xFF);
+        }
+    }
+
+    pub fn mask(&self, irq: u8) {
+        unsafe {
+            if irq < 8 {
+                Self::PIC1_DATA.write_byte(Self::PIC1_DATA.read_byte() | 1 << irq);
+            } else {
+                Self::PIC2_DATA.write_byte(Self::PIC2_DATA.read_byte() | 1 << (irq - 8));
+            }
+        }
+    }
+
+    pub fn unmask(&self, irq: u8) {
+        unsafe {
+            if irq < 8 {
+                Self::PIC1_DATA.write_byte(Self::PIC1_DATA.read_byte() & !(1 << irq));
+            } else {
+                Self::PIC2_DATA.write_byte(Self::PIC2_DATA.read_byte() & !(1 << (irq - 8)));
+            }
+        }
+    }
+
+    pub fn eoi(&self, irq: u8) {
+        unsafe {
+            if irq >= 8 {
+                Self::PIC2_COMMAND.write_byte(OCW2_EOI);
+            }
+
+            Self::PIC1_COMMAND.write_byte(OCW2_EOI);
+        }
+    }
+
+    /// Returns [`true`] if [`irq`] (7 or 15) is actually being serviced rather
+    /// than a spurious interrupt. A spurious IRQ 15 still needs an EOI sent to
+    /// the master, since it did forward the cascade.
+    pub fn is_in_service(&self, irq: u8) -> bool {
+        unsafe {
+            if irq < 8 {
+                Self::PIC1_COMMAND.write_byte(OCW3_READ_ISR);
+                Self::PIC1_COMMAND.read_byte() & 1 << irq != 0
+            } else {
+                Self::PIC2_COMMAND.write_byte(OCW3_READ_ISR);
+                Self::PIC2_COMMAND.read_byte() & 1 << (irq - 8) != 0
+            }
+        }
+    }
+}
+
+pub const unsafe fn pic() -> Pic8259 {
+    unsafe { Pic8259::new() }
+}
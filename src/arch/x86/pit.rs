@@ -0,0 +1,80 @@
+//! The 8254 programmable interval timer, used as the kernel's tick source.
+
+use bitfield_struct::bitfield;
+
+use crate::clock;
+
+use super::{
+    idt::InterruptFrame,
+    percpu::KernelEntry,
+    pic::{self, Pic8259},
+    ports::{PortRW, PortWO, PortWrite, PortWriteCustom},
+};
+
+const CHANNEL0_PORT: u16 = 0x0040;
+const MODE_PORT: u16 = 0x0043;
+
+/// Input clock of the PIT, in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+pub struct ModeReg(PortWO);
+
+#[bitfield(u8)]
+pub struct ModeFlags {
+    pub bcd: bool,
+    #[bits(3)]
+    pub operating_mode: u8,
+    #[bits(2)]
+    pub access_mode: u8,
+    #[bits(2)]
+    pub channel: u8,
+}
+
+impl PortWriteCustom for ModeReg {
+    type Item = ModeFlags;
+
+    unsafe fn write(&self, item: Self::Item) {
+        unsafe { self.0.write_byte(item.into_bits()) };
+    }
+}
+
+const ACCESS_LOBYTE_HIBYTE: u8 = 3;
+const MODE_RATE_GENERATOR: u8 = 2;
+
+pub struct Pit8254 {}
+
+impl Pit8254 {
+    pub const CHANNEL0: PortRW = PortRW::new(CHANNEL0_PORT);
+    pub const MODE: ModeReg = ModeReg(PortWO::new(MODE_PORT));
+
+    /// Safety: there must only ever be one [`Pit8254`].
+    pub const unsafe fn new() -> Self {
+        Self {}
+    }
+
+    /// Makes channel 0 fire IRQ 0 at roughly [`hz`], which must be at least
+    /// 19 so the divisor fits in 16 bits.
+    pub fn set_frequency(&self, hz: u32) {
+        let divisor = (BASE_FREQUENCY / hz).clamp(1, u16::MAX as u32) as u16;
+
+        unsafe {
+            Self::MODE.write(ModeFlags::new()
+                .with_channel(0)
+                .with_access_mode(ACCESS_LOBYTE_HIBYTE)
+                .with_operating_mode(MODE_RATE_GENERATOR)
+                .with_bcd(false));
+            Self::CHANNEL0.write_byte(divisor as u8);
+            Self::CHANNEL0.write_byte((divisor >> 8) as u8);
+        }
+    }
+}
+
+pub const unsafe fn pit() -> Pit8254 {
+    unsafe { Pit8254::new() }
+}
+
+pub extern "x86-interrupt" fn irq_handler(frame: InterruptFrame) {
+    let _entry = KernelEntry::new(&frame);
+    clock::tick();
+    unsafe { pic::pic() }.eoi(Pic8259::IRQ_TIMER);
+}
@@ -144,4 +144,15 @@ impl PortRW {
     pub const fn new(port: u16) -> Self {
         Self(Port::new(port))
     }
-}
\ No newline at end of file
+}
+pub trait PortReadCustom {
+    type Item;
+
+    unsafe fn read(&self) -> Self::Item;
+}
+
+pub trait PortWriteCustom {
+    type Item;
+
+    unsafe fn write(&self, item: Self::Item);
+}
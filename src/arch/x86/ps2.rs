@@ -0,0 +1,297 @@
+//! The 8042 PS/2 controller.
+
+use bitfield_struct::bitfield;
+
+use super::ports::{PortRO, PortRW, PortRead, PortReadCustom, PortWO, PortWrite, PortWriteCustom};
+
+pub mod keyboard;
+pub mod mouse;
+
+const DATA_PORT: u16 = 0x0060;
+const STATUS_PORT: u16 = 0x0064;
+const COMMAND_PORT: u16 = 0x0064;
+
+/// Number of status polls before giving up on the controller or a device.
+const TIMEOUT_SPINS: usize = 500_000;
+const MAX_RESENDS: usize = 3;
+
+pub const RESPONSE_ACK: u8 = 0xFA;
+pub const RESPONSE_RESEND: u8 = 0xFE;
+pub const RESPONSE_SELF_TEST_PASSED: u8 = 0xAA;
+
+const CONTROLLER_SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+#[derive(Debug)]
+pub enum Ps2Error {
+    Timeout,
+    Resend,
+    UnexpectedResponse(u8),
+}
+
+#[derive(Debug)]
+pub enum Ps2InitError {
+    SelfTestFailed(u8),
+    NoWorkingPorts,
+    Io(Ps2Error),
+}
+
+impl From<Ps2Error> for Ps2InitError {
+    fn from(e: Ps2Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Ps2Port {
+    FIRST,
+    SECOND,
+}
+
+/// Ports that passed their interface test during [`Ps2Controller::init`].
+#[derive(Copy, Clone, Debug)]
+pub struct Ps2Ports {
+    pub first: bool,
+    pub second: bool,
+}
+
+pub struct StatusReg(PortRO);
+
+#[bitfield(u8)]
+pub struct StatusFlags {
+    pub output_full: bool,
+    pub input_full: bool,
+    pub system: bool,
+    pub command: bool,
+    pub keyboard_lock: bool,
+    pub second_output_full: bool,
+    pub timeout_error: bool,
+    pub parity_error: bool,
+}
+
+impl PortReadCustom for StatusReg {
+    type Item = StatusFlags;
+
+    unsafe fn read(&self) -> Self::Item {
+        StatusFlags::from_bits(unsafe { self.0.read_byte() })
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum ControllerCommand {
+    READ_CONFIG = 0x20,
+    WRITE_CONFIG = 0x60,
+    DISABLE_SECOND = 0xA7,
+    ENABLE_SECOND = 0xA8,
+    TEST_SECOND = 0xA9,
+    SELF_TEST = 0xAA,
+    TEST_FIRST = 0xAB,
+    DISABLE_FIRST = 0xAD,
+    ENABLE_FIRST = 0xAE,
+    WRITE_SECOND = 0xD4,
+}
+
+pub struct CommandReg(PortWO);
+
+impl PortWriteCustom for CommandReg {
+    type Item = ControllerCommand;
+
+    unsafe fn write(&self, item: Self::Item) {
+        unsafe { self.0.write_byte(item as u8) };
+    }
+}
+
+#[bitfield(u8)]
+pub struct ConfigByte {
+    pub first_irq: bool,
+    pub second_irq: bool,
+    pub system: bool,
+    __: bool,
+    pub first_clock_disable: bool,
+    pub second_clock_disable: bool,
+    pub first_translation: bool,
+    __: bool,
+}
+
+pub struct Ps2Controller {}
+
+impl Ps2Controller {
+    pub const DATA: PortRW = PortRW::new(DATA_PORT);
+    pub const STATUS: StatusReg = StatusReg(PortRO::new(STATUS_PORT));
+    pub const COMMAND: CommandReg = CommandReg(PortWO::new(COMMAND_PORT));
+
+    /// Safety: the controller must be present (check the ACPI FADT on
+    /// anything newer than a PC/AT), and there must only be one
+    /// [`Ps2Controller`] in use at a time.
+    pub const unsafe fn new() -> Self {
+        Self {}
+    }
+
+    fn wait_input_empty(&self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT_SPINS {
+            if !unsafe { Self::STATUS.read() }.input_full() {
+                return Ok(());
+            }
+
+            core::hint::spin_loop();
+        }
+
+        Err(Ps2Error::Timeout)
+    }
+
+    fn wait_output_full(&self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT_SPINS {
+            if unsafe { Self::STATUS.read() }.output_full() {
+                return Ok(());
+            }
+
+            core::hint::spin_loop();
+        }
+
+        Err(Ps2Error::Timeout)
+    }
+
+    fn command(&self, cmd: ControllerCommand) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { Self::COMMAND.write(cmd) };
+        Ok(())
+    }
+
+    fn command_with_response(&self, cmd: ControllerCommand) -> Result<u8, Ps2Error> {
+        self.command(cmd)?;
+        self.read()
+    }
+
+    fn flush(&self) {
+        while unsafe { Self::STATUS.read() }.output_full() {
+            unsafe { Self::DATA.read_byte() };
+        }
+    }
+
+    /// Polls for a byte from the controller or either device.
+    pub fn read(&self) -> Result<u8, Ps2Error> {
+        self.wait_output_full()?;
+        Ok(unsafe { Self::DATA.read_byte() })
+    }
+
+    /// Reads the data port without checking the status register, for use in
+    /// IRQ handlers where the controller has already said a byte is waiting.
+    pub fn read_unchecked(&self) -> u8 {
+        unsafe { Self::DATA.read_byte() }
+    }
+
+    /// Sends a raw byte to the device on [`port`], without waiting for it to
+    /// acknowledge.
+    pub fn write(&self, port: Ps2Port, b: u8) -> Result<(), Ps2Error> {
+        if port == Ps2Port::SECOND {
+            self.command(ControllerCommand::WRITE_SECOND)?;
+        }
+
+        self.wait_input_empty()?;
+        unsafe { Self::DATA.write_byte(b) };
+        Ok(())
+    }
+
+    /// Sends a byte to the device on [`port`] and waits for it to be
+    /// acknowledged, resending it if the device asks.
+    pub fn send(&self, port: Ps2Port, b: u8) -> Result<(), Ps2Error> {
+        for _ in 0..MAX_RESENDS {
+            self.write(port, b)?;
+            match self.read()? {
+                RESPONSE_ACK => return Ok(()),
+                RESPONSE_RESEND => continue,
+                other => return Err(Ps2Error::UnexpectedResponse(other)),
+            }
+        }
+
+        Err(Ps2Error::Resend)
+    }
+
+    /// Sends a command followed by its argument, each of which is
+    /// acknowledged separately.
+    pub fn send_with_arg(&self, port: Ps2Port, cmd: u8, arg: u8) -> Result<(), Ps2Error> {
+        self.send(port, cmd)?;
+        self.send(port, arg)
+    }
+
+    pub fn config(&self) -> Result<ConfigByte, Ps2Error> {
+        self.command_with_response(ControllerCommand::READ_CONFIG).map(ConfigByte::from_bits)
+    }
+
+    pub fn set_config(&self, config: ConfigByte) -> Result<(), Ps2Error> {
+        self.command(ControllerCommand::WRITE_CONFIG)?;
+        self.wait_input_empty()?;
+        unsafe { Self::DATA.write_byte(config.into_bits()) };
+        Ok(())
+    }
+
+    /// Lets [`port`] raise its IRQ (1 for the first port, 12 for the second).
+    pub fn enable_irq(&self, port: Ps2Port) -> Result<(), Ps2Error> {
+        let config = self.config()?;
+        self.set_config(match port {
+            Ps2Port::FIRST => config.with_first_irq(true),
+            Ps2Port::SECOND => config.with_second_irq(true),
+        })
+    }
+
+    /// Runs the controller self-test, detects which ports are present and
+    /// working, and leaves them enabled with their IRQs and scancode
+    /// translation off, ready for the device drivers to take over.
+    pub fn init(&self) -> Result<Ps2Ports, Ps2InitError> {
+        self.command(ControllerCommand::DISABLE_FIRST)?;
+        self.command(ControllerCommand::DISABLE_SECOND)?;
+        self.flush();
+
+        let config = self.config()?
+            .with_first_irq(false)
+            .with_second_irq(false)
+            .with_first_translation(false)
+            .with_first_clock_disable(false);
+        self.set_config(config)?;
+
+        let result = self.command_with_response(ControllerCommand::SELF_TEST)?;
+        if result != CONTROLLER_SELF_TEST_PASSED {
+            return Err(Ps2InitError::SelfTestFailed(result));
+        }
+
+        // some controllers reset themselves during the self-test
+        self.set_config(config)?;
+
+        // the second port's clock is disabled by DISABLE_SECOND above, so if
+        // enabling it clears the bit, the port exists
+        let mut dual = false;
+        if config.second_clock_disable() {
+            self.command(ControllerCommand::ENABLE_SECOND)?;
+            dual = !self.config()?.second_clock_disable();
+            if dual {
+                self.command(ControllerCommand::DISABLE_SECOND)?;
+                self.set_config(self.config()?.with_second_irq(false).with_second_clock_disable(false))?;
+            }
+        }
+
+        let ports = Ps2Ports {
+            first: self.command_with_response(ControllerCommand::TEST_FIRST)? == PORT_TEST_PASSED,
+            second: dual && self.command_with_response(ControllerCommand::TEST_SECOND)? == PORT_TEST_PASSED,
+        };
+
+        if !ports.first && !ports.second {
+            return Err(Ps2InitError::NoWorkingPorts);
+        }
+
+        if ports.first {
+            self.command(ControllerCommand::ENABLE_FIRST)?;
+        }
+
+        if ports.second {
+            self.command(ControllerCommand::ENABLE_SECOND)?;
+        }
+
+        self.flush();
+        Ok(ports)
+    }
+}
+
+pub const unsafe fn ps2() -> Ps2Controller {
+    unsafe { Ps2Controller::new() }
+}
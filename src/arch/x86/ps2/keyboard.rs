@@ -0,0 +1,463 @@
+//! PS/2 keyboard driver, decoding scancode sets 1 and 2.
+
+use bitfield_struct::bitfield;
+use num_traits::FromPrimitive;
+
+use crate::{
+    arch::x86::{idt::InterruptFrame, percpu::KernelEntry, pic::{self, Pic8259}},
+    input::{self, InputEventKind, InputSource},
+    keyboard::{KeyCode, KeyboardState, Modifiers},
+    sync::spin::SpinLock,
+};
+
+use super::{Ps2Controller, Ps2Error, Ps2Port, RESPONSE_ACK, RESPONSE_RESEND, RESPONSE_SELF_TEST_PASSED, ps2};
+
+const CMD_SET_LEDS: u8 = 0xED;
+const CMD_SCANCODE_SET: u8 = 0xF0;
+const CMD_TYPEMATIC: u8 = 0xF3;
+const CMD_ENABLE_SCANNING: u8 = 0xF4;
+const CMD_DISABLE_SCANNING: u8 = 0xF5;
+const CMD_RESET: u8 = 0xFF;
+
+const SCANCODE_SET_GET: u8 = 0;
+
+const PREFIX_EXTENDED: u8 = 0xE0;
+const PREFIX_PAUSE: u8 = 0xE1;
+const PREFIX_RELEASE_SET2: u8 = 0xF0;
+const RELEASE_BIT_SET1: u8 = 0x80;
+
+/// Length of the pause key's make sequence, after the `E1` prefix. Pause has
+/// no break code, so a release is reported right after the press.
+const PAUSE_LEN_SET1: u8 = 5;
+const PAUSE_LEN_SET2: u8 = 7;
+
+/// Bytes the keyboard sends outside of scancodes (errors, echo, BAT
+/// results), which never start a key sequence in either set.
+const NON_SCANCODE_BYTES: [u8; 5] = [0x00, 0xEE, 0xFC, 0xFD, 0xFF];
+
+#[derive(Debug)]
+pub enum KeyboardInitError {
+    SelfTestFailed(u8),
+    Io(Ps2Error),
+}
+
+impl From<Ps2Error> for KeyboardInitError {
+    fn from(e: Ps2Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScancodeSet {
+    SET1 = 1,
+    SET2 = 2,
+}
+
+#[bitfield(u8)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+    #[bits(5)]
+    __: u8,
+}
+
+impl Leds {
+    fn from_modifiers(m: Modifiers) -> Self {
+        Self::new()
+            .with_scroll_lock(m.contains(Modifiers::SCROLL_LOCK))
+            .with_num_lock(m.contains(Modifiers::NUM_LOCK))
+            .with_caps_lock(m.contains(Modifiers::CAPS_LOCK))
+    }
+}
+
+/// Repeat settings. [`rate`] runs from 0 (30 Hz) to 31 (2 Hz), and [`delay`]
+/// from 0 (250 ms) to 3 (1 s).
+#[bitfield(u8)]
+pub struct Typematic {
+    #[bits(5)]
+    pub rate: u8,
+    #[bits(2)]
+    pub delay: u8,
+    __: bool,
+}
+
+impl Typematic {
+    /// 10.9 Hz after 500 ms, the power-on default.
+    pub const DEFAULT: Self = Self::new().with_rate(0x0B).with_delay(1);
+}
+
+const fn scancode_table<const N: usize>(entries: &[(u8, KeyCode)]) -> [Option<KeyCode>; N] {
+    let mut table = [None; N];
+    let mut i = 0;
+    while i < entries.len() {
+        table[entries[i].0 as usize] = Some(entries[i].1);
+        i += 1;
+    }
+
+    table
+}
+
+static SET1_EXTENDED: [Option<KeyCode>; 0x80] = scancode_table(&[
+    (0x1C, KeyCode::KP_ENTER),
+    (0x1D, KeyCode::RIGHT_CTRL),
+    (0x35, KeyCode::KP_SLASH),
+    (0x37, KeyCode::SYSRQ),
+    (0x38, KeyCode::RIGHT_ALT),
+    (0x47, KeyCode::HOME),
+    (0x48, KeyCode::UP),
+    (0x49, KeyCode::PAGE_UP),
+    (0x4B, KeyCode::LEFT),
+    (0x4D, KeyCode::RIGHT),
+    (0x4F, KeyCode::END),
+    (0x50, KeyCode::DOWN),
+    (0x51, KeyCode::PAGE_DOWN),
+    (0x52, KeyCode::INSERT),
+    (0x53, KeyCode::DELETE),
+    (0x5B, KeyCode::LEFT_META),
+    (0x5C, KeyCode::RIGHT_META),
+    (0x5D, KeyCode::COMPOSE),
+]);
+
+static SET2: [Option<KeyCode>; 0x84] = scancode_table(&[
+    (0x01, KeyCode::F9),
+    (0x03, KeyCode::F5),
+    (0x04, KeyCode::F3),
+    (0x05, KeyCode::F1),
+    (0x06, KeyCode::F2),
+    (0x07, KeyCode::F12),
+    (0x09, KeyCode::F10),
+    (0x0A, KeyCode::F8),
+    (0x0B, KeyCode::F6),
+    (0x0C, KeyCode::F4),
+    (0x0D, KeyCode::TAB),
+    (0x0E, KeyCode::GRAVE),
+    (0x11, KeyCode::LEFT_ALT),
+    (0x12, KeyCode::LEFT_SHIFT),
+    (0x14, KeyCode::LEFT_CTRL),
+    (0x15, KeyCode::Q),
+    (0x16, KeyCode::N1),
+    (0x1A, KeyCode::Z),
+    (0x1B, KeyCode::S),
+    (0x1C, KeyCode::A),
+    (0x1D, KeyCode::W),
+    (0x1E, KeyCode::N2),
+    (0x21, KeyCode::C),
+    (0x22, KeyCode::X),
+    (0x23, KeyCode::D),
+    (0x24, KeyCode::E),
+    (0x25, KeyCode::N4),
+    (0x26, KeyCode::N3),
+    (0x29, KeyCode::SPACE),
+    (0x2A, KeyCode::V),
+    (0x2B, KeyCode::F),
+    (0x2C, KeyCode::T),
+    (0x2D, KeyCode::R),
+    (0x2E, KeyCode::N5),
+    (0x31, KeyCode::N),
+    (0x32, KeyCode::B),
+    (0x33, KeyCode::H),
+    (0x34, KeyCode::G),
+    (0x35, KeyCode::Y),
+    (0x36, KeyCode::N6),
+    (0x3A, KeyCode::M),
+    (0x3B, KeyCode::J),
+    (0x3C, KeyCode::U),
+    (0x3D, KeyCode::N7),
+    (0x3E, KeyCode::N8),
+    (0x41, KeyCode::COMMA),
+    (0x42, KeyCode::K),
+    (0x43, KeyCode::I),
+    (0x44, KeyCode::O),
+    (0x45, KeyCode::N0),
+    (0x46, KeyCode::N9),
+    (0x49, KeyCode::DOT),
+    (0x4A, KeyCode::SLASH),
+    (0x4B, KeyCode::L),
+    (0x4C, KeyCode::SEMICOLON),
+    (0x4D, KeyCode::P),
+    (0x4E, KeyCode::MINUS),
+    (0x52, KeyCode::APOSTROPHE),
+    (0x54, KeyCode::LEFT_BRACE),
+    (0x55, KeyCode::EQUAL),
+    (0x58, KeyCode::CAPS_LOCK),
+    (0x59, KeyCode::RIGHT_SHIFT),
+    (0x5A, KeyCode::ENTER),
+    (0x5B, KeyCode::RIGHT_BRACE),
+    (0x5D, KeyCode::BACKSLASH),
+    (0x61, KeyCode::KEY_102ND),
+    (0x66, KeyCode::BACKSPACE),
+    (0x69, KeyCode::KP1),
+    (0x6B, KeyCode::KP4),
+    (0x6C, KeyCode::KP7),
+    (0x70, KeyCode::KP0),
+    (0x71, KeyCode::KP_DOT),
+    (0x72, KeyCode::KP2),
+    (0x73, KeyCode::KP5),
+    (0x74, KeyCode::KP6),
+    (0x75, KeyCode::KP8),
+    (0x76, KeyCode::ESC),
+    (0x77, KeyCode::NUM_LOCK),
+    (0x78, KeyCode::F11),
+    (0x79, KeyCode::KP_PLUS),
+    (0x7A, KeyCode::KP3),
+    (0x7B, KeyCode::KP_MINUS),
+    (0x7C, KeyCode::KP_ASTERISK),
+    (0x7D, KeyCode::KP9),
+    (0x7E, KeyCode::SCROLL_LOCK),
+    (0x83, KeyCode::F7),
+]);
+
+static SET2_EXTENDED: [Option<KeyCode>; 0x80] = scancode_table(&[
+    (0x11, KeyCode::RIGHT_ALT),
+    (0x14, KeyCode::RIGHT_CTRL),
+    (0x1F, KeyCode::LEFT_META),
+    (0x27, KeyCode::RIGHT_META),
+    (0x2F, KeyCode::COMPOSE),
+    (0x4A, KeyCode::KP_SLASH),
+    (0x5A, KeyCode::KP_ENTER),
+    (0x69, KeyCode::END),
+    (0x6B, KeyCode::LEFT),
+    (0x6C, KeyCode::HOME),
+    (0x70, KeyCode::INSERT),
+    (0x71, KeyCode::DELETE),
+    (0x72, KeyCode::DOWN),
+    (0x74, KeyCode::RIGHT),
+    (0x75, KeyCode::UP),
+    (0x7A, KeyCode::PAGE_DOWN),
+    (0x7C, KeyCode::SYSRQ),
+    (0x7D, KeyCode::PAGE_UP),
+]);
+
+#[derive(Copy, Clone)]
+enum DecoderState {
+    START,
+    EXTENDED,
+    RELEASE,
+    EXTENDED_RELEASE,
+    /// Swallowing the rest of the pause sequence.
+    PAUSE(u8),
+}
+
+/// Turns a stream of scancode bytes into key presses and releases.
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    state: DecoderState,
+}
+
+impl ScancodeDecoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            state: DecoderState::START,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Returns the key and whether it was pressed once [`b`] completes a
+    /// sequence.
+    pub fn feed(&mut self, b: u8) -> Option<(KeyCode, bool)> {
+        match self.set {
+            ScancodeSet::SET1 => self.feed_set1(b),
+            ScancodeSet::SET2 => self.feed_set2(b),
+        }
+    }
+
+    fn feed_set1(&mut self, b: u8) -> Option<(KeyCode, bool)> {
+        let state = self.state;
+        self.state = DecoderState::START;
+
+        match (state, b) {
+            (DecoderState::PAUSE(1), _) => Some((KeyCode::PAUSE, true)),
+            (DecoderState::PAUSE(n), _) => {
+                self.state = DecoderState::PAUSE(n - 1);
+                None
+            },
+            (DecoderState::START, PREFIX_EXTENDED) => {
+                self.state = DecoderState::EXTENDED;
+                None
+            },
+            (DecoderState::START, PREFIX_PAUSE) => {
+                self.state = DecoderState::PAUSE(PAUSE_LEN_SET1);
+                None
+            },
+            (DecoderState::START, _) => {
+                KeyCode::from_u8(b & !RELEASE_BIT_SET1).map(|k| (k, b & RELEASE_BIT_SET1 == 0))
+            },
+            (DecoderState::EXTENDED, _) => {
+                SET1_EXTENDED[(b & !RELEASE_BIT_SET1) as usize].map(|k| (k, b & RELEASE_BIT_SET1 == 0))
+            },
+            _ => None,
+        }
+    }
+
+    fn feed_set2(&mut self, b: u8) -> Option<(KeyCode, bool)> {
+        let state = self.state;
+        self.state = DecoderState::START;
+
+        match (state, b) {
+            (DecoderState::PAUSE(1), _) => Some((KeyCode::PAUSE, true)),
+            (DecoderState::PAUSE(n), _) => {
+                self.state = DecoderState::PAUSE(n - 1);
+                None
+            },
+            (DecoderState::START, PREFIX_EXTENDED) => {
+                self.state = DecoderState::EXTENDED;
+                None
+            },
+            (DecoderState::START, PREFIX_PAUSE) => {
+                self.state = DecoderState::PAUSE(PAUSE_LEN_SET2);
+                None
+            },
+            (DecoderState::START, PREFIX_RELEASE_SET2) => {
+                self.state = DecoderState::RELEASE;
+                None
+            },
+            (DecoderState::EXTENDED, PREFIX_RELEASE_SET2) => {
+                self.state = DecoderState::EXTENDED_RELEASE;
+                None
+            },
+            (DecoderState::START, _) => SET2.get(b as usize).copied().flatten().map(|k| (k, true)),
+            (DecoderState::RELEASE, _) => SET2.get(b as usize).copied().flatten().map(|k| (k, false)),
+            (DecoderState::EXTENDED, _) => SET2_EXTENDED.get(b as usize).copied().flatten().map(|k| (k, true)),
+            (DecoderState::EXTENDED_RELEASE, _) => {
+                SET2_EXTENDED.get(b as usize).copied().flatten().map(|k| (k, false))
+            },
+        }
+    }
+}
+
+pub struct Ps2Keyboard {
+    port: Ps2Port,
+    decoder: ScancodeDecoder,
+    state: KeyboardState,
+    /// LED byte to send once the keyboard acknowledges `CMD_SET_LEDS`.
+    pending_leds: Option<Leds>,
+}
+
+static KEYBOARD: SpinLock<Option<Ps2Keyboard>> = SpinLock::new(None);
+
+impl Ps2Keyboard {
+    /// Resets the keyboard on [`port`] and switches it to scancode set 2,
+    /// falling back to set 1 if it refuses.
+    pub fn new(controller: &Ps2Controller, port: Ps2Port, typematic: Typematic) -> Result<Self, KeyboardInitError> {
+        controller.send(port, CMD_RESET)?;
+        let result = controller.read()?;
+        if result != RESPONSE_SELF_TEST_PASSED {
+            return Err(KeyboardInitError::SelfTestFailed(result));
+        }
+
+        controller.send(port, CMD_DISABLE_SCANNING)?;
+
+        let set = if controller.send_with_arg(port, CMD_SCANCODE_SET, ScancodeSet::SET2 as u8).is_ok() {
+            ScancodeSet::SET2
+        } else {
+            controller.send_with_arg(port, CMD_SCANCODE_SET, ScancodeSet::SET1 as u8)?;
+            ScancodeSet::SET1
+        };
+
+        // confirm the keyboard actually switched, some only claim to
+        controller.send_with_arg(port, CMD_SCANCODE_SET, SCANCODE_SET_GET)?;
+        let set = match controller.read()? {
+            1 => ScancodeSet::SET1,
+            2 => ScancodeSet::SET2,
+            _ => set,
+        };
+
+        controller.send_with_arg(port, CMD_TYPEMATIC, typematic.into_bits())?;
+        controller.send_with_arg(port, CMD_SET_LEDS, Leds::new().into_bits())?;
+        controller.send(port, CMD_ENABLE_SCANNING)?;
+
+        Ok(Self {
+            port,
+            decoder: ScancodeDecoder::new(set),
+            state: KeyboardState::new(),
+            pending_leds: None,
+        })
+    }
+
+    pub fn scancode_set(&self) -> ScancodeSet {
+        self.decoder.set()
+    }
+
+    /// Updates the LEDs without waiting for the keyboard, since the
+    /// acknowledgements arrive through the IRQ handler.
+    fn update_leds(&mut self, controller: &Ps2Controller) {
+        if controller.write(self.port, CMD_SET_LEDS).is_ok() {
+            self.pending_leds = Some(Leds::from_modifiers(self.state.modifiers()));
+        }
+    }
+
+    fn handle_byte(&mut self, controller: &Ps2Controller, b: u8) {
+        if b == RESPONSE_ACK {
+            if let Some(leds) = self.pending_leds.take() {
+                let _ = controller.write(self.port, leds.into_bits());
+            }
+
+            return;
+        }
+
+        if b == RESPONSE_RESEND || NON_SCANCODE_BYTES.contains(&b) {
+            self.pending_leds = None;
+            return;
+        }
+
+        let Some((code, pressed)) = self.decoder.feed(b) else {
+            return;
+        };
+
+        let (event, locks_changed) = self.state.update(code, pressed);
+        if locks_changed {
+            self.update_leds(controller);
+        }
+
+        // pause never sends a release, so it's released straight away, or
+        // every later press would look like a repeat
+        if code == KeyCode::PAUSE && pressed {
+            let (release, _) = self.state.update(code, false);
+            input::report(InputSource::KEYBOARD, &[InputEventKind::Key(event), InputEventKind::Key(release)]);
+            return;
+        }
+
+        input::report(InputSource::KEYBOARD, &[InputEventKind::Key(event)]);
+    }
+}
+
+/// Initializes the keyboard on [`port`] and routes its bytes to
+/// [`irq_handler`]. The handler itself still has to be installed in the IDT.
+pub fn init(controller: &Ps2Controller, port: Ps2Port) -> Result<ScancodeSet, KeyboardInitError> {
+    let kb = Ps2Keyboard::new(controller, port, Typematic::DEFAULT)?;
+    let set = kb.scancode_set();
+    *KEYBOARD.lock_irq() = Some(kb);
+
+    controller.enable_irq(port)?;
+    unsafe { pic::pic() }.unmask(Pic8259::IRQ_KEYBOARD);
+    Ok(set)
+}
+
+/// Changes the repeat settings. Must be called with IRQ 1 masked or
+/// interrupts disabled, since the keyboard's acknowledgements are polled.
+pub fn set_typematic(typematic: Typematic) -> Result<(), Ps2Error> {
+    let kb = KEYBOARD.lock_irq();
+    let Some(kb) = kb.as_ref() else {
+        return Ok(());
+    };
+
+    let controller = unsafe { ps2() };
+    controller.send_with_arg(kb.port, CMD_TYPEMATIC, typematic.into_bits())
+}
+
+pub extern "x86-interrupt" fn irq_handler(frame: InterruptFrame) {
+    let _entry = KernelEntry::new(&frame);
+    let controller = unsafe { ps2() };
+    let b = controller.read_unchecked();
+
+    if let Some(kb) = KEYBOARD.lock().as_mut() {
+        kb.handle_byte(&controller, b);
+    }
+
+    unsafe { pic::pic() }.eoi(Pic8259::IRQ_KEYBOARD);
+}
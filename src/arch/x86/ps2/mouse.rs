@@ -0,0 +1,233 @@
+//! PS/2 mouse driver, with the IntelliMouse wheel and 5-button extensions.
+
+use bitfield_struct::bitfield;
+
+use crate::{
+    arch::x86::{idt::InterruptFrame, percpu::KernelEntry, pic::{self, Pic8259}},
+    mouse::{self, MouseButtons},
+    sync::spin::SpinLock,
+};
+
+use super::{Ps2Controller, Ps2Error, Ps2Port, RESPONSE_SELF_TEST_PASSED, ps2};
+
+const CMD_SET_RESOLUTION: u8 = 0xE8;
+const CMD_GET_ID: u8 = 0xF2;
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+const CMD_DISABLE_REPORTING: u8 = 0xF5;
+const CMD_SET_DEFAULTS: u8 = 0xF6;
+const CMD_RESET: u8 = 0xFF;
+
+/// Sample rates that, sent in this order, unlock the wheel on an IntelliMouse.
+const KNOCK_WHEEL: [u8; 3] = [200, 100, 80];
+/// Sample rates that, sent after [`KNOCK_WHEEL`], unlock buttons 4 and 5.
+const KNOCK_FIVE_BUTTON: [u8; 3] = [200, 200, 80];
+
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+/// 8 counts per millimetre.
+const DEFAULT_RESOLUTION: u8 = 3;
+
+#[derive(Debug)]
+pub enum MouseInitError {
+    SelfTestFailed(u8),
+    Io(Ps2Error),
+}
+
+impl From<Ps2Error> for MouseInitError {
+    fn from(e: Ps2Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// What the mouse reports from `CMD_GET_ID`, which also determines the
+/// packet length.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MouseType {
+    STANDARD = 0x00,
+    WHEEL = 0x03,
+    FIVE_BUTTON = 0x04,
+}
+
+impl MouseType {
+    fn from_id(id: u8) -> Self {
+        match id {
+            0x03 => Self::WHEEL,
+            0x04 => Self::FIVE_BUTTON,
+            _ => Self::STANDARD,
+        }
+    }
+
+    pub fn packet_len(self) -> usize {
+        match self {
+            Self::STANDARD => 3,
+            Self::WHEEL | Self::FIVE_BUTTON => 4,
+        }
+    }
+}
+
+#[bitfield(u8)]
+struct PacketFlags {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    /// Always set, which is how a lost byte gets noticed.
+    pub always_one: bool,
+    pub x_sign: bool,
+    pub y_sign: bool,
+    pub x_overflow: bool,
+    pub y_overflow: bool,
+}
+
+#[bitfield(u8)]
+struct FiveButtonExtra {
+    #[bits(4)]
+    pub z: u8,
+    pub button4: bool,
+    pub button5: bool,
+    #[bits(2)]
+    __: u8,
+}
+
+pub struct Ps2Mouse {
+    type_: MouseType,
+    packet: [u8; 4],
+    len: usize,
+    buttons: MouseButtons,
+}
+
+static MOUSE: SpinLock<Option<Ps2Mouse>> = SpinLock::new(None);
+
+impl Ps2Mouse {
+    fn get_id(controller: &Ps2Controller, port: Ps2Port) -> Result<u8, Ps2Error> {
+        controller.send(port, CMD_GET_ID)?;
+        controller.read()
+    }
+
+    fn knock(controller: &Ps2Controller, port: Ps2Port, rates: &[u8]) -> Result<u8, Ps2Error> {
+        for &rate in rates {
+            controller.send_with_arg(port, CMD_SET_SAMPLE_RATE, rate)?;
+        }
+
+        Self::get_id(controller, port)
+    }
+
+    /// Resets the mouse on [`port`] and enables as many IntelliMouse
+    /// extensions as it supports.
+    pub fn new(controller: &Ps2Controller, port: Ps2Port) -> Result<Self, MouseInitError> {
+        controller.send(port, CMD_RESET)?;
+        let result = controller.read()?;
+        if result != RESPONSE_SELF_TEST_PASSED {
+            return Err(MouseInitError::SelfTestFailed(result));
+        }
+
+        // device id, always 0 straight after a reset
+        controller.read()?;
+
+        controller.send(port, CMD_DISABLE_REPORTING)?;
+        controller.send(port, CMD_SET_DEFAULTS)?;
+
+        let mut type_ = MouseType::from_id(Self::knock(controller, port, &KNOCK_WHEEL)?);
+        if type_ == MouseType::WHEEL {
+            type_ = MouseType::from_id(Self::knock(controller, port, &KNOCK_FIVE_BUTTON)?);
+            if type_ == MouseType::STANDARD {
+                type_ = MouseType::WHEEL;
+            }
+        }
+
+        controller.send_with_arg(port, CMD_SET_SAMPLE_RATE, DEFAULT_SAMPLE_RATE)?;
+        controller.send_with_arg(port, CMD_SET_RESOLUTION, DEFAULT_RESOLUTION)?;
+        controller.send(port, CMD_ENABLE_REPORTING)?;
+
+        Ok(Self {
+            type_,
+            packet: [0; 4],
+            len: 0,
+            buttons: MouseButtons::empty(),
+        })
+    }
+
+    pub fn type_(&self) -> MouseType {
+        self.type_
+    }
+
+    fn handle_byte(&mut self, b: u8) {
+        if self.len == 0 && !PacketFlags::from_bits(b).always_one() {
+            // out of sync, drop bytes until something that looks like the
+            // start of a packet comes along
+            return;
+        }
+
+        self.packet[self.len] = b;
+        self.len += 1;
+        if self.len < self.type_.packet_len() {
+            return;
+        }
+
+        self.len = 0;
+        self.handle_packet();
+    }
+
+    fn handle_packet(&mut self) {
+        let flags = PacketFlags::from_bits(self.packet[0]);
+        let dx = movement(self.packet[1], flags.x_sign(), flags.x_overflow());
+        let dy = movement(self.packet[2], flags.y_sign(), flags.y_overflow());
+
+        let mut buttons = MouseButtons::empty();
+        buttons.set(MouseButtons::LEFT, flags.left());
+        buttons.set(MouseButtons::RIGHT, flags.right());
+        buttons.set(MouseButtons::MIDDLE, flags.middle());
+
+        let wheel = match self.type_ {
+            MouseType::STANDARD => 0,
+            MouseType::WHEEL => self.packet[3] as i8,
+            MouseType::FIVE_BUTTON => {
+                let extra = FiveButtonExtra::from_bits(self.packet[3]);
+                buttons.set(MouseButtons::SIDE, extra.button4());
+                buttons.set(MouseButtons::EXTRA, extra.button5());
+                // sign-extend the 4-bit movement
+                ((extra.z() << 4) as i8) >> 4
+            },
+        };
+
+        // PS/2 counts up as positive, and the wheel reports towards the user
+        // as positive, both of which are the other way round from us
+        mouse::report(dx, -dy, wheel.wrapping_neg(), buttons, self.buttons);
+        self.buttons = buttons;
+    }
+}
+
+/// Decodes one axis of a packet: 9-bit two's complement, with the sign bit
+/// in the flags byte. On overflow the low byte is meaningless, so the motion
+/// is clamped to as far as it goes in the direction of the sign.
+fn movement(low: u8, sign: bool, overflow: bool) -> i16 {
+    match (overflow, sign) {
+        (true, false) => 255,
+        (true, true) => -255,
+        (false, _) => low as i16 - if sign { 0x100 } else { 0 },
+    }
+}
+
+/// Initializes the mouse on [`port`] and routes its bytes to
+/// [`irq_handler`]. The handler itself still has to be installed in the IDT.
+pub fn init(controller: &Ps2Controller, port: Ps2Port) -> Result<MouseType, MouseInitError> {
+    let m = Ps2Mouse::new(controller, port)?;
+    let type_ = m.type_();
+    *MOUSE.lock_irq() = Some(m);
+
+    controller.enable_irq(port)?;
+    unsafe { pic::pic() }.unmask(Pic8259::IRQ_MOUSE);
+    Ok(type_)
+}
+
+pub extern "x86-interrupt" fn irq_handler(frame: InterruptFrame) {
+    let _entry = KernelEntry::new(&frame);
+    let controller = unsafe { ps2() };
+    let b = controller.read_unchecked();
+
+    if let Some(m) = MOUSE.lock().as_mut() {
+        m.handle_byte(b);
+    }
+
+    unsafe { pic::pic() }.eoi(Pic8259::IRQ_MOUSE);
+}
@@ -0,0 +1,67 @@
+//! Entropy from the CPU, for seeding things that mustn't be guessable. Comes
+//! from RDSEED or RDRAND when the CPU has them, otherwise from jitter in
+//! the timestamp counter.
+
+use core::arch::{asm, x86_64::__cpuid};
+
+use super::cpu::{self, CpuFeatures};
+
+/// Both instructions can transiently fail; Intel recommends giving RDRAND
+/// 10 tries.
+const RETRIES: usize = 10;
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe { asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+fn rdtsc() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags)) };
+    (hi as u64) << 32 | lo as u64
+}
+
+/// Mixes the low bits of how long a run of CPUIDs takes, which wobble with
+/// cache state, interrupts and (especially) hypervisor exits. Weak, but
+/// better than nothing.
+fn tsc_jitter() -> u64 {
+    let mut x = rdtsc();
+    for _ in 0..64 {
+        let start = rdtsc();
+        let _ = __cpuid(0);
+        let delta = rdtsc().wrapping_sub(start);
+        x = (x.rotate_left(7) ^ delta).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+
+    x ^ x >> 31
+}
+
+/// Returns 64 random bits, from the best source the CPU has.
+pub fn random_u64() -> u64 {
+    let hw = if cpu::has(CpuFeatures::RDSEED) { rdseed() } else { None }
+        .or_else(|| if cpu::has(CpuFeatures::RDRAND) { rdrand() } else { None });
+
+    // mix in the TSC even with a hardware source, in case it's broken
+    hw.unwrap_or(0) ^ tsc_jitter()
+}
@@ -0,0 +1,376 @@
+//! Control registers, RFLAGS, XCR0 and model-specific registers, with typed
+//! access in the same shape as [`super::ports`].
+
+use core::{arch::asm, marker::PhantomData};
+
+use bitfield_struct::bitfield;
+
+pub trait RegisterRead {
+    type Item;
+
+    unsafe fn read(&self) -> Self::Item;
+}
+
+pub trait RegisterWrite {
+    type Item;
+
+    unsafe fn write(&self, item: Self::Item);
+}
+
+#[bitfield(u64)]
+pub struct Cr0 {
+    pub pe: bool,
+    pub mp: bool,
+    pub em: bool,
+    pub ts: bool,
+    pub et: bool,
+    pub ne: bool,
+    #[bits(10)]
+    __: u64,
+    pub wp: bool,
+    __: bool,
+    pub am: bool,
+    #[bits(10)]
+    __: u64,
+    pub nw: bool,
+    pub cd: bool,
+    pub pg: bool,
+    #[bits(32)]
+    __: u64,
+}
+
+/// With CR4.PCIDE clear, the low bits of [`Cr3::pcid`] are the PWT and PCD
+/// bits instead, which the kernel leaves at 0.
+#[bitfield(u64)]
+pub struct Cr3 {
+    #[bits(12)]
+    pub pcid: u16,
+    #[bits(40)]
+    pub root_paddr: u64,
+    #[bits(11)]
+    __: u64,
+    /// On write with CR4.PCIDE set, keeps the TLB entries of [`Cr3::pcid`].
+    pub no_flush: bool,
+}
+
+#[bitfield(u64)]
+pub struct Cr4 {
+    pub vme: bool,
+    pub pvi: bool,
+    pub tsd: bool,
+    pub de: bool,
+    pub pse: bool,
+    pub pae: bool,
+    pub mce: bool,
+    pub pge: bool,
+    pub pce: bool,
+    pub osfxsr: bool,
+    pub osxmmexcpt: bool,
+    pub umip: bool,
+    pub la57: bool,
+    pub vmxe: bool,
+    pub smxe: bool,
+    __: bool,
+    pub fsgsbase: bool,
+    pub pcide: bool,
+    pub osxsave: bool,
+    __: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub pke: bool,
+    pub cet: bool,
+    pub pks: bool,
+    #[bits(39)]
+    __: u64,
+}
+
+#[bitfield(u64)]
+pub struct Efer {
+    pub sce: bool,
+    #[bits(7)]
+    __: u64,
+    pub lme: bool,
+    __: bool,
+    pub lma: bool,
+    pub nxe: bool,
+    pub svme: bool,
+    pub lmsle: bool,
+    pub ffxsr: bool,
+    pub tce: bool,
+    #[bits(48)]
+    __: u64,
+}
+
+#[bitfield(u64)]
+pub struct Rflags {
+    pub carry: bool,
+    /// Always reads as 1.
+    pub reserved_1: bool,
+    pub parity: bool,
+    __: bool,
+    pub adjust: bool,
+    __: bool,
+    pub zero: bool,
+    pub sign: bool,
+    pub trap: bool,
+    pub interrupt_enable: bool,
+    pub direction: bool,
+    pub overflow: bool,
+    #[bits(2)]
+    pub iopl: u8,
+    pub nested_task: bool,
+    __: bool,
+    pub resume: bool,
+    pub virtual_8086: bool,
+    pub alignment_check: bool,
+    pub virtual_interrupt: bool,
+    pub virtual_interrupt_pending: bool,
+    pub id: bool,
+    #[bits(42)]
+    __: u64,
+}
+
+/// Which state components XSAVE manages.
+#[bitfield(u64)]
+pub struct Xcr0 {
+    pub x87: bool,
+    pub sse: bool,
+    pub avx: bool,
+    pub bndreg: bool,
+    pub bndcsr: bool,
+    pub opmask: bool,
+    pub zmm_hi256: bool,
+    pub hi16_zmm: bool,
+    __: bool,
+    pub pkru: bool,
+    #[bits(54)]
+    __: u64,
+}
+
+#[bitfield(u64)]
+pub struct ApicBase {
+    #[bits(8)]
+    __: u64,
+    pub bsp: bool,
+    __: bool,
+    pub x2apic_enable: bool,
+    pub global_enable: bool,
+    #[bits(40)]
+    pub base_pfn: u64,
+    #[bits(12)]
+    __: u64,
+}
+
+/// Segments loaded by SYSCALL and SYSRET.
+#[bitfield(u64)]
+pub struct Star {
+    /// 32-bit SYSCALL target in legacy mode, unused in long mode.
+    pub eip: u32,
+    /// CS on SYSCALL; SS is the next selector.
+    pub syscall_cs: u16,
+    /// Base for CS and SS on SYSRET, which differ between 32 and 64-bit
+    /// returns.
+    pub sysret_cs: u16,
+}
+
+pub struct Cr0Reg;
+pub struct Cr2Reg;
+pub struct Cr3Reg;
+pub struct Cr4Reg;
+pub struct RflagsReg;
+pub struct Xcr0Reg;
+
+pub const CR0: Cr0Reg = Cr0Reg;
+/// The address of the last page fault.
+pub const CR2: Cr2Reg = Cr2Reg;
+pub const CR3: Cr3Reg = Cr3Reg;
+pub const CR4: Cr4Reg = Cr4Reg;
+pub const RFLAGS: RflagsReg = RflagsReg;
+pub const XCR0: Xcr0Reg = Xcr0Reg;
+
+impl RegisterRead for Cr0Reg {
+    type Item = Cr0;
+
+    unsafe fn read(&self) -> Self::Item {
+        let value: u64;
+        unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Cr0::from_bits(value)
+    }
+}
+
+impl RegisterWrite for Cr0Reg {
+    type Item = Cr0;
+
+    unsafe fn write(&self, item: Self::Item) {
+        unsafe { asm!("mov cr0, {}", in(reg) item.into_bits(), options(nostack, preserves_flags)) };
+    }
+}
+
+impl RegisterRead for Cr2Reg {
+    type Item = u64;
+
+    unsafe fn read(&self) -> Self::Item {
+        let value: u64;
+        unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+}
+
+impl RegisterRead for Cr3Reg {
+    type Item = Cr3;
+
+    unsafe fn read(&self) -> Self::Item {
+        let value: u64;
+        unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Cr3::from_bits(value)
+    }
+}
+
+impl RegisterWrite for Cr3Reg {
+    type Item = Cr3;
+
+    unsafe fn write(&self, item: Self::Item) {
+        unsafe { asm!("mov cr3, {}", in(reg) item.into_bits(), options(nostack, preserves_flags)) };
+    }
+}
+
+impl RegisterRead for Cr4Reg {
+    type Item = Cr4;
+
+    unsafe fn read(&self) -> Self::Item {
+        let value: u64;
+        unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Cr4::from_bits(value)
+    }
+}
+
+impl RegisterWrite for Cr4Reg {
+    type Item = Cr4;
+
+    unsafe fn write(&self, item: Self::Item) {
+        unsafe { asm!("mov cr4, {}", in(reg) item.into_bits(), options(nostack, preserves_flags)) };
+    }
+}
+
+impl RegisterRead for RflagsReg {
+    type Item = Rflags;
+
+    unsafe fn read(&self) -> Self::Item {
+        let value: u64;
+        unsafe { asm!("pushfq", "pop {}", out(reg) value, options(nomem, preserves_flags)) };
+        Rflags::from_bits(value)
+    }
+}
+
+impl RegisterWrite for RflagsReg {
+    type Item = Rflags;
+
+    unsafe fn write(&self, item: Self::Item) {
+        unsafe { asm!("push {}", "popfq", in(reg) item.into_bits(), options(nomem)) };
+    }
+}
+
+/// Needs CR4.OSXSAVE set.
+impl RegisterRead for Xcr0Reg {
+    type Item = Xcr0;
+
+    unsafe fn read(&self) -> Self::Item {
+        let (lo, hi): (u32, u32);
+        unsafe { asm!("xgetbv", in("ecx") 0, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags)) };
+        Xcr0::from_bits((hi as u64) << 32 | lo as u64)
+    }
+}
+
+impl RegisterWrite for Xcr0Reg {
+    type Item = Xcr0;
+
+    unsafe fn write(&self, item: Self::Item) {
+        let value = item.into_bits();
+        unsafe {
+            asm!(
+                "xsetbv",
+                in("ecx") 0,
+                in("eax") value as u32,
+                in("edx") (value >> 32) as u32,
+                options(nostack, preserves_flags),
+            )
+        };
+    }
+}
+
+/// A model-specific register, read and written as a plain [`u64`].
+#[derive(Copy, Clone)]
+pub struct Msr(u32);
+
+impl Msr {
+    pub const fn new(index: u32) -> Self {
+        Self(index)
+    }
+
+    pub const fn index(&self) -> u32 {
+        self.0
+    }
+
+    pub unsafe fn read_raw(&self) -> u64 {
+        let (lo, hi): (u32, u32);
+        unsafe { asm!("rdmsr", in("ecx") self.0, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags)) };
+        (hi as u64) << 32 | lo as u64
+    }
+
+    pub unsafe fn write_raw(&self, value: u64) {
+        unsafe {
+            asm!(
+                "wrmsr",
+                in("ecx") self.0,
+                in("eax") value as u32,
+                in("edx") (value >> 32) as u32,
+                options(nostack, preserves_flags),
+            )
+        };
+    }
+}
+
+/// An MSR holding a [`T`].
+pub struct MsrReg<T>(Msr, PhantomData<T>);
+
+impl<T> MsrReg<T> {
+    pub const fn new(index: u32) -> Self {
+        Self(Msr::new(index), PhantomData)
+    }
+
+    pub const fn msr(&self) -> Msr {
+        self.0
+    }
+}
+
+impl<T: From<u64>> RegisterRead for MsrReg<T> {
+    type Item = T;
+
+    unsafe fn read(&self) -> Self::Item {
+        T::from(unsafe { self.0.read_raw() })
+    }
+}
+
+impl<T: Into<u64>> RegisterWrite for MsrReg<T> {
+    type Item = T;
+
+    unsafe fn write(&self, item: Self::Item) {
+        unsafe { self.0.write_raw(item.into()) };
+    }
+}
+
+pub const IA32_APIC_BASE: MsrReg<ApicBase> = MsrReg::new(0x0000_001B);
+/// Fires the local APIC timer when the TSC reaches it, in TSC-deadline mode.
+pub const IA32_TSC_DEADLINE: MsrReg<u64> = MsrReg::new(0x0000_06E0);
+/// Eight entries of one byte each; see [`super::pat`].
+pub const IA32_PAT: MsrReg<u64> = MsrReg::new(0x0000_0277);
+pub const IA32_EFER: MsrReg<Efer> = MsrReg::new(0xC000_0080);
+pub const IA32_STAR: MsrReg<Star> = MsrReg::new(0xC000_0081);
+/// 64-bit SYSCALL target.
+pub const IA32_LSTAR: MsrReg<u64> = MsrReg::new(0xC000_0082);
+/// RFLAGS bits cleared on SYSCALL.
+pub const IA32_FMASK: MsrReg<Rflags> = MsrReg::new(0xC000_0084);
+pub const IA32_FS_BASE: MsrReg<u64> = MsrReg::new(0xC000_0100);
+pub const IA32_GS_BASE: MsrReg<u64> = MsrReg::new(0xC000_0101);
+/// Swapped with [`IA32_GS_BASE`] by SWAPGS.
+pub const IA32_KERNEL_GS_BASE: MsrReg<u64> = MsrReg::new(0xC000_0102);
@@ -1,11 +1,22 @@
+use core::fmt;
+
 use bitfield_struct::bitfield;
 use bitflags::bitflags;
 
-use super::ports::{PortRO, PortRW, PortRead, PortReadCustom, PortWO, PortWrite, PortWriteCustom};
+use crate::{input, sync::spin::SpinLock};
+
+use super::{
+    idt::InterruptFrame,
+    percpu::KernelEntry,
+    pic::{self, Pic8259},
+    ports::{PortRO, PortRW, PortRead, PortReadCustom, PortWO, PortWrite, PortWriteCustom},
+};
 
 const COM1_PORT_BASE: u16 = 0x03F8;
 const COM2_PORT_BASE: u16 = 0x02F8;
 
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+
 #[derive(Debug)]
 pub enum ComInitError {
     FaultyHardware,
@@ -184,15 +195,57 @@ impl<const BASE: u16> Com<BASE> {
     pub fn getc(&self) -> u8 {
         unsafe { Self::RX.read_byte() }
     }
+
+    /// Returns [`None`] if no byte has been received.
+    pub fn try_getc(&self) -> Option<u8> {
+        if unsafe { Self::LINE_STATUS.read_byte() } & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+
+        Some(self.getc())
+    }
+
+    /// Raises the port's IRQ whenever a byte is received.
+    pub fn enable_rx_interrupt(&self) {
+        unsafe { Self::INT_ENABLE.write(Self::INT_ENABLE.read().with_rx_available(true)) };
+    }
+}
+
+impl<const BASE: u16> fmt::Write for Com<BASE> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            self.putc(b);
+        }
+
+        Ok(())
+    }
+}
+
+/// Drains COM1's receive FIFO into the serial console's key decoder.
+pub extern "x86-interrupt" fn com1_irq_handler(frame: InterruptFrame) {
+    let _entry = KernelEntry::new(&frame);
+    {
+        let com1 = com1().lock();
+        while let Some(b) = com1.try_getc() {
+            input::serial::feed(b);
+        }
+    }
+
+    unsafe { pic::pic() }.eoi(Pic8259::IRQ_COM1);
 }
 
 pub type COM1 = Com<COM1_PORT_BASE>;
 pub type COM2 = Com<COM2_PORT_BASE>;
 
-pub const unsafe fn com1() -> COM1 {
-    unsafe { Com::new() }
+static COM1_LOCK: SpinLock<COM1> = SpinLock::new(unsafe { Com::new() });
+static COM2_LOCK: SpinLock<COM2> = SpinLock::new(unsafe { Com::new() });
+
+/// COM1, which takes interrupts, so lock it with [`SpinLock::lock_irq`]
+/// outside of its handler.
+pub fn com1() -> &'static SpinLock<COM1> {
+    &COM1_LOCK
 }
 
-pub const unsafe fn com2() -> COM2 {
-    unsafe { Com::new() }
+pub fn com2() -> &'static SpinLock<COM2> {
+    &COM2_LOCK
 }
\ No newline at end of file
@@ -0,0 +1,369 @@
+//! Starting the application processors, the CPUs other than the one the
+//! bootloader started on. Each is woken with INIT-SIPI-SIPI into the
+//! real-mode trampoline in trampoline.s, which takes it straight to long
+//! mode on the kernel's page tables and into [`ap_main`].
+
+use core::{
+    arch::global_asm,
+    mem::offset_of,
+    ops::Range,
+    ptr::addr_of,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    acpi::{madt::Madt, AcpiError},
+    clock, cmdline,
+    common::LinkerSymbol,
+    memory::{self, stack::{KernelStack, StackError}, vma::VmError, with_kernel_space, LOW_MEMORY_END, PAGE_SIZE},
+    multiboot2::{Multiboot2MemoryMapEntry, MULTIBOOT2_MEMORY_AVAILABLE},
+    sched,
+};
+
+use super::{
+    apic::{self, DeliveryMode, Icr},
+    exceptions, fpu,
+    gdt::{Gdt, Gdtr64, Tss},
+    idt::Idt,
+    interrupts,
+    paging::{self, MapError, PageFlags},
+    pat, percpu,
+    registers::{self, Cr0, Cr4, Efer, RegisterRead, CR3, CR4, IA32_EFER},
+};
+
+/// Most CPUs that can be brought up, the boot CPU included. A [`CpuMask`]
+/// has a bit for each.
+pub const MAX_CPUS: usize = 64;
+
+/// A set of CPUs, by index.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuMask(pub u64);
+
+impl CpuMask {
+    pub const EMPTY: Self = Self(0);
+    pub const ALL: Self = Self(u64::MAX);
+
+    pub const fn single(cpu: usize) -> Self {
+        Self(1 << cpu)
+    }
+
+    pub const fn contains(self, cpu: usize) -> bool {
+        self.0 & 1 << cpu != 0
+    }
+
+    pub const fn with(self, cpu: usize) -> Self {
+        Self(self.0 | 1 << cpu)
+    }
+
+    pub const fn without(self, cpu: usize) -> Self {
+        Self(self.0 & !(1 << cpu))
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn count(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Indices of the CPUs in the set, lowest first.
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        let mut bits = self.0;
+        core::iter::from_fn(move || {
+            if bits == 0 {
+                return None;
+            }
+
+            let cpu = bits.trailing_zeros() as usize;
+            bits &= bits - 1;
+            Some(cpu)
+        })
+    }
+}
+
+/// What the trampoline needs, at `ap_trampoline_data` in the copy.
+#[repr(C, packed)]
+struct TrampolineData {
+    /// [`TrampolineData::boot_gdt`] at its physical address, for the far
+    /// jump into long mode. Only the low 32 bits of the base are used.
+    boot_gdtr: Gdtr64,
+    /// Far pointer to the trampoline's 64-bit code, in the copy.
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    cr3: u32,
+    cr4: u32,
+    efer: u32,
+    /// The AP's own GDT at its virtual address.
+    gdtr: Gdtr64,
+    stack_top: u64,
+    entry: u64,
+    cpu: u64,
+    /// A GDT that's still mapped when paging comes on, since only the
+    /// trampoline's page is mapped at its physical address.
+    boot_gdt: Gdt,
+}
+
+global_asm!(
+    include_str!("trampoline.s"),
+    KERNEL_DATA_SELECTOR = const Gdt::KERNEL_DATA_SELECTOR,
+    CR0_PE_PG            = const Cr0::new().with_pe(true).with_pg(true).into_bits() as u32 as i32,
+    EFER                 = const registers::IA32_EFER.msr().index() as i32,
+    BOOT_GDTR            = const offset_of!(TrampolineData, boot_gdtr),
+    LONG_MODE_OFFSET     = const offset_of!(TrampolineData, long_mode_offset),
+    TRAMPOLINE_CR3       = const offset_of!(TrampolineData, cr3),
+    TRAMPOLINE_CR4       = const offset_of!(TrampolineData, cr4),
+    TRAMPOLINE_EFER      = const offset_of!(TrampolineData, efer),
+    TRAMPOLINE_GDTR      = const offset_of!(TrampolineData, gdtr),
+    STACK_TOP            = const offset_of!(TrampolineData, stack_top),
+    ENTRY                = const offset_of!(TrampolineData, entry),
+    CPU_INDEX            = const offset_of!(TrampolineData, cpu),
+    TRAMPOLINE_DATA_SIZE = const size_of::<TrampolineData>(),
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: LinkerSymbol;
+    static ap_trampoline_long_mode: LinkerSymbol;
+    static ap_trampoline_data: LinkerSymbol;
+    static ap_trampoline_end: LinkerSymbol;
+}
+
+#[derive(Debug)]
+pub enum SmpError {
+    NoTrampoline,
+    Acpi(AcpiError),
+    Stack(StackError),
+    Map(MapError),
+    Vm(VmError),
+    /// The CPU with this local APIC ID never showed up.
+    Timeout(u32),
+}
+
+/// Local APIC ID of each CPU, by index. The boot CPU is 0.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+/// CPUs running so far. An AP bumps this once it's done starting.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// The boot CPU's are in main.rs; these are the APs', from index 1.
+static mut AP_GDTS: [Gdt; MAX_CPUS - 1] = [const { Gdt::new() }; MAX_CPUS - 1];
+static mut AP_TSSS: [Tss; MAX_CPUS - 1] = [const { Tss::new() }; MAX_CPUS - 1];
+/// Every CPU shares the boot CPU's IDT.
+static IDT: AtomicPtr<Idt> = AtomicPtr::new(null_mut());
+
+/// 0 if there was nowhere to put the trampoline, since page 0 never is.
+static TRAMPOLINE_PADDR: AtomicU64 = AtomicU64::new(0);
+
+/// Offset of [`sym`] into the trampoline.
+fn trampoline_offset(sym: &LinkerSymbol) -> u64 {
+    sym as *const LinkerSymbol as u64 - addr_of!(ap_trampoline_start) as u64
+}
+
+/// Picks a page of low memory for the trampoline, since a SIPI can only
+/// start a CPU below 1 MiB. [`crate::memory::frame`] never hands out low
+/// memory, so only [`reserved`] needs avoiding.
+///
+/// Safety: must be called once, before [`init`].
+pub unsafe fn reserve_trampoline(entries: &[Multiboot2MemoryMapEntry], reserved: &[Range<u64>]) {
+    let size = trampoline_offset(unsafe { &ap_trampoline_end }).next_multiple_of(PAGE_SIZE);
+    let paddr = entries.iter().find_map(|entry| {
+        // the entries are packed, so copy the fields out
+        let (base, length, type_) = (entry.base_paddr, entry.length, entry.type_);
+        if type_ != MULTIBOOT2_MEMORY_AVAILABLE {
+            return None;
+        }
+
+        // page 0 has the real-mode interrupt table and BIOS data
+        let mut start = base.max(PAGE_SIZE).next_multiple_of(PAGE_SIZE);
+        let end = base.saturating_add(length).min(LOW_MEMORY_END);
+        while start + size <= end {
+            match reserved.iter().find(|r| r.start < start + size && start < r.end) {
+                Some(r) => start = r.end.next_multiple_of(PAGE_SIZE),
+                None => return Some(start),
+            }
+        }
+
+        None
+    });
+
+    TRAMPOLINE_PADDR.store(paddr.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// How many CPUs are running.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// The CPUs that are running, which are always the lowest indices.
+pub fn online() -> CpuMask {
+    CpuMask(u64::MAX >> (u64::BITS as usize - cpu_count()))
+}
+
+/// Index of the CPU this runs on.
+pub fn current_cpu() -> usize {
+    percpu::CPU_INDEX.get()
+}
+
+/// Local APIC ID of CPU [`cpu`].
+pub fn apic_id(cpu: usize) -> u32 {
+    assert!(cpu < cpu_count());
+    APIC_IDS[cpu].load(Ordering::Relaxed)
+}
+
+/// Starts the APs the MADT lists, one at a time, up to `maxcpus=` CPUs in
+/// all, or none with `nosmp`. Leaves interrupts enabled, since the waits
+/// between steps go by the clock.
+pub fn init(idt: &'static Idt) -> Result<(), SmpError> {
+    IDT.store(idt as *const Idt as *mut Idt, Ordering::Relaxed);
+    APIC_IDS[0].store(apic::id(), Ordering::Relaxed);
+
+    let max_cpus = if cmdline::has_flag("nosmp") {
+        1
+    } else {
+        cmdline::value("maxcpus").and_then(|n| n.parse().ok()).unwrap_or(MAX_CPUS).clamp(1, MAX_CPUS)
+    };
+
+    if max_cpus == 1 {
+        return Ok(());
+    }
+
+    let paddr = match TRAMPOLINE_PADDR.load(Ordering::Relaxed) {
+        0 => return Err(SmpError::NoTrampoline),
+        paddr => paddr,
+    };
+    let madt = Madt::find().map_err(SmpError::Acpi)?;
+
+    let size = trampoline_offset(unsafe { &ap_trampoline_end });
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            addr_of!(ap_trampoline_start).cast::<u8>(),
+            memory::phys_to_virt::<u8>(paddr),
+            size as usize,
+        )
+    };
+
+    // identity mapped, for the instructions between turning paging on and
+    // jumping into the kernel
+    let len = size.next_multiple_of(PAGE_SIZE);
+    with_kernel_space(|space| space.map_range(paddr, paddr, len, PageFlags::empty())).map_err(SmpError::Map)?;
+
+    let bsp = apic::id();
+    let mut result = Ok(());
+    for apic_id in madt.cpus().filter(|&id| id != bsp) {
+        let cpu = cpu_count();
+        if cpu == max_cpus {
+            break;
+        }
+
+        result = start_ap(cpu, apic_id, paddr);
+        // a CPU that's late rather than dead could still be using the
+        // trampoline, so don't start another
+        if result.is_err() {
+            break;
+        }
+    }
+
+    if result.is_ok() {
+        with_kernel_space(|space| space.unmap_range(paddr, len)).map_err(SmpError::Map)?;
+    }
+
+    result
+}
+
+/// Starts the AP with local APIC ID [`apic_id`] as CPU [`cpu`], and waits
+/// for it to say it's running.
+fn start_ap(cpu: usize, apic_id: u32, paddr: u64) -> Result<(), SmpError> {
+    // allocated here, since nothing the AP could use is safe to share yet
+    let stack = KernelStack::new("ap_main").map_err(SmpError::Stack)?;
+    let tss = unsafe { &raw mut AP_TSSS[cpu - 1] };
+    unsafe { exceptions::init_tss(&mut *tss).map_err(SmpError::Stack)? };
+    percpu::alloc(cpu, tss).map_err(SmpError::Vm)?;
+
+    let gdt = unsafe { &raw const AP_GDTS[cpu - 1] } as u64;
+    let gdtr = |base| Gdtr64 { size: size_of::<Gdt>() as u16 - 1, offset: base };
+
+    let entry: extern "C" fn(usize) -> ! = ap_main;
+    // PCIDs are turned on by the AP itself, and until then CR3 can't have one
+    let cr3 = unsafe { CR3.read() }.with_pcid(0).with_no_flush(false).into_bits();
+    assert!(cr3 <= u32::MAX as u64, "the trampoline can only load a CR3 below 4 GiB");
+    let data_paddr = paddr + trampoline_offset(unsafe { &ap_trampoline_data });
+    let data = TrampolineData {
+        boot_gdtr: gdtr(data_paddr + offset_of!(TrampolineData, boot_gdt) as u64),
+        long_mode_offset: (paddr + trampoline_offset(unsafe { &ap_trampoline_long_mode })) as u32,
+        long_mode_selector: Gdt::KERNEL_CODE_SELECTOR as u16,
+        cr3: cr3 as u32,
+        // the same paging mode as this CPU, and NX so that the kernel's
+        // page tables aren't invalid
+        cr4: Cr4::new().with_pae(true).with_la57(unsafe { CR4.read() }.la57()).into_bits() as u32,
+        efer: Efer::new().with_lme(true).with_nxe(unsafe { IA32_EFER.read() }.nxe()).into_bits() as u32,
+        gdtr: gdtr(gdt),
+        // a late AP might still use it, so it's never freed
+        stack_top: stack.leak(),
+        entry: entry as usize as u64,
+        cpu: cpu as u64,
+        boot_gdt: Gdt::new(),
+    };
+
+    unsafe { memory::phys_to_virt::<TrampolineData>(data_paddr).write_unaligned(data) };
+    APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+
+    apic::send_ipi(Icr::ipi(DeliveryMode::INIT, 0, apic_id).with_level_triggered(true));
+    clock::delay(Duration::from_millis(10));
+
+    // the second SIPI is for CPUs that missed the first
+    let vector = (paddr / PAGE_SIZE) as u8;
+    for _ in 0..2 {
+        apic::send_ipi(Icr::ipi(DeliveryMode::STARTUP, vector, apic_id));
+        if wait_for_cpu(cpu, Duration::from_millis(1)) {
+            return Ok(());
+        }
+    }
+
+    if wait_for_cpu(cpu, Duration::from_millis(100)) { Ok(()) } else { Err(SmpError::Timeout(apic_id)) }
+}
+
+/// Waits up to [`timeout`] for CPU [`cpu`] to finish starting.
+fn wait_for_cpu(cpu: usize, timeout: Duration) -> bool {
+    let end = clock::now() + timeout;
+    while CPU_COUNT.load(Ordering::Acquire) <= cpu {
+        if clock::now() > end {
+            return false;
+        }
+
+        interrupts::enable_and_hlt();
+    }
+
+    true
+}
+
+/// Where the trampoline drops each AP, on its own stack and with its own
+/// GDT loaded.
+extern "C" fn ap_main(cpu: usize) -> ! {
+    unsafe { percpu::init_cpu(cpu) };
+
+    // the same per-CPU setup the boot CPU did
+    paging::enable_protection();
+    pat::init();
+    fpu::init();
+    // the same tables the trampoline loaded, but now tagged with the kernel's
+    // PCID and counted as loaded here for TLB shootdowns
+    with_kernel_space(|space| unsafe { space.activate() });
+
+    unsafe {
+        let gdt = &raw mut AP_GDTS[cpu - 1];
+        let tss = &raw const AP_TSSS[cpu - 1];
+        (*gdt).load_tss(&*tss);
+        (*IDT.load(Ordering::Relaxed)).load();
+    }
+
+    apic::init_ap();
+    apic::start_timer();
+    CPU_COUNT.fetch_add(1, Ordering::Release);
+
+    sched::run_ap()
+}
@@ -0,0 +1,140 @@
+//! Keeping every CPU's TLB in step with the page tables.
+//!
+//! The higher half is the same in every address space, so its pages are
+//! global and flushed on every CPU. The lower half is flushed on the CPUs
+//! that have loaded the address space. With PCIDs, a CPU keeps the entries
+//! of address spaces it has switched away from, and INVPCID flushes those
+//! without loading them; without, a switch drops them anyway.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+};
+
+use crate::memory::PAGE_SIZE;
+
+use super::{
+    cpu::{self, CpuFeatures},
+    ipi,
+    registers::{RegisterRead, RegisterWrite, CR3, CR4},
+    smp::CpuMask,
+};
+
+/// Above this many pages, flushing everything is cheaper than one at a
+/// time.
+const FULL_FLUSH_PAGES: u64 = 32;
+
+/// PCIDs are 12 bits.
+const PCID_COUNT: u16 = 1 << 12;
+
+#[derive(Copy, Clone)]
+#[repr(u64)]
+enum InvpcidKind {
+    ADDRESS = 0,
+    CONTEXT = 1,
+    ALL_GLOBAL = 2,
+}
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+/// PCIDs are never reused, so a CPU can't have stale entries under one.
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+
+/// Turns on global pages, and PCIDs if the CPU can also flush them with
+/// INVPCID.
+///
+/// Safety: CR3 can't have a PCID yet.
+pub unsafe fn init() {
+    let has_pcid = cpu::has(CpuFeatures::PCID) && cpu::has(CpuFeatures::INVPCID);
+    unsafe {
+        let cr4 = CR4.read().with_pge(cpu::has(CpuFeatures::PGE));
+        CR4.write(cr4.with_pcide(has_pcid));
+    }
+
+    PCID_ENABLED.store(has_pcid, Ordering::Relaxed);
+}
+
+pub fn pcids_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// A PCID for a new address space. Once they run out it's 0, which is
+/// flushed on every switch.
+pub fn alloc_pcid() -> u16 {
+    let pcid = NEXT_PCID.fetch_add(1, Ordering::Relaxed);
+    if pcid < PCID_COUNT { pcid } else { 0 }
+}
+
+/// Safety: must only be used with PCIDs on.
+unsafe fn invpcid(kind: InvpcidKind, pcid: u16, vaddr: u64) {
+    let descriptor = [pcid as u64, vaddr];
+    unsafe {
+        asm!("invpcid {}, [{}]", in(reg) kind as u64, in(reg) &descriptor, options(readonly, nostack, preserves_flags))
+    };
+}
+
+fn invlpg(vaddr: u64) {
+    unsafe { asm!("invlpg [{}]", in(reg) vaddr, options(nostack, preserves_flags)) };
+}
+
+/// Flushes this CPU's whole TLB, global pages included.
+fn flush_all_global() {
+    unsafe {
+        if pcids_enabled() {
+            invpcid(InvpcidKind::ALL_GLOBAL, 0, 0);
+        } else {
+            // turning PGE off and on again drops the global pages
+            let cr4 = CR4.read();
+            CR4.write(cr4.with_pge(!cr4.pge()));
+            CR4.write(cr4);
+        }
+    }
+}
+
+/// Pages to flush from one address space.
+#[derive(Copy, Clone)]
+pub struct Flush {
+    /// The address space's top-level table.
+    pub root: u64,
+    pub pcid: u16,
+    pub vaddr: u64,
+    pub pages: u64,
+    /// The pages are in the higher half.
+    pub global: bool,
+}
+
+impl Flush {
+    fn addresses(&self) -> impl Iterator<Item = u64> {
+        (0..self.pages).map(|i| self.vaddr.wrapping_add(i * PAGE_SIZE))
+    }
+
+    /// Flushes the pages from this CPU's TLB.
+    fn run(&self) {
+        let full = self.pages > FULL_FLUSH_PAGES;
+        if self.global {
+            if full { flush_all_global() } else { self.addresses().for_each(invlpg) }
+        } else if pcids_enabled() {
+            unsafe {
+                if full {
+                    invpcid(InvpcidKind::CONTEXT, self.pcid, 0);
+                } else {
+                    self.addresses().for_each(|vaddr| invpcid(InvpcidKind::ADDRESS, self.pcid, vaddr));
+                }
+            }
+        } else if unsafe { CR3.read() }.root_paddr() << 12 == self.root {
+            // otherwise its entries went with the last switch
+            if full {
+                unsafe { CR3.write(CR3.read()) };
+            } else {
+                self.addresses().for_each(invlpg);
+            }
+        }
+    }
+}
+
+/// Flushes [`flush`] on every CPU that could have cached it: all of them
+/// for the higher half, or [`cpus`], the ones that loaded the address
+/// space. Waits until they're done.
+pub fn shootdown(cpus: CpuMask, flush: Flush) {
+    let cpus = if flush.global { CpuMask::ALL } else { cpus };
+    ipi::call_on(cpus, move || flush.run());
+}
@@ -0,0 +1,78 @@
+// where application processors start, in real mode, after a SIPI. this
+// gets copied to a page below 1 MiB, which cs points at, and the boot CPU
+// fills in ap_trampoline_data before each start. the page is also mapped
+// at its physical address, so that the code is still there once paging
+// is on
+
+.pushsection .text.ap_trampoline, "ax"
+
+.global ap_trampoline_start
+.global ap_trampoline_long_mode
+.global ap_trampoline_data
+.global ap_trampoline_end
+
+// real-mode addresses are offsets from cs, the start of the copy
+.set TRAMPOLINE_DATA, ap_trampoline_data - ap_trampoline_start
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    // the boot GDTR holds the physical address of the GDT in the data
+    // below. the operand size prefix makes lgdt load all 32 bits of it
+    // rather than 24
+    .byte 0x66
+    lgdt [TRAMPOLINE_DATA + {BOOT_GDTR}]
+
+    mov eax, [TRAMPOLINE_DATA + {TRAMPOLINE_CR4}]
+    mov cr4, eax
+    mov eax, [TRAMPOLINE_DATA + {TRAMPOLINE_CR3}]
+    mov cr3, eax
+
+    mov ecx, {EFER}
+    mov eax, [TRAMPOLINE_DATA + {TRAMPOLINE_EFER}]
+    xor edx, edx
+    wrmsr
+
+    // protection and paging at once goes straight from real mode to long
+    // mode, without a stop in 32-bit protected mode
+    mov eax, cr0
+    or eax, {CR0_PE_PG}
+    mov cr0, eax
+
+    // through a 32-bit far pointer to ap_trampoline_long_mode in the copy,
+    // which needs the operand size prefix too
+    .byte 0x66
+    ljmp fword ptr [TRAMPOLINE_DATA + {LONG_MODE_OFFSET}]
+
+.code64
+ap_trampoline_long_mode:
+    // still running from the copy, which is where rip-relative addressing
+    // finds the data
+    lea rbx, [rip + ap_trampoline_data]
+
+    // this CPU's own GDT, at its virtual address
+    lgdt [rbx + {TRAMPOLINE_GDTR}]
+    mov ax, {KERNEL_DATA_SELECTOR}
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor eax, eax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [rbx + {STACK_TOP}]
+    mov rdi, [rbx + {CPU_INDEX}]
+    xor ebp, ebp
+    call [rbx + {ENTRY}]
+    ud2
+
+.balign 8
+ap_trampoline_data:
+    .space {TRAMPOLINE_DATA_SIZE}
+ap_trampoline_end:
+
+.popsection
@@ -1,10 +1,10 @@
-use core::sync::atomic::{AtomicIsize, Ordering};
-
-use volatile::VolatileRef;
+use crate::{memory, sync::spin::SpinLock};
 
 use super::ports::{PortRW, PortRead, PortWrite};
 
-const VGA_BUFFER: *mut u16 = 0x000B8000 as _;
+pub mod cp437;
+
+const VGA_BUFFER_PADDR: u64 = 0x000B8000;
 const VGA_WIDTH: usize = 80;
 const VGA_HEIGHT: usize = 25;
 const VGA_BUFFER_LEN: usize = VGA_WIDTH * VGA_HEIGHT;
@@ -22,7 +22,7 @@ impl VgaCursor {
     const CRTC_ADDR: PortRW = PortRW::new(CRTC_ADDR_PORT);
     const CRTC_DATA: PortRW = PortRW::new(CRTC_DATA_PORT);
 
-    pub unsafe fn new() -> Self { 
+    pub const unsafe fn new() -> Self { 
         Self {
             enabled: false,
         } 
@@ -81,7 +81,6 @@ impl VgaCursor {
 
 pub struct VgaWriter {
     pos: usize,
-    buf: VolatileRef<'static, [u16]>,
     cursor: VgaCursor,
 }
 
@@ -106,47 +105,45 @@ pub enum VgaColor {
 	WHITE = 0x000F,
 }
 
-static VGA_NESTING: AtomicIsize = AtomicIsize::new(0);
+/// Assumes the VGA buffer is 80x25 and lies at [`VGA_BUFFER_PADDR`].
+static WRITER: SpinLock<VgaWriter> = SpinLock::new(unsafe { VgaWriter::new() });
+
+/// The one [`VgaWriter`]. Lock it with [`SpinLock::lock_irq`] wherever a
+/// panic could happen meanwhile, so the panic handler doesn't find it held
+/// by the code it interrupted.
+pub fn writer() -> &'static SpinLock<VgaWriter> {
+    &WRITER
+}
 
 impl VgaWriter {
-    /// Returns [`None`] if another [`VgaWriter`] exists.
-    /// 
-    /// Safety: assumes VGA buffer is 80x25 and lies at [`VGA_BUFFER`].
-    pub unsafe fn new() -> Option<Self> {
-        if VGA_NESTING.load(Ordering::Relaxed) > 0 {
-            return None;
+    /// Safety: there must only be one, since it has the buffer to itself.
+    const unsafe fn new() -> Self {
+        Self {
+            pos: 0,
+            cursor: unsafe { VgaCursor::new() },
         }
+    }
 
-        let buf = VolatileRef::from_mut_ref(unsafe { core::slice::from_raw_parts_mut(VGA_BUFFER, VGA_BUFFER_LEN) });
-        let pos = 0;
-        let cursor = unsafe { VgaCursor::new() };
-        
-        VGA_NESTING.fetch_add(1, Ordering::Relaxed);
-
-        Some(Self {
-            buf,
-            pos,
-            cursor,
-        })
+    /// Looked up on every access, since the direct map moves when KASLR
+    /// picks its place.
+    fn buf(&self) -> *mut u16 {
+        memory::phys_to_virt(VGA_BUFFER_PADDR)
     }
 
     fn read(&mut self, index: usize) -> u16 {
         assert!(index < VGA_BUFFER_LEN);
 
-        let c = unsafe { self.buf.as_mut_ptr().as_raw_ptr().cast::<u16>().add(index) };
-        unsafe { c.read_volatile() }
+        unsafe { self.buf().add(index).read_volatile() }
     }
 
     fn write(&mut self, index: usize, val: u16) {
         assert!(index < VGA_BUFFER_LEN);
 
-        let c = unsafe { self.buf.as_mut_ptr().as_raw_ptr().cast::<u16>().add(index) };
-        unsafe { c.write_volatile(val) };
+        unsafe { self.buf().add(index).write_volatile(val) };
     }
 
     pub fn clear(&mut self, background_color: VgaColor) {
         for i in 0..VGA_BUFFER_LEN {
-            let c = unsafe { self.buf.as_mut_ptr().as_raw_ptr().cast::<u16>().add(i) };
             self.write(i, ((background_color as u16) << 12) & 0xF000);
         }
 
@@ -162,6 +159,7 @@ impl VgaWriter {
         self.cursor.disable();
     }
 
+    /// [`c`] is a code page 437 byte; use [`VgaWriter::puts`] for Unicode text.
     pub fn putc(&mut self, c: u8, text_color: VgaColor) {
         self.putc_internal(c, text_color);
         self.cursor.update(self.pos as _);
@@ -182,15 +180,27 @@ impl VgaWriter {
             return;
         }
 
-        let val = self.read(self.pos) & 0xF000 | c as u16 | (((text_color as u16) << 8) & 0x0F00);
+        self.put_glyph(c, text_color);
+    }
+
+    /// Writes [`glyph`] as a raw code page 437 cell, without interpreting
+    /// control characters.
+    fn put_glyph(&mut self, glyph: u8, text_color: VgaColor) {
+        if self.pos >= VGA_BUFFER_LEN {
+            self.scroll();
+        }
+
+        let val = self.read(self.pos) & 0xF000 | glyph as u16 | (((text_color as u16) << 8) & 0x0F00);
         self.write(self.pos, val);
         self.pos += 1;
     }
 
     pub fn puts(&mut self, s: impl AsRef<str>, text_color: VgaColor) {
         for c in s.as_ref().chars() {
-            if c.is_ascii() {
+            if c.is_ascii_control() {
                 self.putc_internal(c as u8, text_color);
+            } else {
+                self.put_glyph(cp437::from_char_lossy(c), text_color);
             }
         }
 
@@ -205,32 +215,28 @@ impl VgaWriter {
             b'\n' => {
                 self.pos += VGA_WIDTH - self.pos % VGA_WIDTH;
             },
-            0x08 => { // backspace
-                let val= self.read(self.pos) & 0xF000 | b' ' as u16 | (((text_color as u16) << 8) & 0x0F00);
-                self.write(self.pos, val);
+            // backspace
+            0x08 if self.pos > 0 => {
+                self.pos -= 1;
             },
             _ => {}
         }
     }
 
+    /// Bytes are code page 437, so everything but the C0 controls has a glyph.
     fn is_trivially_printable(c: u8) -> bool {
-        c >= b' ' && c < 127
+        c >= b' '
     }
 
     fn is_printable(c: u8) -> bool {
-        c != b'\0' && c < 127
+        c != b'\0'
     }
 
     fn scroll(&mut self) {
-        unsafe { core::ptr::copy(VGA_BUFFER.add(VGA_HEIGHT), VGA_BUFFER, self.pos) };
+        let buf = self.buf();
+        unsafe { core::ptr::copy(buf.add(VGA_HEIGHT), buf, self.pos) };
         self.pos -= VGA_WIDTH;
     }
 
 
-}
-
-impl Drop for VgaWriter {
-    fn drop(&mut self) {
-        VGA_NESTING.fetch_sub(1, Ordering::Relaxed);
-    }
 }
\ No newline at end of file
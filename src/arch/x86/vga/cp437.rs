@@ -0,0 +1,69 @@
+//! Code page 437, the character set burned into the VGA text mode font.
+
+/// Glyph shown for characters that have no code page 437 equivalent (■).
+pub const REPLACEMENT: u8 = 0xFE;
+
+/// Unicode equivalents of every code page 437 glyph, indexed by byte.
+///
+/// 0x00 has no glyph and is mapped to NUL. 0x20..0x7F is plain ASCII.
+const CP437: [char; 256] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+    ' ', '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?',
+    '@', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O',
+    'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '[', '\\', ']', '^', '_',
+    '`', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o',
+    'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '{', '|', '}', '~', '⌂',
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Characters that aren't in the table but have a glyph that is visually
+/// close enough to stand in for them.
+const ALIASES: [(char, u8); 12] = [
+    ('β', 0xE1),
+    ('μ', 0xE6),
+    ('∈', 0xEE),
+    ('∅', 0xED),
+    ('Ø', 0xED),
+    ('∑', 0xE4),
+    ('\u{2126}', 0xEA), // ohm sign
+    ('⋅', 0xFA),
+    ('━', 0xCD),
+    ('┃', 0xBA),
+    ('▪', 0xFE),
+    ('\u{FFFD}', REPLACEMENT),
+];
+
+pub const fn to_char(b: u8) -> char {
+    CP437[b as usize]
+}
+
+/// Returns [`None`] if [`c`] has no code page 437 glyph.
+pub fn from_char(c: char) -> Option<u8> {
+    if c == ' ' || c.is_ascii_graphic() {
+        return Some(c as u8);
+    }
+
+    if c == '\0' {
+        return None;
+    }
+
+    if let Some(b) = CP437.iter().position(|&g| g == c) {
+        return Some(b as u8);
+    }
+
+    ALIASES.iter().find(|&&(a, _)| a == c).map(|&(_, b)| b)
+}
+
+/// Like [`from_char`], but falls back to [`REPLACEMENT`].
+pub fn from_char_lossy(c: char) -> u8 {
+    from_char(c).unwrap_or(REPLACEMENT)
+}
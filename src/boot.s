@@ -1,13 +1,60 @@
+.section .boot, "ax"
 .code32
 
 .global _start
 
+// everything but this file is linked in the higher half, so until paging is
+// on, symbols from Rust have to be translated to physical addresses by
+// subtracting KERNEL_VIRT_OFFSET. the image may also have been loaded higher
+// than it's linked, and it's position independent, so nothing here can use
+// an absolute address: ebp holds where _start really is, and everything is
+// found from there. subtracting _start, which is in this section, makes the
+// assembler emit the distance as a pc-relative relocation, which the linker
+// resolves. only at&t syntax takes expressions with two symbols, hence the
+// switching back and forth
+
+// sets \reg to where \addr, a physical address in the image as linked, is
+// now
+.macro phys_addr reg, addr
+    .att_syntax
+    movl $(\addr - _start), %\reg
+    .intel_syntax noprefix
+    add \reg, ebp
+.endm
+
 _start:
     cli
-    // multiboot2 doesn't guarantee us a stack, so we have to make one
+    // multiboot2 doesn't guarantee us a stack, so find out where we are
+    // before making one
+    mov ebp, {KERNEL_LINK_PADDR}
+    lea esi, [ebx + 8]
+    mov edi, ebx
+    add edi, [ebx]
+4:
+    cmp esi, edi
+    jae 5f
+    mov ecx, [esi]
+    test ecx, ecx
+    jz 5f
+    cmp ecx, {MULTIBOOT2_TAG_LOAD_BASE}
+    jne 6f
+    mov ebp, [esi + 8]
+    jmp 5f
+6:
+    // tags are padded to 8 bytes
+    mov ecx, [esi + 4]
+    add ecx, 7
+    and ecx, ~7
+    add esi, ecx
+    jmp 4b
+5:
+    // from where the image starts to where _start is
+    .att_syntax
+    subl $(KERNEL_START - ({KERNEL_VIRT_OFFSET}) - _start), %ebp
+    .intel_syntax noprefix
+
     // assumes stack size is 16384
-    lea esp, [INIT_STACK + {INIT_STACK_SIZE}]
-    mov ebp, esp
+    phys_addr esp, INIT_STACK + {INIT_STACK_SIZE} - ({KERNEL_VIRT_OFFSET})
     // preserve eax and ebx for their multiboot info
     push eax
     push ebx
@@ -21,28 +68,66 @@ _start:
     mov gs, ax
     pop ebx
     pop eax
-    ljmp {KERNEL_CODE_SELECTOR}, offset long_mode
-// sets up a basic page table that identity maps the first 2mb of memory
-// intended to be replaced with a proper page table by the kernel
-// assumes kernel KERNEL_END < 2mb
+    // a far return rather than a far jump, since long_mode's address isn't
+    // known until now
+    phys_addr ecx, long_mode
+    push {KERNEL_CODE_SELECTOR}
+    push ecx
+    retf
+// sets up basic page tables mapping the first 1gb of memory three times:
+// identity mapped so this code keeps running, at PHYS_MAP_BASE for the
+// kernel's direct map, and at KERNEL_VIRT_OFFSET where the kernel is linked,
+// shifted by the load delta. the first 2mb go through INIT_PT and the rest
+// are 2mb pages in INIT_PDT and INIT_KERNEL_PDT. the identity map is dropped
+// by the kernel once it's running in the higher half
 
 init_paging:
-    // assumes INIT_PT is already initialized in Rust
-    lea eax, [INIT_PT]
+    // assumes INIT_PT and INIT_PDT are already initialized in Rust
+    phys_addr eax, INIT_PT - ({KERNEL_VIRT_OFFSET})
+    or eax, {PAGE_PRESENT} | {PAGE_RW}
+    phys_addr edx, INIT_PDT - ({KERNEL_VIRT_OFFSET})
+    mov [edx], eax
+
+    // the load delta, which is where physical address 0 would be now, is
+    // 2mb aligned, which the relocatable header tag asks the bootloader for
+    phys_addr edi, INIT_KERNEL_PDT - ({KERNEL_VIRT_OFFSET})
+    phys_addr eax, KERNEL_START - ({KERNEL_VIRT_OFFSET}) - {KERNEL_LINK_PADDR}
+    or eax, {PAGE_PRESENT} | {PAGE_RW} | {PAGE_PS}
+    mov ecx, 512
+7:
+    mov [edi], eax
+    mov dword ptr [edi + 4], 0
+    add eax, 0x200000
+    add edi, 8
+    loop 7b
+
+    phys_addr eax, INIT_PDT - ({KERNEL_VIRT_OFFSET})
     or eax, {PAGE_PRESENT} | {PAGE_RW}
-    mov [INIT_PDT], eax
+    phys_addr edx, INIT_PDPT - ({KERNEL_VIRT_OFFSET})
+    mov [edx], eax
 
-    lea eax, [INIT_PDT]
+    phys_addr eax, INIT_KERNEL_PDT - ({KERNEL_VIRT_OFFSET})
     or eax, {PAGE_PRESENT} | {PAGE_RW}
-    mov [INIT_PDPT], eax
-    
-    lea eax, [INIT_PDPT]
+    phys_addr edx, INIT_KERNEL_PDPT - ({KERNEL_VIRT_OFFSET})
+    mov [edx + {KERNEL_PDPT_INDEX} * 8], eax
+
+    phys_addr eax, INIT_PDPT - ({KERNEL_VIRT_OFFSET})
     or eax, {PAGE_PRESENT} | {PAGE_RW}
-    mov [INIT_PML4T], eax
-    
-    lea eax, INIT_PML4T
+    phys_addr edx, INIT_PML4T - ({KERNEL_VIRT_OFFSET})
+    mov [edx], eax
+    mov [edx + {PHYS_MAP_PML4_INDEX} * 8], eax
+
+    phys_addr eax, INIT_KERNEL_PDPT - ({KERNEL_VIRT_OFFSET})
+    or eax, {PAGE_PRESENT} | {PAGE_RW}
+    phys_addr edx, INIT_PML4T - ({KERNEL_VIRT_OFFSET})
+    mov [edx + {KERNEL_PML4_INDEX} * 8], eax
+
+    // the direct map and the kernel share a PML5 entry
+    phys_addr eax, INIT_PML4T - ({KERNEL_VIRT_OFFSET})
     or eax, {PAGE_PRESENT} | {PAGE_RW}
-    mov [INIT_PML5T], eax
+    phys_addr edx, INIT_PML5T - ({KERNEL_VIRT_OFFSET})
+    mov [edx], eax
+    mov [edx + {KERNEL_PML5_INDEX} * 8], eax
 
     // disable paging in case it's enabled for some reason
     mov ebx, cr0
@@ -58,7 +143,27 @@ init_paging:
     or eax, {EFER_LME}
     wrmsr
 
-    lea eax, [INIT_PML4T]
+    // use 5-level paging if the CPU has it, since LA57 can only be changed
+    // while paging is off
+    xor eax, eax
+    cpuid
+    cmp eax, 7
+    jb 3f
+    mov eax, 7
+    xor ecx, ecx
+    cpuid
+    test ecx, {CPUID_7_ECX_LA57}
+    jz 3f
+
+    mov eax, cr4
+    or eax, {CR4_LA57}
+    mov cr4, eax
+
+    phys_addr eax, INIT_PML5T - ({KERNEL_VIRT_OFFSET})
+    jmp 2f
+3:
+    phys_addr eax, INIT_PML4T - ({KERNEL_VIRT_OFFSET})
+2:
     mov cr3, eax
 
     mov ebx, cr0
@@ -70,17 +175,50 @@ init_paging:
 // sets GDTR.base to addr_of GDT and loads GDT
 init_gdt: 
     // assumes GDT is loaded in the lower 4GB of memory
-    lea eax, [GDT]
-    mov [GDTR+{GDTR_OFFSET}], eax
-    lgdt [GDTR]
+    phys_addr eax, GDT - ({KERNEL_VIRT_OFFSET})
+    phys_addr edx, GDTR - ({KERNEL_VIRT_OFFSET})
+    mov [edx + {GDTR_OFFSET}], eax
+    lgdt [edx]
     ret
 
 .code64
 long_mode:
-    // parameters for kernel_main
-    mov rdi, rax
-    mov rsi, rbx
-    
+    // still running from the identity map, so jump up to where the kernel
+    // is linked. rip-relative addressing gives that plus the load delta
+    lea rcx, [rip + higher_half]
+    phys_addr edx, KERNEL_START - ({KERNEL_VIRT_OFFSET}) - {KERNEL_LINK_PADDR}
+    sub rcx, rdx
+    jmp rcx
+
+.text
+higher_half:
+    lea rsp, [rip + INIT_STACK + {INIT_STACK_SIZE}]
+
+    // parameters for kernel_main, zero-extended from 32 bits and kept
+    // where kernel_relocate leaves them alone
+    mov r12d, eax
+    mov r13d, ebx
+
+    // the image is also mapped wherever kernel_relocate moved it to, with
+    // its relocations applied for running there, so carry on there
+    mov edi, ebx
+    call kernel_relocate
+    add rsp, rax
+    lea rcx, [rip + 8f]
+    add rcx, rax
+    jmp rcx
+8:
+    mov rbp, rsp
+
+    // point GDTR at the higher half too, so that nothing needs the
+    // identity map after this
+    lea rcx, [rip + GDT]
+    mov [rip + GDTR + {GDTR_OFFSET}], rcx
+    lgdt [rip + GDTR]
+
+    mov edi, r12d
+    mov esi, r13d
+
     // clear other gprs
     xor rax, rax
     xor rbx, rbx
@@ -0,0 +1,37 @@
+//! Monotonic time since boot, driven by the timer interrupt.
+
+use core::{sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+use crate::arch::x86::interrupts;
+
+/// How often the timer interrupt calls [`tick`].
+pub const TICK_HZ: u32 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Called from the timer interrupt once per tick.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+const NANOS_PER_TICK: u64 = 1_000_000_000 / TICK_HZ as u64;
+
+/// Time since the timer was started, with a resolution of one tick.
+pub fn now() -> Duration {
+    Duration::from_nanos(ticks() * NANOS_PER_TICK)
+}
+
+/// Waits at least [`duration`], halting between ticks. Interrupts are left
+/// enabled, since nothing else would move the clock.
+pub fn delay(duration: Duration) {
+    // the current tick is already partly over, so wait for one more
+    let wait = (duration.as_nanos() as u64).div_ceil(NANOS_PER_TICK) + 1;
+    let end = ticks() + wait;
+    while ticks() < end {
+        interrupts::enable_and_hlt();
+    }
+}
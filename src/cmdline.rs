@@ -0,0 +1,45 @@
+//! The kernel command line, as passed by the bootloader: words separated by
+//! whitespace, each either a flag like `nokaslr` or a `key=value` pair.
+
+const MAX_LEN: usize = 256;
+
+static mut BUF: [u8; MAX_LEN] = [0; MAX_LEN];
+static mut LEN: usize = 0;
+
+/// Keeps a copy of [`cmdline`], since the bootloader's copy is only mapped
+/// while booting. Anything past [`MAX_LEN`] bytes is dropped.
+///
+/// Safety: must be called before anything reads the command line, while
+/// only one CPU is running.
+pub unsafe fn init(cmdline: &str) {
+    let mut len = cmdline.len().min(MAX_LEN);
+    while !cmdline.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    let buf = &raw mut BUF;
+    unsafe {
+        (&mut *buf)[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+        LEN = len;
+    }
+}
+
+pub fn get() -> &'static str {
+    let buf = &raw const BUF;
+    let buf = unsafe { &(&*buf)[..LEN] };
+    // only ever copied from a str, cut at a char boundary
+    unsafe { core::str::from_utf8_unchecked(buf) }
+}
+
+pub fn args() -> impl Iterator<Item = &'static str> {
+    get().split_whitespace()
+}
+
+pub fn has_flag(flag: &str) -> bool {
+    args().any(|arg| arg == flag)
+}
+
+/// Returns the value of the first `key=value` argument for [`key`].
+pub fn value(key: &str) -> Option<&'static str> {
+    args().find_map(|arg| arg.strip_prefix(key)?.strip_prefix('='))
+}
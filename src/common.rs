@@ -10,6 +10,19 @@ macro_rules! size_of {
     };
 }
 
+/// Declares variables that every CPU has its own copy of. They're
+/// [`crate::arch::x86::percpu::PerCpu`]s, reached through GS.
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[unsafe(link_section = ".percpu")]
+            $vis static $name: $crate::arch::x86::percpu::PerCpu<$t> = $crate::arch::x86::percpu::PerCpu::new($init);
+        )*
+    };
+}
+
 #[repr(C)]
 pub struct LinkerSymbol {
     __: c_void,
@@ -0,0 +1,213 @@
+//! Source-independent input events, modelled on Linux's evdev.
+//!
+//! Drivers call [`report`] with whatever happened; every open
+//! [`InputReader`] gets its own copy in its own queue, so consumers don't
+//! have to care whether a key came from a PS/2 keyboard or a serial
+//! terminal.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use bitflags::bitflags;
+
+use crate::{
+    arch::x86::interrupts,
+    clock,
+    keyboard::{self, KeyEvent, keymap},
+    mouse::MouseButton,
+    ringbuf::RingBuffer,
+    sync::wait_queue::WaitQueue,
+};
+
+pub mod serial;
+
+const MAX_READERS: usize = 8;
+const QUEUE_LEN: usize = 128;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputSource {
+    KEYBOARD,
+    MOUSE,
+    SERIAL,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RelativeAxis {
+    X,
+    Y,
+    WHEEL,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AbsoluteAxis {
+    X,
+    Y,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum InputEventKind {
+    Key(KeyEvent),
+    Relative { axis: RelativeAxis, value: i32 },
+    Absolute { axis: AbsoluteAxis, value: i32 },
+    Button { button: MouseButton, pressed: bool },
+    /// Ends a group of events that happened together, like the motion and
+    /// buttons from a single mouse packet.
+    Sync,
+}
+
+impl InputEventKind {
+    fn filter(&self) -> InputFilter {
+        match self {
+            Self::Key(_) => InputFilter::KEY,
+            Self::Relative { .. } => InputFilter::RELATIVE,
+            Self::Absolute { .. } => InputFilter::ABSOLUTE,
+            Self::Button { .. } => InputFilter::BUTTON,
+            Self::Sync => InputFilter::SYNC,
+        }
+    }
+}
+
+bitflags! {
+    /// Which kinds of event an [`InputReader`] wants.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct InputFilter: u8 {
+        const KEY = 1 << 0;
+        const RELATIVE = 1 << 1;
+        const ABSOLUTE = 1 << 2;
+        const BUTTON = 1 << 3;
+        const SYNC = 1 << 4;
+
+        const POINTER = Self::RELATIVE.bits() | Self::ABSOLUTE.bits() | Self::BUTTON.bits();
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InputEvent {
+    /// Time since boot, from [`clock::now`].
+    pub timestamp: Duration,
+    pub source: InputSource,
+    pub kind: InputEventKind,
+}
+
+impl InputEvent {
+    /// Returns the character a key event types. Keyboard events go through
+    /// the active keymap; serial events were decoded against [`keymap::US`],
+    /// so they go back through that regardless.
+    pub fn translate(&self) -> Option<char> {
+        let InputEventKind::Key(key) = &self.kind else {
+            return None;
+        };
+
+        match self.source {
+            InputSource::SERIAL => keymap::US.translate(key),
+            _ => keyboard::keymap().translate(key),
+        }
+    }
+}
+
+struct ReaderSlot {
+    in_use: AtomicBool,
+    filter: AtomicU8,
+    dropped: AtomicUsize,
+    queue: RingBuffer<InputEvent, QUEUE_LEN>,
+}
+
+impl ReaderSlot {
+    const fn new() -> Self {
+        Self {
+            in_use: AtomicBool::new(false),
+            filter: AtomicU8::new(0),
+            dropped: AtomicUsize::new(0),
+            queue: RingBuffer::new(),
+        }
+    }
+}
+
+static READERS: [ReaderSlot; MAX_READERS] = [const { ReaderSlot::new() }; MAX_READERS];
+/// Readers sleeping in [`InputReader::read_wait`], woken by every report.
+static READ_WAITERS: WaitQueue = WaitQueue::new();
+
+/// Timestamps [`events`] and queues them, followed by a
+/// [`InputEventKind::Sync`], for every reader that wants them.
+pub fn report(source: InputSource, events: &[InputEventKind]) {
+    let timestamp = clock::now();
+
+    // readers' queues are single-producer, and drivers report from their IRQ
+    // handlers, so keep other handlers out while pushing
+    interrupts::without(|| {
+        for slot in READERS.iter().filter(|s| s.in_use.load(Ordering::Acquire)) {
+            let filter = InputFilter::from_bits_truncate(slot.filter.load(Ordering::Relaxed));
+            for &kind in events.iter().chain(core::iter::once(&InputEventKind::Sync)) {
+                if !filter.contains(kind.filter()) {
+                    continue;
+                }
+
+                if !slot.queue.push(InputEvent { timestamp, source, kind }) {
+                    slot.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+
+    READ_WAITERS.wake_all();
+}
+
+/// A handle with its own queue of input events. Events reported before the
+/// reader was opened aren't seen.
+pub struct InputReader {
+    slot: &'static ReaderSlot,
+}
+
+impl InputReader {
+    /// Returns [`None`] if every reader slot is taken.
+    pub fn open(filter: InputFilter) -> Option<Self> {
+        let slot = READERS.iter().find(|s| {
+            s.in_use.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+        })?;
+
+        while slot.queue.pop().is_some() {}
+        slot.dropped.store(0, Ordering::Relaxed);
+        slot.filter.store(filter.bits(), Ordering::Relaxed);
+
+        Some(Self { slot })
+    }
+
+    pub fn set_filter(&self, filter: InputFilter) {
+        self.slot.filter.store(filter.bits(), Ordering::Relaxed);
+    }
+
+    pub fn read(&self) -> Option<InputEvent> {
+        self.slot.queue.pop()
+    }
+
+    /// Sleeps until there's an event, and returns it.
+    pub fn read_wait(&self) -> InputEvent {
+        loop {
+            if let Some(event) = self.read() {
+                return event;
+            }
+
+            READ_WAITERS.wait_until(|| !self.is_empty());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slot.queue.is_empty()
+    }
+
+    /// Number of events lost because this reader's queue was full.
+    pub fn dropped(&self) -> usize {
+        self.slot.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for InputReader {
+    fn drop(&mut self) {
+        // an empty filter stops producers pushing into the slot before it's
+        // reused, so the next owner doesn't inherit our events
+        self.slot.filter.store(0, Ordering::Relaxed);
+        self.slot.in_use.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,184 @@
+//! Turns bytes from a serial terminal into key events, so a headless machine
+//! can be driven the same way as one with a keyboard.
+//!
+//! Printable ASCII is looked up in [`keymap::US`], C0 controls become Ctrl
+//! combinations, and the common VT100/xterm escape sequences become
+//! navigation keys, along with any xterm modifier parameter (`ESC [ 1 ; 5 A`
+//! for Ctrl+Up). A lone ESC is only recognized once the next byte
+//! arrives, and an ESC followed by anything else is treated as Alt held down.
+//! Non-ASCII bytes are dropped.
+
+use crate::{
+    keyboard::{keymap, KeyCode, KeyEvent, KeyState, Modifiers},
+    sync::spin::SpinLock,
+};
+
+use super::{report, InputEventKind, InputSource};
+
+const ESC: u8 = 0x1B;
+const DEL: u8 = 0x7F;
+const BS: u8 = 0x08;
+
+#[derive(Copy, Clone)]
+enum DecoderState {
+    START,
+    ESCAPE,
+    /// Inside `ESC [`, accumulating the first numeric parameter.
+    CSI(u8),
+    /// Past the `;` in `ESC [`, with the first parameter, accumulating the
+    /// modifier parameter.
+    CSI_MODIFIERS(u8, u8),
+    /// After `ESC O`, which some terminals send before arrow keys, Home and
+    /// End.
+    SS3,
+}
+
+pub struct SerialKeyDecoder {
+    state: DecoderState,
+    /// Terminals may send `\r\n` for Enter, which shouldn't count twice.
+    after_cr: bool,
+}
+
+static DECODER: SpinLock<SerialKeyDecoder> = SpinLock::new(SerialKeyDecoder::new());
+
+impl SerialKeyDecoder {
+    pub const fn new() -> Self {
+        Self {
+            state: DecoderState::START,
+            after_cr: false,
+        }
+    }
+
+    pub fn feed(&mut self, b: u8) {
+        let state = self.state;
+        self.state = DecoderState::START;
+
+        match (state, b) {
+            (DecoderState::START, ESC) => self.state = DecoderState::ESCAPE,
+            (DecoderState::START, _) => self.byte(b, Modifiers::empty()),
+            (DecoderState::ESCAPE, b'[') => self.state = DecoderState::CSI(0),
+            (DecoderState::ESCAPE, b'O') => self.state = DecoderState::SS3,
+            (DecoderState::ESCAPE, ESC) => {
+                press(KeyCode::ESC, Modifiers::empty());
+                self.state = DecoderState::ESCAPE;
+            },
+            (DecoderState::ESCAPE, _) => self.byte(b, Modifiers::LEFT_ALT),
+            (DecoderState::CSI(n), b'0'..=b'9') => {
+                self.state = DecoderState::CSI(n.saturating_mul(10).saturating_add(b - b'0'));
+            },
+            (DecoderState::CSI(n), b';') => self.state = DecoderState::CSI_MODIFIERS(n, 0),
+            (DecoderState::CSI(n), _) => {
+                if let Some(code) = Self::csi_key(n, b) {
+                    press(code, Modifiers::empty());
+                }
+            },
+            (DecoderState::CSI_MODIFIERS(n, m), b'0'..=b'9') => {
+                self.state = DecoderState::CSI_MODIFIERS(n, m.saturating_mul(10).saturating_add(b - b'0'));
+            },
+            // any further parameters mean nothing to us
+            (DecoderState::CSI_MODIFIERS(n, m), b';') => self.state = DecoderState::CSI_MODIFIERS(n, m),
+            (DecoderState::CSI_MODIFIERS(n, m), _) => {
+                if let Some(code) = Self::csi_key(n, b) {
+                    press(code, csi_modifiers(m));
+                }
+            },
+            (DecoderState::SS3, _) => match Self::ss3_key(b) {
+                Some(code) => press(code, Modifiers::empty()),
+                // it was Alt+O after all
+                None => {
+                    self.byte(b'O', Modifiers::LEFT_ALT);
+                    self.feed(b);
+                },
+            },
+        }
+    }
+
+    fn ss3_key(final_byte: u8) -> Option<KeyCode> {
+        Some(match final_byte {
+            b'A' => KeyCode::UP,
+            b'B' => KeyCode::DOWN,
+            b'C' => KeyCode::RIGHT,
+            b'D' => KeyCode::LEFT,
+            b'H' => KeyCode::HOME,
+            b'F' => KeyCode::END,
+            _ => return None,
+        })
+    }
+
+    fn csi_key(param: u8, final_byte: u8) -> Option<KeyCode> {
+        Some(match (final_byte, param) {
+            (b'A', _) => KeyCode::UP,
+            (b'B', _) => KeyCode::DOWN,
+            (b'C', _) => KeyCode::RIGHT,
+            (b'D', _) => KeyCode::LEFT,
+            (b'H', _) => KeyCode::HOME,
+            (b'F', _) => KeyCode::END,
+            (b'~', 1 | 7) => KeyCode::HOME,
+            (b'~', 2) => KeyCode::INSERT,
+            (b'~', 3) => KeyCode::DELETE,
+            (b'~', 4 | 8) => KeyCode::END,
+            (b'~', 5) => KeyCode::PAGE_UP,
+            (b'~', 6) => KeyCode::PAGE_DOWN,
+            _ => return None,
+        })
+    }
+
+    fn byte(&mut self, b: u8, modifiers: Modifiers) {
+        let after_cr = self.after_cr;
+        self.after_cr = b == b'\r';
+
+        let (code, modifiers) = match b {
+            b'\n' if after_cr => return,
+            b'\r' | b'\n' => (KeyCode::ENTER, modifiers),
+            b'\t' => (KeyCode::TAB, modifiers),
+            // most terminals send DEL for the backspace key
+            BS | DEL => (KeyCode::BACKSPACE, modifiers),
+            // NUL comes back as Ctrl+@, which the keymaps turn into 0
+            0x00..=0x1F => {
+                let Some((code, shift)) = keymap::US.find((b | 0x40).to_ascii_lowercase() as char) else {
+                    return;
+                };
+
+                let shift = if shift { Modifiers::LEFT_SHIFT } else { Modifiers::empty() };
+                (code, modifiers | shift | Modifiers::LEFT_CTRL)
+            },
+            0x20..=0x7E => {
+                let Some((code, shift)) = keymap::US.find(b as char) else {
+                    return;
+                };
+
+                let shift = if shift { Modifiers::LEFT_SHIFT } else { Modifiers::empty() };
+                (code, modifiers | shift)
+            },
+            _ => return,
+        };
+
+        press(code, modifiers);
+    }
+}
+
+/// Decodes an xterm modifier parameter, which is one more than a bitmask of
+/// shift, alt, ctrl and meta.
+fn csi_modifiers(param: u8) -> Modifiers {
+    let bits = param.saturating_sub(1);
+    let mut modifiers = Modifiers::empty();
+    modifiers.set(Modifiers::LEFT_SHIFT, bits & 1 != 0);
+    modifiers.set(Modifiers::LEFT_ALT, bits & 2 != 0);
+    modifiers.set(Modifiers::LEFT_CTRL, bits & 4 != 0);
+    modifiers.set(Modifiers::LEFT_META, bits & 8 != 0);
+    modifiers
+}
+
+/// Reports a press and release of [`code`], since a terminal only tells us a
+/// key was typed.
+fn press(code: KeyCode, modifiers: Modifiers) {
+    for state in [KeyState::PRESSED, KeyState::RELEASED] {
+        report(InputSource::SERIAL, &[InputEventKind::Key(KeyEvent { code, state, modifiers })]);
+    }
+}
+
+/// Feeds one byte received from the serial console, normally from the
+/// UART's IRQ handler.
+pub fn feed(b: u8) {
+    DECODER.lock_irq().feed(b);
+}
@@ -0,0 +1,278 @@
+//! Hardware-independent key codes, modifier state and keymaps.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use bitflags::bitflags;
+use num_derive::FromPrimitive;
+
+use keymap::Keymap;
+
+pub mod keymap;
+
+/// Physical keys, named after their position on a US layout and numbered the
+/// same way as Linux's evdev codes (which in turn match scancode set 1 for
+/// the main block).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+#[repr(u8)]
+pub enum KeyCode {
+    ESC = 1,
+    N1 = 2,
+    N2 = 3,
+    N3 = 4,
+    N4 = 5,
+    N5 = 6,
+    N6 = 7,
+    N7 = 8,
+    N8 = 9,
+    N9 = 10,
+    N0 = 11,
+    MINUS = 12,
+    EQUAL = 13,
+    BACKSPACE = 14,
+    TAB = 15,
+    Q = 16,
+    W = 17,
+    E = 18,
+    R = 19,
+    T = 20,
+    Y = 21,
+    U = 22,
+    I = 23,
+    O = 24,
+    P = 25,
+    LEFT_BRACE = 26,
+    RIGHT_BRACE = 27,
+    ENTER = 28,
+    LEFT_CTRL = 29,
+    A = 30,
+    S = 31,
+    D = 32,
+    F = 33,
+    G = 34,
+    H = 35,
+    J = 36,
+    K = 37,
+    L = 38,
+    SEMICOLON = 39,
+    APOSTROPHE = 40,
+    GRAVE = 41,
+    LEFT_SHIFT = 42,
+    BACKSLASH = 43,
+    Z = 44,
+    X = 45,
+    C = 46,
+    V = 47,
+    B = 48,
+    N = 49,
+    M = 50,
+    COMMA = 51,
+    DOT = 52,
+    SLASH = 53,
+    RIGHT_SHIFT = 54,
+    KP_ASTERISK = 55,
+    LEFT_ALT = 56,
+    SPACE = 57,
+    CAPS_LOCK = 58,
+    F1 = 59,
+    F2 = 60,
+    F3 = 61,
+    F4 = 62,
+    F5 = 63,
+    F6 = 64,
+    F7 = 65,
+    F8 = 66,
+    F9 = 67,
+    F10 = 68,
+    NUM_LOCK = 69,
+    SCROLL_LOCK = 70,
+    KP7 = 71,
+    KP8 = 72,
+    KP9 = 73,
+    KP_MINUS = 74,
+    KP4 = 75,
+    KP5 = 76,
+    KP6 = 77,
+    KP_PLUS = 78,
+    KP1 = 79,
+    KP2 = 80,
+    KP3 = 81,
+    KP0 = 82,
+    KP_DOT = 83,
+    /// The extra key between left shift and Z on ISO keyboards.
+    KEY_102ND = 86,
+    F11 = 87,
+    F12 = 88,
+    KP_ENTER = 96,
+    RIGHT_CTRL = 97,
+    KP_SLASH = 98,
+    SYSRQ = 99,
+    /// AltGr on non-US layouts.
+    RIGHT_ALT = 100,
+    HOME = 102,
+    UP = 103,
+    PAGE_UP = 104,
+    LEFT = 105,
+    RIGHT = 106,
+    END = 107,
+    DOWN = 108,
+    PAGE_DOWN = 109,
+    INSERT = 110,
+    DELETE = 111,
+    PAUSE = 119,
+    LEFT_META = 125,
+    RIGHT_META = 126,
+    COMPOSE = 127,
+}
+
+impl KeyCode {
+    pub const COUNT: usize = 128;
+
+    /// Keys that type digits with num lock on and navigate with it off.
+    pub fn is_keypad_navigation(self) -> bool {
+        matches!(
+            self,
+            Self::KP0 | Self::KP1 | Self::KP2 | Self::KP3 | Self::KP4 | Self::KP5
+                | Self::KP6 | Self::KP7 | Self::KP8 | Self::KP9 | Self::KP_DOT
+        )
+    }
+
+    pub fn modifier(self) -> Option<Modifiers> {
+        Some(match self {
+            Self::LEFT_SHIFT => Modifiers::LEFT_SHIFT,
+            Self::RIGHT_SHIFT => Modifiers::RIGHT_SHIFT,
+            Self::LEFT_CTRL => Modifiers::LEFT_CTRL,
+            Self::RIGHT_CTRL => Modifiers::RIGHT_CTRL,
+            Self::LEFT_ALT => Modifiers::LEFT_ALT,
+            Self::RIGHT_ALT => Modifiers::RIGHT_ALT,
+            Self::LEFT_META => Modifiers::LEFT_META,
+            Self::RIGHT_META => Modifiers::RIGHT_META,
+            _ => return None,
+        })
+    }
+
+    pub fn lock(self) -> Option<Modifiers> {
+        Some(match self {
+            Self::CAPS_LOCK => Modifiers::CAPS_LOCK,
+            Self::NUM_LOCK => Modifiers::NUM_LOCK,
+            Self::SCROLL_LOCK => Modifiers::SCROLL_LOCK,
+            _ => return None,
+        })
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct Modifiers: u16 {
+        const LEFT_SHIFT = 1 << 0;
+        const RIGHT_SHIFT = 1 << 1;
+        const LEFT_CTRL = 1 << 2;
+        const RIGHT_CTRL = 1 << 3;
+        const LEFT_ALT = 1 << 4;
+        const RIGHT_ALT = 1 << 5;
+        const LEFT_META = 1 << 6;
+        const RIGHT_META = 1 << 7;
+        const CAPS_LOCK = 1 << 8;
+        const NUM_LOCK = 1 << 9;
+        const SCROLL_LOCK = 1 << 10;
+
+        const SHIFT = Self::LEFT_SHIFT.bits() | Self::RIGHT_SHIFT.bits();
+        const CTRL = Self::LEFT_CTRL.bits() | Self::RIGHT_CTRL.bits();
+        const META = Self::LEFT_META.bits() | Self::RIGHT_META.bits();
+        const LOCKS = Self::CAPS_LOCK.bits() | Self::NUM_LOCK.bits() | Self::SCROLL_LOCK.bits();
+    }
+}
+
+impl Modifiers {
+    pub fn shift(self) -> bool {
+        self.intersects(Self::SHIFT)
+    }
+
+    pub fn ctrl(self) -> bool {
+        self.intersects(Self::CTRL)
+    }
+
+    pub fn alt(self) -> bool {
+        self.contains(Self::LEFT_ALT)
+    }
+
+    pub fn altgr(self) -> bool {
+        self.contains(Self::RIGHT_ALT)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyState {
+    RELEASED,
+    PRESSED,
+    /// Generated by typematic repeat while the key is held down.
+    REPEATED,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// Modifier and lock state after this event has been applied.
+    pub modifiers: Modifiers,
+}
+
+/// Tracks which keys are held down, and the modifier and lock state they
+/// imply, so that drivers only have to report raw presses and releases.
+pub struct KeyboardState {
+    down: [u64; KeyCode::COUNT / 64],
+    modifiers: Modifiers,
+}
+
+impl KeyboardState {
+    pub const fn new() -> Self {
+        Self {
+            down: [0; KeyCode::COUNT / 64],
+            modifiers: Modifiers::empty(),
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    pub fn is_down(&self, code: KeyCode) -> bool {
+        self.down[code as usize / 64] & 1 << (code as usize % 64) != 0
+    }
+
+    /// Returns the resulting event and whether a lock key changed state (so
+    /// the keyboard's LEDs need updating).
+    pub fn update(&mut self, code: KeyCode, pressed: bool) -> (KeyEvent, bool) {
+        let was_down = self.is_down(code);
+        let bit = 1 << (code as usize % 64);
+        let mut locks_changed = false;
+
+        let state = if pressed {
+            self.down[code as usize / 64] |= bit;
+            if was_down { KeyState::REPEATED } else { KeyState::PRESSED }
+        } else {
+            self.down[code as usize / 64] &= !bit;
+            KeyState::RELEASED
+        };
+
+        if let Some(m) = code.modifier() {
+            self.modifiers.set(m, pressed);
+        }
+
+        if let Some(l) = code.lock() && state == KeyState::PRESSED {
+            self.modifiers.toggle(l);
+            locks_changed = true;
+        }
+
+        (KeyEvent { code, state, modifiers: self.modifiers }, locks_changed)
+    }
+}
+
+static KEYMAP: AtomicPtr<Keymap> = AtomicPtr::new(&raw const keymap::US as *mut Keymap);
+
+pub fn keymap() -> &'static Keymap {
+    unsafe { &*KEYMAP.load(Ordering::Relaxed) }
+}
+
+pub fn set_keymap(keymap: &'static Keymap) {
+    KEYMAP.store(keymap as *const Keymap as *mut Keymap, Ordering::Relaxed);
+}
@@ -0,0 +1,345 @@
+use num_traits::FromPrimitive;
+
+use super::{KeyCode, KeyEvent, KeyState, Modifiers};
+
+/// No character for this key and level.
+const NONE: char = '\0';
+
+/// Keys that type the same thing on every layout.
+const COMMON: [(KeyCode, char, char, char); 17] = [
+    (KeyCode::ESC, '\x1B', '\x1B', NONE),
+    (KeyCode::BACKSPACE, '\x08', '\x08', NONE),
+    (KeyCode::TAB, '\t', '\t', NONE),
+    (KeyCode::ENTER, '\n', '\n', NONE),
+    (KeyCode::SPACE, ' ', ' ', ' '),
+    (KeyCode::DELETE, '\x7F', '\x7F', NONE),
+    (KeyCode::KP0, '0', NONE, NONE),
+    (KeyCode::KP1, '1', NONE, NONE),
+    (KeyCode::KP2, '2', NONE, NONE),
+    (KeyCode::KP3, '3', NONE, NONE),
+    (KeyCode::KP4, '4', NONE, NONE),
+    (KeyCode::KP5, '5', NONE, NONE),
+    (KeyCode::KP6, '6', NONE, NONE),
+    (KeyCode::KP7, '7', NONE, NONE),
+    (KeyCode::KP8, '8', NONE, NONE),
+    (KeyCode::KP9, '9', NONE, NONE),
+    (KeyCode::KP_ENTER, '\n', '\n', NONE),
+];
+
+/// Keypad operators, which ignore num lock.
+const KEYPAD_OPERATORS: [(KeyCode, char, char, char); 4] = [
+    (KeyCode::KP_SLASH, '/', '/', NONE),
+    (KeyCode::KP_ASTERISK, '*', '*', NONE),
+    (KeyCode::KP_MINUS, '-', '-', NONE),
+    (KeyCode::KP_PLUS, '+', '+', NONE),
+];
+
+/// Maps each [`KeyCode`] to the character it types on its own, with shift,
+/// and with AltGr.
+pub struct Keymap {
+    pub name: &'static str,
+    map: [[char; 3]; KeyCode::COUNT],
+}
+
+impl Keymap {
+    const fn new(name: &'static str, kp_dot: char, entries: &[(KeyCode, char, char, char)]) -> Self {
+        let mut map = [[NONE; 3]; KeyCode::COUNT];
+
+        let mut i = 0;
+        while i < COMMON.len() {
+            let (code, base, shift, altgr) = COMMON[i];
+            map[code as usize] = [base, shift, altgr];
+            i += 1;
+        }
+
+        let mut i = 0;
+        while i < KEYPAD_OPERATORS.len() {
+            let (code, base, shift, altgr) = KEYPAD_OPERATORS[i];
+            map[code as usize] = [base, shift, altgr];
+            i += 1;
+        }
+
+        map[KeyCode::KP_DOT as usize] = [kp_dot, NONE, NONE];
+
+        let mut i = 0;
+        while i < entries.len() {
+            let (code, base, shift, altgr) = entries[i];
+            map[code as usize] = [base, shift, altgr];
+            i += 1;
+        }
+
+        Self { name, map }
+    }
+
+    /// Returns the character [`event`] types, if any. Ctrl combined with a
+    /// letter or one of `@[\]^_` yields the matching C0 control character.
+    pub fn translate(&self, event: &KeyEvent) -> Option<char> {
+        if event.state == KeyState::RELEASED {
+            return None;
+        }
+
+        let m = event.modifiers;
+        if event.code.is_keypad_navigation() && !m.contains(Modifiers::NUM_LOCK) {
+            return None;
+        }
+
+        let [base, shifted, altgr] = self.map[event.code as usize];
+        let shift = if m.contains(Modifiers::CAPS_LOCK) && base.is_alphabetic() {
+            !m.shift()
+        } else {
+            m.shift()
+        };
+
+        let c = match (m.altgr(), shift) {
+            (true, _) => altgr,
+            (false, true) => shifted,
+            (false, false) => base,
+        };
+
+        if c == NONE {
+            return None;
+        }
+
+        if m.ctrl() {
+            let upper = c.to_ascii_uppercase();
+            if ('@'..='_').contains(&upper) {
+                return Some((upper as u8 & 0x1F) as char);
+            }
+        }
+
+        Some(c)
+    }
+
+    /// Finds a key that types [`c`] without AltGr, and whether it needs
+    /// shift. Main-block keys win over the keypad.
+    pub fn find(&self, c: char) -> Option<(KeyCode, bool)> {
+        if c == NONE {
+            return None;
+        }
+
+        self.map.iter().enumerate().find_map(|(i, &[base, shifted, _])| {
+            let code = KeyCode::from_usize(i)?;
+            if base == c {
+                Some((code, false))
+            } else if shifted == c {
+                Some((code, true))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+pub static US: Keymap = Keymap::new("us", '.', &[
+    (KeyCode::GRAVE, '`', '~', NONE),
+    (KeyCode::N1, '1', '!', NONE),
+    (KeyCode::N2, '2', '@', NONE),
+    (KeyCode::N3, '3', '#', NONE),
+    (KeyCode::N4, '4', '$', NONE),
+    (KeyCode::N5, '5', '%', NONE),
+    (KeyCode::N6, '6', '^', NONE),
+    (KeyCode::N7, '7', '&', NONE),
+    (KeyCode::N8, '8', '*', NONE),
+    (KeyCode::N9, '9', '(', NONE),
+    (KeyCode::N0, '0', ')', NONE),
+    (KeyCode::MINUS, '-', '_', NONE),
+    (KeyCode::EQUAL, '=', '+', NONE),
+    (KeyCode::Q, 'q', 'Q', NONE),
+    (KeyCode::W, 'w', 'W', NONE),
+    (KeyCode::E, 'e', 'E', NONE),
+    (KeyCode::R, 'r', 'R', NONE),
+    (KeyCode::T, 't', 'T', NONE),
+    (KeyCode::Y, 'y', 'Y', NONE),
+    (KeyCode::U, 'u', 'U', NONE),
+    (KeyCode::I, 'i', 'I', NONE),
+    (KeyCode::O, 'o', 'O', NONE),
+    (KeyCode::P, 'p', 'P', NONE),
+    (KeyCode::LEFT_BRACE, '[', '{', NONE),
+    (KeyCode::RIGHT_BRACE, ']', '}', NONE),
+    (KeyCode::BACKSLASH, '\\', '|', NONE),
+    (KeyCode::A, 'a', 'A', NONE),
+    (KeyCode::S, 's', 'S', NONE),
+    (KeyCode::D, 'd', 'D', NONE),
+    (KeyCode::F, 'f', 'F', NONE),
+    (KeyCode::G, 'g', 'G', NONE),
+    (KeyCode::H, 'h', 'H', NONE),
+    (KeyCode::J, 'j', 'J', NONE),
+    (KeyCode::K, 'k', 'K', NONE),
+    (KeyCode::L, 'l', 'L', NONE),
+    (KeyCode::SEMICOLON, ';', ':', NONE),
+    (KeyCode::APOSTROPHE, '\'', '"', NONE),
+    (KeyCode::KEY_102ND, '\\', '|', NONE),
+    (KeyCode::Z, 'z', 'Z', NONE),
+    (KeyCode::X, 'x', 'X', NONE),
+    (KeyCode::C, 'c', 'C', NONE),
+    (KeyCode::V, 'v', 'V', NONE),
+    (KeyCode::B, 'b', 'B', NONE),
+    (KeyCode::N, 'n', 'N', NONE),
+    (KeyCode::M, 'm', 'M', NONE),
+    (KeyCode::COMMA, ',', '<', NONE),
+    (KeyCode::DOT, '.', '>', NONE),
+    (KeyCode::SLASH, '/', '?', NONE),
+]);
+
+pub static UK: Keymap = Keymap::new("uk", '.', &[
+    (KeyCode::GRAVE, '`', '¬', '¦'),
+    (KeyCode::N1, '1', '!', NONE),
+    (KeyCode::N2, '2', '"', NONE),
+    (KeyCode::N3, '3', '£', NONE),
+    (KeyCode::N4, '4', '$', '€'),
+    (KeyCode::N5, '5', '%', NONE),
+    (KeyCode::N6, '6', '^', NONE),
+    (KeyCode::N7, '7', '&', NONE),
+    (KeyCode::N8, '8', '*', NONE),
+    (KeyCode::N9, '9', '(', NONE),
+    (KeyCode::N0, '0', ')', NONE),
+    (KeyCode::MINUS, '-', '_', NONE),
+    (KeyCode::EQUAL, '=', '+', NONE),
+    (KeyCode::Q, 'q', 'Q', NONE),
+    (KeyCode::W, 'w', 'W', NONE),
+    (KeyCode::E, 'e', 'E', 'é'),
+    (KeyCode::R, 'r', 'R', NONE),
+    (KeyCode::T, 't', 'T', NONE),
+    (KeyCode::Y, 'y', 'Y', NONE),
+    (KeyCode::U, 'u', 'U', 'ú'),
+    (KeyCode::I, 'i', 'I', 'í'),
+    (KeyCode::O, 'o', 'O', 'ó'),
+    (KeyCode::P, 'p', 'P', NONE),
+    (KeyCode::LEFT_BRACE, '[', '{', NONE),
+    (KeyCode::RIGHT_BRACE, ']', '}', NONE),
+    (KeyCode::BACKSLASH, '#', '~', NONE),
+    (KeyCode::A, 'a', 'A', 'á'),
+    (KeyCode::S, 's', 'S', NONE),
+    (KeyCode::D, 'd', 'D', NONE),
+    (KeyCode::F, 'f', 'F', NONE),
+    (KeyCode::G, 'g', 'G', NONE),
+    (KeyCode::H, 'h', 'H', NONE),
+    (KeyCode::J, 'j', 'J', NONE),
+    (KeyCode::K, 'k', 'K', NONE),
+    (KeyCode::L, 'l', 'L', NONE),
+    (KeyCode::SEMICOLON, ';', ':', NONE),
+    (KeyCode::APOSTROPHE, '\'', '@', NONE),
+    (KeyCode::KEY_102ND, '\\', '|', NONE),
+    (KeyCode::Z, 'z', 'Z', NONE),
+    (KeyCode::X, 'x', 'X', NONE),
+    (KeyCode::C, 'c', 'C', NONE),
+    (KeyCode::V, 'v', 'V', NONE),
+    (KeyCode::B, 'b', 'B', NONE),
+    (KeyCode::N, 'n', 'N', NONE),
+    (KeyCode::M, 'm', 'M', NONE),
+    (KeyCode::COMMA, ',', '<', NONE),
+    (KeyCode::DOT, '.', '>', NONE),
+    (KeyCode::SLASH, '/', '?', NONE),
+]);
+
+/// German QWERTZ. The dead keys (`^` and `´`) type their accent directly,
+/// since there is no compose support yet.
+pub static DE: Keymap = Keymap::new("de", ',', &[
+    (KeyCode::GRAVE, '^', '°', NONE),
+    (KeyCode::N1, '1', '!', NONE),
+    (KeyCode::N2, '2', '"', '²'),
+    (KeyCode::N3, '3', '§', '³'),
+    (KeyCode::N4, '4', '$', NONE),
+    (KeyCode::N5, '5', '%', NONE),
+    (KeyCode::N6, '6', '&', NONE),
+    (KeyCode::N7, '7', '/', '{'),
+    (KeyCode::N8, '8', '(', '['),
+    (KeyCode::N9, '9', ')', ']'),
+    (KeyCode::N0, '0', '=', '}'),
+    (KeyCode::MINUS, 'ß', '?', '\\'),
+    (KeyCode::EQUAL, '´', '`', NONE),
+    (KeyCode::Q, 'q', 'Q', '@'),
+    (KeyCode::W, 'w', 'W', NONE),
+    (KeyCode::E, 'e', 'E', '€'),
+    (KeyCode::R, 'r', 'R', NONE),
+    (KeyCode::T, 't', 'T', NONE),
+    (KeyCode::Y, 'z', 'Z', NONE),
+    (KeyCode::U, 'u', 'U', NONE),
+    (KeyCode::I, 'i', 'I', NONE),
+    (KeyCode::O, 'o', 'O', NONE),
+    (KeyCode::P, 'p', 'P', NONE),
+    (KeyCode::LEFT_BRACE, 'ü', 'Ü', NONE),
+    (KeyCode::RIGHT_BRACE, '+', '*', '~'),
+    (KeyCode::BACKSLASH, '#', '\'', NONE),
+    (KeyCode::A, 'a', 'A', NONE),
+    (KeyCode::S, 's', 'S', NONE),
+    (KeyCode::D, 'd', 'D', NONE),
+    (KeyCode::F, 'f', 'F', NONE),
+    (KeyCode::G, 'g', 'G', NONE),
+    (KeyCode::H, 'h', 'H', NONE),
+    (KeyCode::J, 'j', 'J', NONE),
+    (KeyCode::K, 'k', 'K', NONE),
+    (KeyCode::L, 'l', 'L', NONE),
+    (KeyCode::SEMICOLON, 'ö', 'Ö', NONE),
+    (KeyCode::APOSTROPHE, 'ä', 'Ä', NONE),
+    (KeyCode::KEY_102ND, '<', '>', '|'),
+    (KeyCode::Z, 'y', 'Y', NONE),
+    (KeyCode::X, 'x', 'X', NONE),
+    (KeyCode::C, 'c', 'C', NONE),
+    (KeyCode::V, 'v', 'V', NONE),
+    (KeyCode::B, 'b', 'B', NONE),
+    (KeyCode::N, 'n', 'N', NONE),
+    (KeyCode::M, 'm', 'M', 'µ'),
+    (KeyCode::COMMA, ',', ';', NONE),
+    (KeyCode::DOT, '.', ':', NONE),
+    (KeyCode::SLASH, '-', '_', NONE),
+]);
+
+pub static DVORAK: Keymap = Keymap::new("dvorak", '.', &[
+    (KeyCode::GRAVE, '`', '~', NONE),
+    (KeyCode::N1, '1', '!', NONE),
+    (KeyCode::N2, '2', '@', NONE),
+    (KeyCode::N3, '3', '#', NONE),
+    (KeyCode::N4, '4', '$', NONE),
+    (KeyCode::N5, '5', '%', NONE),
+    (KeyCode::N6, '6', '^', NONE),
+    (KeyCode::N7, '7', '&', NONE),
+    (KeyCode::N8, '8', '*', NONE),
+    (KeyCode::N9, '9', '(', NONE),
+    (KeyCode::N0, '0', ')', NONE),
+    (KeyCode::MINUS, '[', '{', NONE),
+    (KeyCode::EQUAL, ']', '}', NONE),
+    (KeyCode::Q, '\'', '"', NONE),
+    (KeyCode::W, ',', '<', NONE),
+    (KeyCode::E, '.', '>', NONE),
+    (KeyCode::R, 'p', 'P', NONE),
+    (KeyCode::T, 'y', 'Y', NONE),
+    (KeyCode::Y, 'f', 'F', NONE),
+    (KeyCode::U, 'g', 'G', NONE),
+    (KeyCode::I, 'c', 'C', NONE),
+    (KeyCode::O, 'r', 'R', NONE),
+    (KeyCode::P, 'l', 'L', NONE),
+    (KeyCode::LEFT_BRACE, '/', '?', NONE),
+    (KeyCode::RIGHT_BRACE, '=', '+', NONE),
+    (KeyCode::BACKSLASH, '\\', '|', NONE),
+    (KeyCode::A, 'a', 'A', NONE),
+    (KeyCode::S, 'o', 'O', NONE),
+    (KeyCode::D, 'e', 'E', NONE),
+    (KeyCode::F, 'u', 'U', NONE),
+    (KeyCode::G, 'i', 'I', NONE),
+    (KeyCode::H, 'd', 'D', NONE),
+    (KeyCode::J, 'h', 'H', NONE),
+    (KeyCode::K, 't', 'T', NONE),
+    (KeyCode::L, 'n', 'N', NONE),
+    (KeyCode::SEMICOLON, 's', 'S', NONE),
+    (KeyCode::APOSTROPHE, '-', '_', NONE),
+    (KeyCode::KEY_102ND, '\\', '|', NONE),
+    (KeyCode::Z, ';', ':', NONE),
+    (KeyCode::X, 'q', 'Q', NONE),
+    (KeyCode::C, 'j', 'J', NONE),
+    (KeyCode::V, 'k', 'K', NONE),
+    (KeyCode::B, 'x', 'X', NONE),
+    (KeyCode::N, 'b', 'B', NONE),
+    (KeyCode::M, 'm', 'M', NONE),
+    (KeyCode::COMMA, 'w', 'W', NONE),
+    (KeyCode::DOT, 'v', 'V', NONE),
+    (KeyCode::SLASH, 'z', 'Z', NONE),
+]);
+
+pub static KEYMAPS: [&Keymap; 4] = [&US, &UK, &DE, &DVORAK];
+
+/// Finds a layout by its [`Keymap::name`], as given by the `keymap=`
+/// command line option.
+pub fn by_name(name: &str) -> Option<&'static Keymap> {
+    KEYMAPS.iter().copied().find(|k| k.name == name)
+}
@@ -1,28 +1,47 @@
 #![no_std]
 #![no_main]
 #![feature(never_type)]
+#![feature(abi_x86_interrupt)]
 #![allow(non_camel_case_types)]
+// boot.s switches to at&t syntax and back for what intel syntax can't express
+#![allow(bad_asm_style)]
 
+mod acpi;
 mod arch;
+mod clock;
+mod cmdline;
 #[macro_use]
 mod common;
+mod input;
+mod keyboard;
+mod memory;
+mod mouse;
 mod multiboot2;
+mod ringbuf;
+mod sched;
+mod sync;
+mod tty;
 
 use core::{
-    arch::{asm, global_asm}, hint::black_box, panic::PanicInfo, ptr::addr_of
+    arch::{asm, global_asm}, fmt::Write, hint::black_box, panic::PanicInfo, ptr::addr_of, str, time::Duration
 };
 
-use arch::x86::{gdt::{Gdt, Gdtr64}, pages::{
+use arch::x86::{apic, cpu, exceptions, fpu, gdt::{Gdt, Gdtr64, Tss}, idt::Idt, interrupts, ipi, paging::{self, AddressSpace, PagingLevels}, pat, percpu, pages::{
     PageDirectoryPointerTable4k, PageDirectoryTable4k, PageTable, Pdpte4k, Pdte4k, Pml4Table4k,
     Pml4te4k, Pml5Table4k, Pml5te4k,
-}, serial, vga::{self, VgaColor, VgaWriter}};
-use multiboot2::{Multiboot2Header, Multiboot2Info, Multiboot2InfoHeader, Multiboot2InfoIter, MULTIBOOT2_LOAD_MAGIC};
-use common::LinkerSymbol;
+}, pic::{self, Pic8259}, pit, registers::{self, Cr0, Cr4, Efer}, ps2::{self, keyboard as ps2_keyboard, mouse as ps2_mouse, Ps2Port}, serial, smp, vga::{self, VgaColor}};
+use multiboot2::{
+    Multiboot2Header, Multiboot2Info, Multiboot2InfoHeader, Multiboot2InfoIter, Multiboot2InfoTagType,
+    Multiboot2MemoryMapEntry, MULTIBOOT2_LOAD_MAGIC,
+};
+use input::{InputFilter, InputReader};
+use keyboard::keymap;
+use memory::{frame, kaslr::{self, Layout}, stack::KernelStack, KERNEL_END, KERNEL_START};
+use sync::mutex::Mutex;
+use tty::LineDiscipline;
 
-unsafe extern "C" {
-    static KERNEL_START: LinkerSymbol;
-    static KERNEL_END: LinkerSymbol;
-}
+/// What's been typed at the console and how it's echoed.
+static CONSOLE: Mutex<LineDiscipline> = Mutex::new(LineDiscipline::new());
 
 fn kernel_size() -> usize {
     addr_of!(KERNEL_END) as usize - addr_of!(KERNEL_START) as usize
@@ -57,6 +76,9 @@ static mut GDTR: Gdtr64 = Gdtr64 {
     offset: 0,
 };
 
+static mut IDT: Idt = Idt::new();
+static mut TSS: Tss = Tss::new();
+
 #[used]
 #[unsafe(no_mangle)]
 static mut INIT_PML5T: Pml5Table4k = Pml5Table4k([Pml5te4k::from_bits(0); 512]);
@@ -67,9 +89,18 @@ static mut INIT_PML4T: Pml4Table4k = Pml4Table4k([Pml4te4k::from_bits(0); 512]);
 #[unsafe(no_mangle)]
 static mut INIT_PDPT: PageDirectoryPointerTable4k =
     PageDirectoryPointerTable4k([Pdpte4k::from_bits(0); 512]);
+/// Maps the kernel's 2 GiB at the top of the address space.
+#[used]
+#[unsafe(no_mangle)]
+static mut INIT_KERNEL_PDPT: PageDirectoryPointerTable4k =
+    PageDirectoryPointerTable4k([Pdpte4k::from_bits(0); 512]);
+/// Maps the 1 GiB the kernel is linked in to wherever it was loaded.
+#[used]
+#[unsafe(no_mangle)]
+static mut INIT_KERNEL_PDT: PageDirectoryTable4k = PageDirectoryTable4k([Pdte4k::from_bits(0); 512]);
 #[used]
 #[unsafe(no_mangle)]
-static mut INIT_PDT: PageDirectoryTable4k = PageDirectoryTable4k([Pdte4k::from_bits(0); 512]);
+static mut INIT_PDT: PageDirectoryTable4k = PageDirectoryTable4k::identity();
 #[used]
 #[unsafe(no_mangle)]
 static mut INIT_PT: PageTable = PageTable::identity();
@@ -80,78 +111,278 @@ global_asm!(
     KERNEL_DATA_SELECTOR = const Gdt::KERNEL_DATA_SELECTOR,
     PAGE_PRESENT         = const 0x00000001,
     PAGE_RW              = const 0x00000002,
-    CR0_PG               = const 0x80000000u32 as i32,
-    CR4_PAE              = const 0x00000020,
-    EFER                 = const 0xC0000080u32 as i32,
-    EFER_LME             = const 0x00000100,
+    PAGE_PS              = const 0x00000080,
+    CR0_PG               = const Cr0::new().with_pg(true).into_bits() as u32 as i32,
+    CR4_PAE              = const Cr4::new().with_pae(true).into_bits() as i32,
+    CR4_LA57             = const Cr4::new().with_la57(true).into_bits() as i32,
+    CPUID_7_ECX_LA57     = const 0x00010000,
+    EFER                 = const registers::IA32_EFER.msr().index() as i32,
+    EFER_LME             = const Efer::new().with_lme(true).into_bits() as i32,
     INIT_STACK_SIZE      = const InitStack::SIZE,
     GDTR_OFFSET          = const Gdtr64::GDTR_OFFSET,
+    MULTIBOOT2_TAG_LOAD_BASE = const Multiboot2InfoTagType::IMAGE_LOAD_BASE_PADDR as u32,
+    KERNEL_VIRT_OFFSET   = const memory::KERNEL_VIRT_OFFSET as i64,
+    KERNEL_LINK_PADDR    = const memory::KERNEL_LINK_PADDR,
+    KERNEL_PML5_INDEX    = const paging::index(memory::KERNEL_VIRT_OFFSET, 5),
+    KERNEL_PML4_INDEX    = const paging::index(memory::KERNEL_VIRT_OFFSET, 4),
+    KERNEL_PDPT_INDEX    = const paging::index(memory::KERNEL_VIRT_OFFSET, 3),
+    PHYS_MAP_PML4_INDEX  = const paging::index(memory::PHYS_MAP_BASE, 4),
 );
 
+fn find_memory_map(info: *const Multiboot2InfoHeader) -> &'static [Multiboot2MemoryMapEntry] {
+    Multiboot2InfoIter::new(info)
+        .find_map(|tag| match tag {
+            Multiboot2Info::MemoryMap(entries) => Some(entries),
+            _ => None,
+        })
+        .unwrap()
+}
+
+/// Called by boot.s before [`kernel_main`], still where the image is
+/// linked, to move it somewhere random unless `nokaslr` is given. Returns
+/// how far it moved, for boot.s to carry on there.
+#[unsafe(no_mangle)]
+extern "C" fn kernel_relocate(multiboot2_info_paddr: u64) -> u64 {
+    let multiboot2_info = memory::phys_to_virt::<Multiboot2InfoHeader>(multiboot2_info_paddr);
+
+    // both keep what they need in statics, which the image shares with its
+    // moved copy
+    unsafe { cpu::init() };
+    let cmdline = Multiboot2InfoIter::new(multiboot2_info)
+        .find_map(|tag| match tag {
+            Multiboot2Info::CommandLine(s) => Some(s),
+            _ => None,
+        })
+        .unwrap_or("");
+    unsafe { cmdline::init(cmdline) };
+
+    if cmdline::has_flag("nokaslr") {
+        return 0;
+    }
+    let pdt = &raw mut INIT_KERNEL_PDT;
+    unsafe { kaslr::relocate_image(&mut *pdt) }
+}
+
 #[unsafe(no_mangle)]
-extern "C" fn kernel_main(magic: u32, multiboot2_info: *mut Multiboot2InfoHeader) -> ! {
+extern "C" fn kernel_main(magic: u32, multiboot2_info_paddr: u64) -> ! {
     black_box(&raw const MULTIBOOT2_HEADER);
     black_box(&raw const INIT_STACK);
     black_box(&raw const INIT_PML5T);
     black_box(&raw const INIT_PML4T);
     black_box(&raw const INIT_PDPT);
+    black_box(&raw const INIT_KERNEL_PDPT);
+    black_box(&raw const INIT_KERNEL_PDT);
     black_box(&raw const INIT_PDT);
     black_box(&raw const INIT_PT);
     black_box(&raw const GDT);
     black_box(&raw const GDTR);
 
     assert!(magic == MULTIBOOT2_LOAD_MAGIC);
-    assert!(!multiboot2_info.is_null());
-    assert!(kernel_size() <= 2 * 1024 * 1024);
+    assert!(multiboot2_info_paddr != 0);
+    let multiboot2_info = memory::phys_to_virt::<Multiboot2InfoHeader>(multiboot2_info_paddr);
+
+    serial::com1().lock().init().unwrap();
+    unsafe { percpu::init_boot_cpu(&raw mut TSS) };
+
+    let load_paddr = Multiboot2InfoIter::new(multiboot2_info)
+        .find_map(|tag| match tag {
+            Multiboot2Info::ImageLoadBase(paddr) => Some(paddr as u64),
+            _ => None,
+        })
+        .unwrap_or(memory::KERNEL_LINK_PADDR);
+    unsafe { memory::set_kernel_load_paddr(load_paddr) };
+
+    // prefer the ACPI 2.0 copy when there are both
+    let rsdp = Multiboot2InfoIter::new(multiboot2_info)
+        .filter_map(|tag| match tag {
+            Multiboot2Info::AcpiRsdp(rsdp) => Some(rsdp),
+            _ => None,
+        })
+        .max_by_key(|rsdp| rsdp.revision);
+    if let Some(rsdp) = rsdp {
+        let _ = unsafe { acpi::init(rsdp) };
+    }
+
+    let memory_map = find_memory_map(multiboot2_info);
+    let kernel_paddr = memory::kernel_virt_to_phys(addr_of!(KERNEL_START) as u64);
+    let info_size = unsafe { (*multiboot2_info).total_size } as u64;
+    let reserved = [
+        kernel_paddr..kernel_paddr + kernel_size() as u64,
+        multiboot2_info_paddr..multiboot2_info_paddr + info_size,
+    ];
+
+    // the new page tables have to come from memory the boot page tables
+    // already map, and NX has to be on before they're used
+    unsafe { frame::add(memory_map, &reserved, 0..memory::BOOT_MAPPED_END) };
+    unsafe { smp::reserve_trampoline(memory_map, &reserved) };
+    paging::enable_protection();
+    pat::init();
+    fpu::init();
+    let layout = if cmdline::has_flag("nokaslr") { Layout::FIXED } else { Layout::randomized() };
+    let mut kernel_space = AddressSpace::new(PagingLevels::current()).unwrap();
+    memory::init_direct_map(&mut kernel_space, memory_map, layout.phys_map_base).unwrap();
+    memory::map_kernel_image(&mut kernel_space).unwrap();
+    kernel_space.populate_higher_half().unwrap();
+    unsafe {
+        kernel_space.activate();
+        kaslr::set_layout(layout);
+    }
+
+    // the boot direct map is gone, so the memory map has to be found again
+    let multiboot2_info = memory::phys_to_virt::<Multiboot2InfoHeader>(multiboot2_info_paddr);
+    let memory_map = find_memory_map(multiboot2_info);
+    unsafe { frame::add(memory_map, &reserved, memory::BOOT_MAPPED_END..memory::PHYS_MAP_MAX) };
+    unsafe { memory::set_kernel_space(kernel_space) };
+
+    // INIT_STACK has nothing below it to catch an overflow
+    let stack = KernelStack::new("kernel_main").unwrap();
+    unsafe { stack.switch_to(kernel_init) }
+}
+
+/// The rest of [`kernel_main`], on a stack with guard pages.
+extern "C" fn kernel_init() -> ! {
+    {
+        let mut vga = vga::writer().lock_irq();
+        vga.clear(VgaColor::BLACK);
+        vga.enable_cursor();
+    }
+
+    let (idt_ptr, tss_ptr, gdt_ptr) = (&raw mut IDT, &raw mut TSS, &raw mut GDT);
+    let idt = unsafe { &mut *idt_ptr };
+    exceptions::init(idt, unsafe { &mut *tss_ptr }).unwrap();
+    unsafe { (*gdt_ptr).load_tss(&*tss_ptr) };
+
+    let pic = unsafe { pic::pic() };
+    pic.init();
 
-    let com1 = unsafe { serial::com1() };
-    com1.init().unwrap();
+    idt.set_handler(Pic8259::vector(Pic8259::IRQ_TIMER), pit::irq_handler);
+    idt.set_handler(Pic8259::vector(Pic8259::IRQ_KEYBOARD), ps2_keyboard::irq_handler);
+    idt.set_handler(Pic8259::vector(Pic8259::IRQ_COM1), serial::com1_irq_handler);
+    idt.set_handler(Pic8259::vector(Pic8259::IRQ_MOUSE), ps2_mouse::irq_handler);
+    idt.set_handler(Pic8259::vector(Pic8259::IRQ_SPURIOUS_MASTER), interrupts::spurious_master_handler);
+    idt.set_handler(Pic8259::vector(Pic8259::IRQ_SPURIOUS_SLAVE), interrupts::spurious_slave_handler);
+    idt.set_handler(apic::SPURIOUS_VECTOR, apic::spurious_handler);
+    idt.set_handler(apic::TIMER_VECTOR, apic::timer_handler);
+    idt.set_handler(ipi::CALL_VECTOR, ipi::call_handler);
+    idt.set_handler(ipi::WAKE_VECTOR, ipi::wake_handler);
+    unsafe { (*idt_ptr).load() };
+    unsafe { apic::init() };
 
-    let mut vga = unsafe { VgaWriter::new() }.unwrap();
-    vga.clear(VgaColor::BLACK);
-    vga.enable_cursor();
+    unsafe { pit::pit() }.set_frequency(clock::TICK_HZ);
+    pic.unmask(Pic8259::IRQ_TIMER);
+    apic::calibrate_timer();
+    apic::start_timer();
 
-    // let iter = Multiboot2InfoIter::new(multiboot2_info);
-    // for tag in iter {
-    //     match tag {
-    //         Multiboot2Info::MemoryMap(entries) => {
+    serial::com1().lock_irq().enable_rx_interrupt();
+    pic.unmask(Pic8259::IRQ_COM1);
 
-    //         },
-    //         Multiboot2Info::Unimplemented(tag_type) => {
+    if let Some(name) = cmdline::value("keymap") {
+        match keymap::by_name(name) {
+            Some(keymap) => keyboard::set_keymap(keymap),
+            None => {
+                let _ = writeln!(serial::com1().lock_irq(), "unknown keymap {name}, keeping {}", keyboard::keymap().name);
+            },
+        }
+    }
+
+    let controller = unsafe { ps2::ps2() };
+    if let Ok(ports) = controller.init() {
+        if ports.first {
+            let _ = ps2_keyboard::init(&controller, Ps2Port::FIRST);
+        }
 
-    //         },
-    //     }
-    // }
+        if ports.second {
+            let _ = ps2_mouse::init(&controller, Ps2Port::SECOND);
+        }
+    }
+
+    if let Err(e) = smp::init(unsafe { &*idt_ptr }) {
+        let _ = writeln!(serial::com1().lock_irq(), "couldn't start the other CPUs: {e:?}");
+    }
+
+    {
+        let mut com1 = serial::com1().lock_irq();
+        let _ = write!(com1, "{}", cpu::info());
+        let _ = writeln!(com1, "{} CPUs online", smp::cpu_count());
+    }
+
+    sched::init().unwrap();
 
     let s = b"Hello, World!\nThis is a new line\n";
     for c in s.iter() {
-        com1.putc(*c);
-        vga.putc(*c, VgaColor::WHITE);
+        serial::com1().lock_irq().putc(*c);
+        vga::writer().lock_irq().putc(*c, VgaColor::WHITE);
     }
 
+    // the console runs in a thread of its own, and the boot thread just
+    // waits for it
+    let console = sched::spawn("console", console).unwrap();
+    console.join();
+    let _ = writeln!(serial::com1().lock_irq(), "the console exited");
+    sched::exit();
+}
+
+/// Reads lines from the keyboard and runs them: `ps`, `sleep` for some
+/// seconds, and `exit`.
+fn console() {
+    let mut out = (vga::writer(), serial::com1());
+    CONSOLE.lock().write("> ", &mut out);
+
+    let console_input = InputReader::open(InputFilter::KEY).unwrap();
     loop {
-        unsafe {
-            asm!(
-                "
-            cli
-            hlt
-        "
-            )
+        let Some(c) = console_input.read_wait().translate() else {
+            continue;
         };
+
+        let mut console = CONSOLE.lock();
+        console.receive(c, &mut out);
+        if console.take_signal().is_some() {
+            console.write("> ", &mut out);
+        }
+
+        let mut line = [0u8; 1024];
+        while let Some(n) = console.read(&mut line) {
+            if n == 0 {
+                console.write("\n", &mut out);
+            }
+
+            let mut words = str::from_utf8(&line[..n]).unwrap_or("").split_ascii_whitespace();
+            match (words.next(), words.next()) {
+                (Some("ps"), None) => {
+                    let _ = sched::stats::dump(&mut console.writer(&mut out));
+                },
+                (Some("sleep"), Some(secs)) => {
+                    if let Ok(secs) = secs.parse() {
+                        sched::sleep(Duration::from_secs(secs));
+                    }
+                },
+                (Some("exit"), None) => return,
+                _ => {},
+            }
+
+            console.write("> ", &mut out);
+        }
     }
 }
 
 #[panic_handler]
-fn panic_handler(_info: &PanicInfo) -> ! {
+fn panic_handler(info: &PanicInfo) -> ! {
+    interrupts::disable();
+
+    // whatever panicked might be holding either lock, in which case the VGA
+    // is skipped and COM1 is written to anyway
     let s = b"Panicked!\n";
-    let com1 = unsafe { serial::com1() };
+    let mut vga = vga::writer().try_lock();
+    let mut com1 = unsafe { serial::COM1::new() };
     for c in s.iter() {
-        if let Some(mut vga) = unsafe { VgaWriter::new() } {
+        if let Some(vga) = vga.as_mut() {
             vga.putc(*c, VgaColor::RED);
         }
         com1.putc(*c);
     }
 
+    let _ = writeln!(com1, "{info}");
+
     loop {
         unsafe {
             asm!(
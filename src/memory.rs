@@ -0,0 +1,161 @@
+//! Physical memory and the kernel's view of it.
+
+use core::ptr::addr_of;
+
+use crate::{
+    arch::x86::paging::{AddressSpace, MapError, PageFlags},
+    common::LinkerSymbol,
+    memory::vma::VmSpace,
+    multiboot2::{Multiboot2MemoryMapEntry, MULTIBOOT2_MEMORY_ACPI_NVS, MULTIBOOT2_MEMORY_ACPI_RECLAIMABLE, MULTIBOOT2_MEMORY_AVAILABLE},
+    sync::spin::SpinLock,
+};
+
+pub mod frame;
+pub mod io;
+pub mod kaslr;
+pub mod stack;
+pub mod vma;
+
+unsafe extern "C" {
+    pub static KERNEL_START: LinkerSymbol;
+    static KERNEL_TEXT_START: LinkerSymbol;
+    static KERNEL_RODATA_START: LinkerSymbol;
+    static KERNEL_DATA_START: LinkerSymbol;
+    pub static KERNEL_END: LinkerSymbol;
+}
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Where the kernel image is linked, in the top 2 GiB so that code built
+/// for the kernel code model can reach it. The image sits at the same
+/// offset from here as it's linked at physically, wherever it's loaded,
+/// plus [`kaslr::image_slide`].
+pub const KERNEL_VIRT_OFFSET: u64 = 0xFFFF_FFFF_8000_0000;
+
+/// Physical address the image is linked at. Must match linker.ld. The
+/// bootloader may load it higher, 2 MiB aligned.
+pub const KERNEL_LINK_PADDR: u64 = 0x20_0000;
+
+/// All physical RAM is mapped here while booting, and somewhere in the
+/// [`PHYS_MAP_SLIDE`] bytes after it once [`kaslr`] has picked a spot.
+pub const PHYS_MAP_BASE: u64 = 0xFFFF_8000_0000_0000;
+pub const PHYS_MAP_SLIDE: u64 = 16 << 40;
+/// How much physical memory the direct map can cover.
+pub const PHYS_MAP_MAX: u64 = 64 << 40;
+
+/// Physical memory the boot page tables already map at [`PHYS_MAP_BASE`].
+pub const BOOT_MAPPED_END: u64 = 1 << 30;
+
+/// The first 1 MiB is full of firmware structures and legacy hardware
+/// rather than RAM.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// How much higher the image was loaded than [`KERNEL_LINK_PADDR`].
+static mut KERNEL_LOAD_DELTA: u64 = 0;
+
+/// Safety: must be called once, before [`kernel_virt_to_phys`] is used,
+/// with where the bootloader loaded the image.
+pub unsafe fn set_kernel_load_paddr(paddr: u64) {
+    unsafe { KERNEL_LOAD_DELTA = paddr - KERNEL_LINK_PADDR };
+}
+
+static KERNEL_SPACE: SpinLock<Option<VmSpace>> = SpinLock::new(None);
+
+/// Safety: must be called once, with the address space the kernel is
+/// running in.
+pub unsafe fn set_kernel_space(space: AddressSpace) {
+    *KERNEL_SPACE.lock_irq() = Some(VmSpace::new(space));
+}
+
+/// Runs [`f`] on the kernel's address space, locked and with interrupts
+/// disabled.
+pub fn with_kernel_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+    with_kernel_vm(|vm| f(&mut vm.page_tables))
+}
+
+/// Like [`with_kernel_space`], but with the kernel's areas too.
+pub fn with_kernel_vm<R>(f: impl FnOnce(&mut VmSpace) -> R) -> R {
+    f(KERNEL_SPACE.lock_irq().as_mut().unwrap())
+}
+
+/// Returns a pointer through which the kernel can access [`paddr`].
+pub fn phys_to_virt<T>(paddr: u64) -> *mut T {
+    debug_assert!(paddr < PHYS_MAP_MAX);
+    (kaslr::layout().phys_map_base + paddr) as *mut T
+}
+
+/// Like [`phys_to_virt`], but only if the direct map covers all [`len`]
+/// bytes at [`paddr`], as it does for RAM and the ACPI areas in the memory
+/// map.
+pub fn phys_to_virt_mapped<T>(paddr: u64, len: u64) -> Option<*mut T> {
+    if paddr.checked_add(len)? > PHYS_MAP_MAX {
+        return None;
+    }
+
+    let start = paddr & !(PAGE_SIZE - 1);
+    let end = (paddr + len).max(paddr + 1);
+    let covered = with_kernel_space(|space| {
+        (start..end).step_by(PAGE_SIZE as usize).all(|page| space.translate(phys_to_virt::<u8>(page) as u64).is_some())
+    });
+
+    covered.then(|| phys_to_virt(paddr))
+}
+
+/// Inverse of [`phys_to_virt`].
+pub fn virt_to_phys(vaddr: u64) -> u64 {
+    let base = kaslr::layout().phys_map_base;
+    debug_assert!(vaddr >= base && vaddr < base + PHYS_MAP_MAX);
+    vaddr - base
+}
+
+/// Physical address of something in the kernel image.
+pub fn kernel_virt_to_phys(vaddr: u64) -> u64 {
+    debug_assert!(vaddr >= KERNEL_VIRT_OFFSET);
+    vaddr - KERNEL_VIRT_OFFSET - kaslr::image_slide() + unsafe { KERNEL_LOAD_DELTA }
+}
+
+/// Maps the first 1 MiB, RAM, and the ACPI tables in [`entries`] at
+/// [`base`]. Partial pages at the ends of an entry are left out.
+pub fn init_direct_map(space: &mut AddressSpace, entries: &[Multiboot2MemoryMapEntry], base: u64) -> Result<(), MapError> {
+    let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+    space.map_range(base, 0, LOW_MEMORY_END, flags)?;
+
+    for entry in entries {
+        // the entries are packed, so copy the fields out
+        let (entry_base, length, type_) = (entry.base_paddr, entry.length, entry.type_);
+        if !matches!(type_, MULTIBOOT2_MEMORY_AVAILABLE | MULTIBOOT2_MEMORY_ACPI_RECLAIMABLE | MULTIBOOT2_MEMORY_ACPI_NVS) {
+            continue;
+        }
+
+        let start = entry_base.max(LOW_MEMORY_END).next_multiple_of(PAGE_SIZE);
+        let end = entry_base.saturating_add(length).min(PHYS_MAP_MAX) & !(PAGE_SIZE - 1);
+        if start >= end {
+            continue;
+        }
+
+        space.map_range(base + start, start, end - start, flags)?;
+    }
+
+    Ok(())
+}
+
+/// Maps the kernel image where it runs, with `.text` executable,
+/// `.rodata` read-only, and everything after it writable.
+pub fn map_kernel_image(space: &mut AddressSpace) -> Result<(), MapError> {
+    let text = addr_of!(KERNEL_TEXT_START) as u64;
+    let rodata = addr_of!(KERNEL_RODATA_START) as u64;
+    let data = addr_of!(KERNEL_DATA_START) as u64;
+    let end = (addr_of!(KERNEL_END) as u64).next_multiple_of(PAGE_SIZE);
+
+    let sections = [
+        (text, rodata, PageFlags::empty()),
+        (rodata, data, PageFlags::NO_EXECUTE),
+        (data, end, PageFlags::WRITABLE | PageFlags::NO_EXECUTE),
+    ];
+
+    for (start, end, flags) in sections {
+        space.map_range(start, kernel_virt_to_phys(start), end - start, flags)?;
+    }
+
+    Ok(())
+}
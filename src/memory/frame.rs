@@ -0,0 +1,211 @@
+//! Allocator for 4 KiB physical page frames, fed from the bootloader's
+//! memory map. Frames are reference counted so that they can be shared
+//! between mappings, as copy-on-write pages are.
+
+use core::ops::Range;
+
+use crate::{
+    multiboot2::{Multiboot2MemoryMapEntry, MULTIBOOT2_MEMORY_AVAILABLE},
+    sync::spin::SpinLock,
+};
+
+use super::{phys_to_virt, LOW_MEMORY_END, PAGE_SIZE};
+
+const MAX_REGIONS: usize = 32;
+
+#[derive(Copy, Clone)]
+struct Region {
+    /// First frame handed out from the region.
+    base: u64,
+    /// Next frame that has never been handed out.
+    next: u64,
+    end: u64,
+    /// Physical address of the reference counts of the frames from
+    /// [`Region::base`] on, which take up the frames before it.
+    counts: u64,
+}
+
+impl Region {
+    fn count(&self, frame: u64) -> Option<*mut u16> {
+        if !(self.base..self.end).contains(&frame) {
+            return None;
+        }
+
+        Some(unsafe { phys_to_virt::<u16>(self.counts).add(((frame - self.base) / PAGE_SIZE) as usize) })
+    }
+}
+
+pub struct FrameAllocator {
+    regions: [Region; MAX_REGIONS],
+    region_count: usize,
+    /// Freed frames, linked through their first 8 bytes. 0 ends the list,
+    /// which is fine since frame 0 is never handed out.
+    free_list: u64,
+    free_frames: u64,
+    total_frames: u64,
+}
+
+impl FrameAllocator {
+    pub const fn new() -> Self {
+        Self {
+            regions: [Region { base: 0, next: 0, end: 0, counts: 0 }; MAX_REGIONS],
+            region_count: 0,
+            free_list: 0,
+            free_frames: 0,
+            total_frames: 0,
+        }
+    }
+
+    /// Adds the parts of the available regions in [`entries`] that lie in
+    /// [`range`], minus anything overlapping [`reserved`].
+    pub fn add(&mut self, entries: &[Multiboot2MemoryMapEntry], reserved: &[Range<u64>], range: Range<u64>) {
+        for entry in entries {
+            // the entries are packed, so copy the fields out
+            let (base, length, type_) = (entry.base_paddr, entry.length, entry.type_);
+            if type_ != MULTIBOOT2_MEMORY_AVAILABLE {
+                continue;
+            }
+
+            // low memory is also the only place real-mode code can run from,
+            // so it's kept out of the allocator
+            let start = base.max(range.start).max(LOW_MEMORY_END);
+            let end = base.saturating_add(length).min(range.end);
+            self.add_region(start, end, reserved);
+        }
+    }
+
+    fn add_region(&mut self, start: u64, end: u64, reserved: &[Range<u64>]) {
+        let start = start.next_multiple_of(PAGE_SIZE);
+        let end = end & !(PAGE_SIZE - 1);
+        if start >= end {
+            return;
+        }
+
+        if let Some((r, rest)) = reserved.split_first() {
+            if r.start < end && start < r.end {
+                self.add_region(start, r.start, rest);
+                self.add_region(r.end, end, rest);
+            } else {
+                self.add_region(start, end, rest);
+            }
+
+            return;
+        }
+
+        // anything past the last slot is lost, but the map is rarely that fragmented
+        if self.region_count == MAX_REGIONS {
+            return;
+        }
+
+        // 2 bytes of count per frame, with a little waste for the frames
+        // the counts themselves take up
+        let count_bytes = (end - start) / PAGE_SIZE * size_of::<u16>() as u64;
+        let base = start + count_bytes.next_multiple_of(PAGE_SIZE);
+        if base >= end {
+            return;
+        }
+
+        unsafe { core::ptr::write_bytes(phys_to_virt::<u8>(start), 0, (base - start) as usize) };
+        self.regions[self.region_count] = Region { base, next: base, end, counts: start };
+        self.region_count += 1;
+
+        let frames = (end - base) / PAGE_SIZE;
+        self.free_frames += frames;
+        self.total_frames += frames;
+    }
+
+    fn count(&self, frame: u64) -> *mut u16 {
+        self.regions[..self.region_count]
+            .iter()
+            .find_map(|r| r.count(frame))
+            .expect("frame didn't come from the frame allocator")
+    }
+
+    /// Returns the physical address of a free frame, with one reference. Its
+    /// contents are undefined.
+    pub fn alloc(&mut self) -> Option<u64> {
+        let frame = if self.free_list != 0 {
+            let frame = self.free_list;
+            self.free_list = unsafe { *phys_to_virt::<u64>(frame) };
+            frame
+        } else {
+            let region = self.regions[..self.region_count].iter_mut().find(|r| r.next < r.end)?;
+            let frame = region.next;
+            region.next += PAGE_SIZE;
+            frame
+        };
+
+        self.free_frames -= 1;
+        unsafe { *self.count(frame) = 1 };
+        Some(frame)
+    }
+
+    /// Adds a reference to [`frame`], which must be allocated.
+    pub fn share(&mut self, frame: u64) {
+        let count = unsafe { &mut *self.count(frame) };
+        assert!(*count != 0, "sharing free frame {frame:#x}");
+        *count = count.checked_add(1).expect("too many references to a frame");
+    }
+
+    /// Number of references to [`frame`], 0 if it's free.
+    pub fn ref_count(&self, frame: u64) -> u16 {
+        unsafe { *self.count(frame) }
+    }
+
+    /// Drops a reference to [`frame`], freeing it if it was the last one.
+    ///
+    /// Safety: [`frame`] must have come from [`FrameAllocator::alloc`], and
+    /// the reference being dropped must not be used after this.
+    pub unsafe fn free(&mut self, frame: u64) {
+        debug_assert!(frame.is_multiple_of(PAGE_SIZE) && frame != 0);
+        let count = unsafe { &mut *self.count(frame) };
+        assert!(*count != 0, "double free of frame {frame:#x}");
+        *count -= 1;
+        if *count != 0 {
+            return;
+        }
+
+        unsafe { *phys_to_virt::<u64>(frame) = self.free_list };
+        self.free_list = frame;
+        self.free_frames += 1;
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+}
+
+static FRAMES: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::new());
+
+/// Safety: [`range`] must be mapped at [`super::PHYS_MAP_BASE`], and must
+/// not overlap anything added before.
+pub unsafe fn add(entries: &[Multiboot2MemoryMapEntry], reserved: &[Range<u64>], range: Range<u64>) {
+    FRAMES.lock_irq().add(entries, reserved, range);
+}
+
+pub fn alloc() -> Option<u64> {
+    FRAMES.lock_irq().alloc()
+}
+
+/// Safety: see [`FrameAllocator::free`].
+pub unsafe fn free(frame: u64) {
+    unsafe { FRAMES.lock_irq().free(frame) };
+}
+
+pub fn share(frame: u64) {
+    FRAMES.lock_irq().share(frame);
+}
+
+pub fn ref_count(frame: u64) -> u16 {
+    FRAMES.lock_irq().ref_count(frame)
+}
+
+/// Returns the number of free frames and the total number of frames.
+pub fn stats() -> (u64, u64) {
+    let frames = FRAMES.lock_irq();
+    (frames.free_frames(), frames.total_frames())
+}
@@ -0,0 +1,86 @@
+//! Mapping device memory, which isn't RAM and so isn't in the direct map.
+
+use crate::{
+    arch::x86::{pages::PageSize, paging::{MapError, PageFlags}, pat::CacheMode},
+    sync::spin::SpinLock,
+};
+
+use super::{kaslr, with_kernel_space, PAGE_SIZE};
+
+/// Virtual memory for device mappings, between the direct map and the
+/// kernel stacks. The area starts somewhere in the first
+/// [`IO_REMAP_SLIDE`] bytes.
+pub const IO_REMAP_BASE: u64 = 0xFFFF_D000_0000_0000;
+pub const IO_REMAP_SLIDE: u64 = 8 << 40;
+const IO_REMAP_SIZE: u64 = 8 << 40;
+
+/// Offset of the next free address into the area. Addresses aren't reused
+/// after [`IoMapping`]s are dropped, but there's far more of them than there
+/// is device memory.
+static NEXT: SpinLock<u64> = SpinLock::new(0);
+
+/// A mapping of device memory, unmapped on drop.
+pub struct IoMapping {
+    /// Page-aligned start of the mapping.
+    vaddr: u64,
+    /// Offset of the requested address into the first page.
+    offset: u64,
+    len: u64,
+}
+
+impl IoMapping {
+    pub fn as_ptr<T>(&self) -> *mut T {
+        (self.vaddr + self.offset) as *mut T
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Keeps the mapping forever and returns its address.
+    pub fn leak<T>(self) -> *mut T {
+        let ptr = self.as_ptr();
+        core::mem::forget(self);
+        ptr
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        let len = (self.offset + self.len).next_multiple_of(PAGE_SIZE);
+        let _ = with_kernel_space(|space| space.unmap_range(self.vaddr, len));
+    }
+}
+
+/// Maps [`len`] bytes of device memory at [`paddr`] with [`mode`] caching,
+/// usually [`CacheMode::UNCACHED`] for registers and
+/// [`CacheMode::WRITE_COMBINING`] for framebuffers.
+pub fn ioremap(paddr: u64, len: u64, mode: CacheMode) -> Result<IoMapping, MapError> {
+    let offset = paddr % PAGE_SIZE;
+    let start = paddr - offset;
+    let size = (offset + len).next_multiple_of(PAGE_SIZE);
+
+    // keep the virtual address congruent to the physical one, so that big
+    // mappings can use huge pages
+    let align = match size {
+        s if s >= PageSize::SIZE_1G.bytes() => PageSize::SIZE_1G.bytes(),
+        s if s >= PageSize::SIZE_2M.bytes() => PageSize::SIZE_2M.bytes(),
+        _ => PAGE_SIZE,
+    };
+
+    let base = kaslr::layout().io_remap_base;
+    let vaddr = {
+        let mut next = NEXT.lock_irq();
+        let vaddr = (base + *next).next_multiple_of(align) + start % align;
+        if vaddr + size > base + IO_REMAP_SIZE {
+            return Err(MapError::OutOfVirtualMemory);
+        }
+
+        *next = vaddr + size - base;
+        vaddr
+    };
+
+    let flags = (PageFlags::WRITABLE | PageFlags::NO_EXECUTE).with_cache_mode(mode);
+    with_kernel_space(|space| space.map_range(vaddr, start, size, flags))?;
+    Ok(IoMapping { vaddr, offset, len })
+}
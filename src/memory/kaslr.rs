@@ -0,0 +1,135 @@
+//! Kernel address space layout randomization. The direct map, device
+//! mappings, kernel stacks and the vmalloc area each slide by a random
+//! number of 1 GiB steps within their part of the address space, so that
+//! where they are can't be known ahead of time.
+//!
+//! The kernel image slides too, by a random number of 2 MiB steps within
+//! the 1 GiB at [`KERNEL_VIRT_OFFSET`]. It's built position independent, so
+//! [`relocate_image`] only has to map it at the new address and patch the
+//! absolute addresses listed in its relocations before it runs there.
+
+use core::{
+    ptr::addr_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::x86::{
+        pages::PageDirectoryTable4k,
+        random,
+        registers::{RegisterRead, RegisterWrite, CR3},
+    },
+    common::LinkerSymbol,
+};
+
+use super::{
+    io::{IO_REMAP_BASE, IO_REMAP_SLIDE},
+    stack::{KERNEL_STACKS_BASE, KERNEL_STACKS_SLIDE},
+    vma::{VMALLOC_BASE, VMALLOC_SLIDE},
+    KERNEL_END, KERNEL_START, KERNEL_VIRT_OFFSET, PHYS_MAP_BASE, PHYS_MAP_SLIDE,
+};
+
+unsafe extern "C" {
+    static RELA_START: LinkerSymbol;
+    static RELA_END: LinkerSymbol;
+}
+
+/// Slides are multiples of this, so that huge pages still line up.
+const SLIDE_ALIGN: u64 = 1 << 30;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Layout {
+    pub phys_map_base: u64,
+    pub io_remap_base: u64,
+    pub stacks_base: u64,
+    pub vmalloc_base: u64,
+}
+
+impl Layout {
+    /// Everything at the bottom of its range, as with `nokaslr`.
+    pub const FIXED: Self = Self {
+        phys_map_base: PHYS_MAP_BASE,
+        io_remap_base: IO_REMAP_BASE,
+        stacks_base: KERNEL_STACKS_BASE,
+        vmalloc_base: VMALLOC_BASE,
+    };
+
+    pub fn randomized() -> Self {
+        let slide = |base: u64, max: u64| base + random::random_u64() % (max / SLIDE_ALIGN + 1) * SLIDE_ALIGN;
+        Self {
+            phys_map_base: slide(PHYS_MAP_BASE, PHYS_MAP_SLIDE),
+            io_remap_base: slide(IO_REMAP_BASE, IO_REMAP_SLIDE),
+            stacks_base: slide(KERNEL_STACKS_BASE, KERNEL_STACKS_SLIDE),
+            vmalloc_base: slide(VMALLOC_BASE, VMALLOC_SLIDE),
+        }
+    }
+}
+
+static mut LAYOUT: Layout = Layout::FIXED;
+
+pub fn layout() -> Layout {
+    unsafe { LAYOUT }
+}
+
+/// Safety: must be called once, right after switching to page tables with
+/// the direct map at [`Layout::phys_map_base`], and before anything is
+/// mapped in the other areas.
+pub unsafe fn set_layout(layout: Layout) {
+    unsafe { LAYOUT = layout };
+}
+
+/// The image moves in steps of this, the size of the pages boot.s maps it
+/// with.
+const IMAGE_ALIGN: u64 = 2 << 20;
+
+/// An entry in `.rela.dyn`.
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: u64,
+}
+
+/// The only kind a static position-independent executable has: the link
+/// address [`Rela::addend`], plus wherever the image moved to.
+const R_X86_64_RELATIVE: u64 = 8;
+
+static IMAGE_SLIDE: AtomicU64 = AtomicU64::new(0);
+
+/// How far above where it's linked the kernel image runs.
+pub fn image_slide() -> u64 {
+    IMAGE_SLIDE.load(Ordering::Relaxed)
+}
+
+/// Maps the image again in [`pdt`], the boot page directory for the 1 GiB
+/// at [`KERNEL_VIRT_OFFSET`], at a random 2 MiB-aligned address past where
+/// it's linked, and applies its relocations for running there. Returns
+/// how far it moved.
+///
+/// Safety: must be called once, running where the image is linked, before
+/// anything has kept a pointer into it that the relocations won't fix up.
+pub unsafe fn relocate_image(pdt: &mut PageDirectoryTable4k) -> u64 {
+    let start = addr_of!(KERNEL_START) as u64;
+    let end = addr_of!(KERNEL_END) as u64;
+    let first = ((start - KERNEL_VIRT_OFFSET) / IMAGE_ALIGN) as usize;
+    let count = (end - start).div_ceil(IMAGE_ALIGN) as usize;
+
+    // past the end of where it is now, so that the old mapping of the code
+    // doing this stays put
+    let choices = pdt.0.len() - first - 2 * count + 1;
+    let steps = count + (random::random_u64() % choices as u64) as usize;
+    pdt.0.copy_within(first..first + count, first + steps);
+    unsafe { CR3.write(CR3.read()) };
+
+    let slide = steps as u64 * IMAGE_ALIGN;
+    let mut rela = addr_of!(RELA_START) as *const Rela;
+    while rela < addr_of!(RELA_END) as *const Rela {
+        let Rela { offset, info, addend } = unsafe { rela.read() };
+        assert!(info & 0xFFFF_FFFF == R_X86_64_RELATIVE);
+        unsafe { (offset as *mut u64).write(addend + slide) };
+        rela = unsafe { rela.add(1) };
+    }
+
+    IMAGE_SLIDE.store(slide, Ordering::Relaxed);
+    slide
+}
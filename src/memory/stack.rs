@@ -0,0 +1,131 @@
+//! Kernel stacks. Each gets a slot of virtual memory with an unmapped guard
+//! page on either side, so that running off either end faults instead of
+//! corrupting whatever is next to it.
+
+use crate::{arch::x86::{paging::{MapError, PageFlags}, pages::PageSize}, sync::spin::SpinLock};
+
+use super::{frame, kaslr, with_kernel_space, PAGE_SIZE};
+
+pub const KERNEL_STACK_SIZE: u64 = 16 * 1024;
+
+/// Start of the virtual memory kernel stacks live in, clear of the direct
+/// map. The stacks start somewhere in the first [`KERNEL_STACKS_SLIDE`]
+/// bytes.
+pub const KERNEL_STACKS_BASE: u64 = 0xFFFF_E000_0000_0000;
+pub const KERNEL_STACKS_SLIDE: u64 = 8 << 40;
+const GUARD_SIZE: u64 = PAGE_SIZE;
+const SLOT_SIZE: u64 = GUARD_SIZE + KERNEL_STACK_SIZE + GUARD_SIZE;
+const MAX_STACKS: usize = 512;
+
+/// Owner of each slot, for reporting overflows.
+static SLOTS: SpinLock<[Option<&'static str>; MAX_STACKS]> = SpinLock::new([None; MAX_STACKS]);
+
+#[derive(Debug)]
+pub enum StackError {
+    NoFreeSlots,
+    Map(MapError),
+}
+
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Allocates and maps a stack. [`owner`] names whoever runs on it in
+    /// overflow reports.
+    pub fn new(owner: &'static str) -> Result<Self, StackError> {
+        let slot = {
+            let mut slots = SLOTS.lock_irq();
+            let slot = slots.iter().position(Option::is_none).ok_or(StackError::NoFreeSlots)?;
+            slots[slot] = Some(owner);
+            slot
+        };
+
+        let stack = Self { slot };
+        let mut mapped = 0;
+        while mapped < KERNEL_STACK_SIZE {
+            let Some(frame) = frame::alloc() else {
+                // dropping unmaps whatever was mapped so far
+                return Err(StackError::Map(MapError::OutOfFrames));
+            };
+
+            let vaddr = stack.bottom() + mapped;
+            let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+            if let Err(e) = with_kernel_space(|space| space.map(vaddr, frame, PageSize::SIZE_4K, flags)) {
+                unsafe { frame::free(frame) };
+                return Err(StackError::Map(e));
+            }
+
+            mapped += PAGE_SIZE;
+        }
+
+        Ok(stack)
+    }
+
+    pub fn bottom(&self) -> u64 {
+        kaslr::layout().stacks_base + self.slot as u64 * SLOT_SIZE + GUARD_SIZE
+    }
+
+    /// Initial stack pointer. Stacks grow down from here.
+    pub fn top(&self) -> u64 {
+        self.bottom() + KERNEL_STACK_SIZE
+    }
+
+    /// Keeps the stack mapped forever and returns its top.
+    pub fn leak(self) -> u64 {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+
+    /// Switches to this stack and calls [`f`] on it. The stack is leaked,
+    /// since there's no coming back.
+    ///
+    /// Safety: nothing on the old stack may be used after this.
+    pub unsafe fn switch_to(self, f: extern "C" fn() -> !) -> ! {
+        let top = self.leak();
+        unsafe {
+            core::arch::asm!(
+                "mov rsp, {top}",
+                "xor ebp, ebp",
+                "call {f}",
+                "ud2",
+                top = in(reg) top,
+                f = in(reg) f,
+                options(noreturn),
+            )
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut vaddr = self.bottom();
+        while vaddr < self.top() {
+            if let Ok(mapping) = with_kernel_space(|space| space.unmap(vaddr)) {
+                unsafe { frame::free(mapping.paddr) };
+            }
+
+            vaddr += PAGE_SIZE;
+        }
+
+        SLOTS.lock_irq()[self.slot] = None;
+    }
+}
+
+/// If [`vaddr`] is in one of the guard pages of a stack in use, returns the
+/// stack's owner.
+pub fn guard_page_owner(vaddr: u64) -> Option<&'static str> {
+    let base = kaslr::layout().stacks_base;
+    if vaddr < base || vaddr >= base + MAX_STACKS as u64 * SLOT_SIZE {
+        return None;
+    }
+
+    let slot = ((vaddr - base) / SLOT_SIZE) as usize;
+    let offset = (vaddr - base) % SLOT_SIZE;
+    if (GUARD_SIZE..GUARD_SIZE + KERNEL_STACK_SIZE).contains(&offset) {
+        return None;
+    }
+
+    SLOTS.lock_irq()[slot]
+}
@@ -0,0 +1,370 @@
+//! Virtual memory areas: ranges of an address space that have been handed
+//! out, and what should back them. Pages are only mapped when first
+//! touched, so reserving a large area costs nothing until it's used.
+
+use core::ptr::null;
+
+use crate::{
+    arch::x86::{
+        fpu,
+        pages::PageSize,
+        paging::{AddressSpace, MapError, Mapping, PageFlags, PagingLevels},
+        percpu,
+    },
+    sync::spin::SpinLock,
+};
+
+use super::{frame, kaslr, phys_to_virt, with_kernel_space, with_kernel_vm, PAGE_SIZE};
+
+/// Kernel areas handed out by [`vmalloc`] live here, starting somewhere in
+/// the first [`VMALLOC_SLIDE`] bytes.
+pub const VMALLOC_BASE: u64 = 0xFFFF_F000_0000_0000;
+pub const VMALLOC_SLIDE: u64 = 4 << 40;
+const VMALLOC_SIZE: u64 = 8 << 40;
+
+const MAX_VMAS: usize = 64;
+
+crate::per_cpu! {
+    /// The address space whose lower half this CPU is using, or null for the
+    /// kernel's, whose lower half is empty.
+    static ACTIVE: *const SpinLock<VmSpace> = null();
+}
+
+/// Something that can fill in the pages of a file-backed area.
+pub trait PageSource: Sync {
+    /// Fills [`buf`] with the page at [`offset`], which is page-aligned.
+    /// [`buf`] starts out zeroed. Returns [`false`] if reading failed.
+    fn read_page(&self, offset: u64, buf: &mut [u8]) -> bool;
+}
+
+#[derive(Copy, Clone)]
+pub enum Backing {
+    /// Fresh zeroed frames.
+    Anonymous,
+    /// Physical memory starting at [`paddr`], which isn't freed with the
+    /// area.
+    Physical { paddr: u64 },
+    /// Pages read from [`source`], starting at [`offset`] into it.
+    File { source: &'static dyn PageSource, offset: u64 },
+}
+
+impl Backing {
+    /// Whether the area owns the frames it maps.
+    fn owns_frames(&self) -> bool {
+        !matches!(self, Self::Physical { .. })
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, vaddr: u64) -> bool {
+        (self.start..self.end).contains(&vaddr)
+    }
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    Misaligned,
+    Overlap,
+    TooManyAreas,
+    NoSpace,
+    NotFound,
+    AccessViolation,
+    ReadFailed,
+    Map(MapError),
+}
+
+impl From<MapError> for VmError {
+    fn from(value: MapError) -> Self {
+        Self::Map(value)
+    }
+}
+
+/// What a faulting access was trying to do.
+#[derive(Copy, Clone, Debug)]
+pub struct Access {
+    /// The page was mapped, so this is a protection fault.
+    pub present: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub user: bool,
+}
+
+/// An address space along with the areas reserved in it.
+pub struct VmSpace {
+    pub page_tables: AddressSpace,
+    vmas: [Option<Vma>; MAX_VMAS],
+}
+
+impl VmSpace {
+    pub const fn new(page_tables: AddressSpace) -> Self {
+        Self { page_tables, vmas: [None; MAX_VMAS] }
+    }
+
+    pub fn find(&self, vaddr: u64) -> Option<&Vma> {
+        self.vmas.iter().flatten().find(|vma| vma.contains(vaddr))
+    }
+
+    /// Reserves [`len`] bytes at [`vaddr`]. Nothing is mapped until it's
+    /// touched.
+    pub fn reserve(&mut self, vaddr: u64, len: u64, flags: PageFlags, backing: Backing) -> Result<(), VmError> {
+        if !(vaddr | len).is_multiple_of(PAGE_SIZE) || len == 0 {
+            return Err(VmError::Misaligned);
+        }
+
+        if let Backing::Physical { paddr } = backing && paddr % PAGE_SIZE != 0 {
+            return Err(VmError::Misaligned);
+        }
+
+        let end = vaddr.checked_add(len).ok_or(VmError::NoSpace)?;
+        if self.vmas.iter().flatten().any(|vma| vma.start < end && vaddr < vma.end) {
+            return Err(VmError::Overlap);
+        }
+
+        let slot = self.vmas.iter_mut().find(|vma| vma.is_none()).ok_or(VmError::TooManyAreas)?;
+        *slot = Some(Vma { start: vaddr, end, flags, backing });
+        Ok(())
+    }
+
+    /// Reserves [`len`] bytes wherever they fit in [`range`] and returns
+    /// their address.
+    pub fn reserve_in(
+        &mut self,
+        range: core::ops::Range<u64>,
+        len: u64,
+        flags: PageFlags,
+        backing: Backing,
+    ) -> Result<u64, VmError> {
+        let mut candidate = range.start;
+        loop {
+            let end = candidate.checked_add(len).ok_or(VmError::NoSpace)?;
+            if end > range.end {
+                return Err(VmError::NoSpace);
+            }
+
+            // skip past whatever is in the way, if anything
+            match self.vmas.iter().flatten().filter(|vma| vma.start < end && candidate < vma.end).map(|vma| vma.end).max() {
+                Some(blocker_end) => candidate = blocker_end,
+                None => break,
+            }
+        }
+
+        self.reserve(candidate, len, flags, backing)?;
+        Ok(candidate)
+    }
+
+    /// Unmaps and forgets the area starting at [`vaddr`], freeing the frames
+    /// it owns.
+    pub fn release(&mut self, vaddr: u64) -> Result<(), VmError> {
+        let slot = self
+            .vmas
+            .iter_mut()
+            .find(|vma| vma.is_some_and(|vma| vma.start == vaddr))
+            .ok_or(VmError::NotFound)?;
+        let vma = slot.take().unwrap();
+
+        let mut page = vma.start;
+        while page < vma.end {
+            if let Ok(mapping) = self.page_tables.unmap(page)
+                && vma.backing.owns_frames()
+            {
+                unsafe { frame::free(mapping.paddr) };
+            }
+
+            page += PAGE_SIZE;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new address space with the same kernel mappings, and with
+    /// copies of the areas in the lower half. Pages already touched are
+    /// shared copy-on-write rather than copied.
+    pub fn fork(&mut self) -> Result<VmSpace, VmError> {
+        let levels = self.page_tables.levels();
+        let mut child = VmSpace::new(AddressSpace::new(levels)?);
+        child.page_tables.share_higher_half(&self.page_tables);
+
+        for i in 0..MAX_VMAS {
+            let Some(vma) = self.vmas[i].filter(|vma| vma.end <= levels.lower_half_end()) else {
+                continue;
+            };
+
+            let result = child
+                .reserve(vma.start, vma.end - vma.start, vma.flags, vma.backing)
+                .and_then(|()| self.share_pages(&mut child, &vma));
+            if let Err(e) = result {
+                while let Some(vma) = child.vmas.iter().flatten().next() {
+                    let _ = child.release(vma.start);
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(child)
+    }
+
+    /// Maps whatever is mapped of [`vma`] into [`other`] as well, marking
+    /// pages this space owns copy-on-write in both.
+    fn share_pages(&mut self, other: &mut VmSpace, vma: &Vma) -> Result<(), VmError> {
+        let mut page = vma.start;
+        while page < vma.end {
+            let Ok(mapping) = self.page_tables.translate_page(page) else {
+                page += PAGE_SIZE;
+                continue;
+            };
+
+            let mut flags = mapping.flags;
+            if vma.backing.owns_frames() {
+                if flags.contains(PageFlags::WRITABLE) {
+                    flags = (flags - PageFlags::WRITABLE) | PageFlags::COPY_ON_WRITE;
+                    self.page_tables.protect(page, flags)?;
+                }
+
+                frame::share(mapping.paddr);
+            }
+
+            if let Err(e) = other.page_tables.map(page, mapping.paddr, mapping.size, flags) {
+                if vma.backing.owns_frames() {
+                    unsafe { frame::free(mapping.paddr) };
+                }
+
+                return Err(e.into());
+            }
+
+            page += mapping.size.bytes();
+        }
+
+        Ok(())
+    }
+
+    /// Gives the copy-on-write page at [`mapping`] a frame of its own, or
+    /// takes over the frame if nothing else references it anymore.
+    fn break_cow(&mut self, mapping: Mapping) -> Result<(), VmError> {
+        let flags = (mapping.flags - PageFlags::COPY_ON_WRITE) | PageFlags::WRITABLE;
+        if frame::ref_count(mapping.paddr) == 1 {
+            self.page_tables.protect(mapping.vaddr, flags)?;
+            return Ok(());
+        }
+
+        let copy = frame::alloc().ok_or(VmError::Map(MapError::OutOfFrames))?;
+        let fpu = fpu::kernel_fpu_begin();
+        unsafe { fpu.copy(phys_to_virt::<u8>(copy), phys_to_virt::<u8>(mapping.paddr), PAGE_SIZE as usize) };
+        fpu::kernel_fpu_end(fpu);
+
+        self.page_tables.unmap(mapping.vaddr)?;
+        self.page_tables.map(mapping.vaddr, copy, PageSize::SIZE_4K, flags)?;
+        unsafe { frame::free(mapping.paddr) };
+        Ok(())
+    }
+
+    /// Resolves a fault at [`vaddr`], either by mapping in the page for a
+    /// not-present fault or by breaking copy-on-write for a write.
+    pub fn handle_fault(&mut self, vaddr: u64, access: Access) -> Result<(), VmError> {
+        let vma = *self.find(vaddr).ok_or(VmError::NotFound)?;
+        if access.write && !vma.flags.contains(PageFlags::WRITABLE)
+            || access.execute && vma.flags.contains(PageFlags::NO_EXECUTE)
+            || access.user && !vma.flags.contains(PageFlags::USER)
+        {
+            return Err(VmError::AccessViolation);
+        }
+
+        if access.present {
+            let mapping = self.page_tables.translate_page(vaddr)?;
+            if !access.write || !mapping.flags.contains(PageFlags::COPY_ON_WRITE) {
+                return Err(VmError::AccessViolation);
+            }
+
+            return self.break_cow(mapping);
+        }
+
+        let page = vaddr & !(PAGE_SIZE - 1);
+        let offset = page - vma.start;
+        let paddr = match vma.backing {
+            Backing::Physical { paddr } => paddr + offset,
+            Backing::Anonymous | Backing::File { .. } => {
+                let frame = frame::alloc().ok_or(VmError::Map(MapError::OutOfFrames))?;
+                let buf = unsafe { core::slice::from_raw_parts_mut(phys_to_virt::<u8>(frame), PAGE_SIZE as usize) };
+                buf.fill(0);
+
+                if let Backing::File { source, offset: file_offset } = vma.backing
+                    && !source.read_page(file_offset + offset, buf)
+                {
+                    unsafe { frame::free(frame) };
+                    return Err(VmError::ReadFailed);
+                }
+
+                frame
+            },
+        };
+
+        match self.page_tables.map(page, paddr, PageSize::SIZE_4K, vma.flags) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if vma.backing.owns_frames() {
+                    unsafe { frame::free(paddr) };
+                }
+
+                // someone else got there first
+                if matches!(e, MapError::AlreadyMapped) { Ok(()) } else { Err(e.into()) }
+            },
+        }
+    }
+}
+
+/// Reserves [`len`] bytes of zero-filled kernel memory, rounded up to whole
+/// pages. Frames are only allocated as the pages get touched.
+pub fn vmalloc(len: u64) -> Result<u64, VmError> {
+    let len = len.next_multiple_of(PAGE_SIZE);
+    let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+    let base = kaslr::layout().vmalloc_base;
+    with_kernel_vm(|vm| vm.reserve_in(base..base + VMALLOC_SIZE, len, flags, Backing::Anonymous))
+}
+
+/// Safety: [`vaddr`] must have come from [`vmalloc`] and must not be used
+/// after this.
+pub unsafe fn vfree(vaddr: u64) -> Result<(), VmError> {
+    with_kernel_vm(|vm| vm.release(vaddr))
+}
+
+/// Switches this CPU to [`space`], or back to the kernel's address space if
+/// it's null.
+///
+/// Safety: [`space`] must share the kernel's higher half, and must stay
+/// alive until something else is activated.
+pub unsafe fn activate(space: *const SpinLock<VmSpace>) {
+    unsafe {
+        match space.as_ref() {
+            Some(space) => space.lock_irq().page_tables.activate(),
+            None => with_kernel_space(|kernel| kernel.activate()),
+        }
+    }
+
+    ACTIVE.set(space);
+}
+
+/// The address space last [`activate`]d on this CPU, or null for the
+/// kernel's.
+pub fn active() -> *const SpinLock<VmSpace> {
+    if percpu::is_ready() { ACTIVE.get() } else { null() }
+}
+
+/// Resolves a fault at [`vaddr`] in the address space it belongs to: the
+/// active one for the lower half, and the kernel's, which every address
+/// space shares, for the higher half.
+pub fn handle_fault(vaddr: u64, access: Access) -> Result<(), VmError> {
+    let space = active();
+    if !space.is_null() && vaddr < PagingLevels::current().lower_half_end() {
+        return unsafe { &*space }.lock_irq().handle_fault(vaddr, access);
+    }
+
+    with_kernel_vm(|vm| vm.handle_fault(vaddr, access))
+}
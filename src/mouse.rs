@@ -0,0 +1,76 @@
+//! Hardware-independent mouse buttons.
+
+use bitflags::bitflags;
+
+use crate::input::{self, InputEventKind, InputSource, RelativeAxis};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    LEFT,
+    RIGHT,
+    MIDDLE,
+    /// Usually "back", on the side of the mouse.
+    SIDE,
+    /// Usually "forward", on the side of the mouse.
+    EXTRA,
+}
+
+impl MouseButton {
+    pub const ALL: [Self; 5] = [Self::LEFT, Self::RIGHT, Self::MIDDLE, Self::SIDE, Self::EXTRA];
+
+    pub fn flag(self) -> MouseButtons {
+        match self {
+            Self::LEFT => MouseButtons::LEFT,
+            Self::RIGHT => MouseButtons::RIGHT,
+            Self::MIDDLE => MouseButtons::MIDDLE,
+            Self::SIDE => MouseButtons::SIDE,
+            Self::EXTRA => MouseButtons::EXTRA,
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct MouseButtons: u8 {
+        const LEFT = 1 << 0;
+        const RIGHT = 1 << 1;
+        const MIDDLE = 1 << 2;
+        const SIDE = 1 << 3;
+        const EXTRA = 1 << 4;
+    }
+}
+
+/// Reports the events implied by one report from a mouse: motion, then
+/// wheel, then any buttons that changed since [`previous`]. Positive [`dy`]
+/// is down the screen and positive [`wheel`] is away from the user.
+pub fn report(dx: i16, dy: i16, wheel: i8, buttons: MouseButtons, previous: MouseButtons) {
+    let mut events = [InputEventKind::Sync; 3 + MouseButton::ALL.len()];
+    let mut n = 0;
+
+    if dx != 0 {
+        events[n] = InputEventKind::Relative { axis: RelativeAxis::X, value: dx as i32 };
+        n += 1;
+    }
+
+    if dy != 0 {
+        events[n] = InputEventKind::Relative { axis: RelativeAxis::Y, value: dy as i32 };
+        n += 1;
+    }
+
+    if wheel != 0 {
+        events[n] = InputEventKind::Relative { axis: RelativeAxis::WHEEL, value: wheel as i32 };
+        n += 1;
+    }
+
+    for button in MouseButton::ALL {
+        let flag = button.flag();
+        if buttons.contains(flag) != previous.contains(flag) {
+            events[n] = InputEventKind::Button { button, pressed: buttons.contains(flag) };
+            n += 1;
+        }
+    }
+
+    if n > 0 {
+        input::report(InputSource::MOUSE, &events[..n]);
+    }
+}
@@ -1,8 +1,10 @@
-use core::marker::PhantomData;
+use core::{ffi::CStr, marker::PhantomData};
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
+use crate::{acpi::Rsdp, memory};
+
 #[repr(C, packed)]
 pub struct Multiboot2Header {
     magic: Multiboot2Magic,
@@ -10,6 +12,7 @@ pub struct Multiboot2Header {
     header_length: u32,
     checksum: u32,
     //framebuffer_tag: Multiboot2FramebufferTag,
+    relocatable_tag: Multiboot2RelocatableTag,
     final_tag: Multiboot2FinalTag,
 }
 
@@ -21,6 +24,7 @@ impl Multiboot2Header {
             header_length: size_of!(Multiboot2Header) as u32,
             checksum: (-((Multiboot2Magic::new().0 as u32 + Multiboot2Arch::PROTECTED_MODE as u32 + size_of!(Multiboot2Header) as u32) as i32)) as u32,
             //framebuffer_tag: Multiboot2FramebufferTag::new(),
+            relocatable_tag: Multiboot2RelocatableTag::new(),
             final_tag: Multiboot2FinalTag::new(),
         }
     }
@@ -30,6 +34,11 @@ const MULTIBOOT2_HEADER_MAGIC: u32 = 0xE85250D6;
 
 pub const MULTIBOOT2_LOAD_MAGIC: u32 = 0x36D76289;
 
+/// Memory map entry type for RAM that's free to use.
+pub const MULTIBOOT2_MEMORY_AVAILABLE: u32 = 1;
+pub const MULTIBOOT2_MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+pub const MULTIBOOT2_MEMORY_ACPI_NVS: u32 = 4;
+
 #[repr(transparent)]
 struct Multiboot2Magic(u32);
 impl Multiboot2Magic {
@@ -47,6 +56,7 @@ enum Multiboot2Arch {
 enum Multiboot2TagType {
     FINAL = 0,
     FRAMEBUFFER = 5,
+    RELOCATABLE = 10,
 }
 
 #[repr(C, packed)]
@@ -77,6 +87,30 @@ impl Multiboot2FramebufferTag {
     }
 }
 
+/// Lets the bootloader load the image anywhere 2 MiB aligned in the memory
+/// the boot page tables map, rather than only where it's linked.
+#[repr(C, packed)]
+struct Multiboot2RelocatableTag {
+    base: Multiboot2TagBase,
+    min_addr: u32,
+    max_addr: u32,
+    align: u32,
+    preference: u32,
+}
+
+impl Multiboot2RelocatableTag {
+    const fn new() -> Self {
+        Self {
+            base: Multiboot2TagBase { type_: Multiboot2TagType::RELOCATABLE, flags: 0, size: size_of!(Self) as u32 },
+            min_addr: memory::KERNEL_LINK_PADDR as u32,
+            max_addr: memory::BOOT_MAPPED_END as u32,
+            align: 0x20_0000,
+            // no preference
+            preference: 0,
+        }
+    }
+}
+
 #[repr(C, packed)]
 struct Multiboot2FinalTag {
     base: Multiboot2TagBase,
@@ -129,6 +163,11 @@ pub enum Multiboot2InfoTagType {
 }
 
 pub enum Multiboot2Info<'a> {
+    /// A copy of the RSDP, from either the ACPI 1.0 or 2.0 tag.
+    AcpiRsdp(&'a Rsdp),
+    CommandLine(&'a str),
+    /// Physical address the image was loaded at.
+    ImageLoadBase(u32),
     MemoryMap(&'a [Multiboot2MemoryMapEntry]),
     Unimplemented(Multiboot2InfoTagType),
 }
@@ -140,6 +179,12 @@ struct Multiboot2MemoryMapTag {
     entry_version: u32,
 }
 
+#[repr(C, packed)]
+struct Multiboot2ImageLoadBaseTag {
+    base: Multiboot2InfoTag,
+    load_base_paddr: u32,
+}
+
 #[repr(C, packed)]
 pub struct Multiboot2MemoryMapEntry {
     pub base_paddr: u64,
@@ -164,6 +209,17 @@ impl<'a> Iterator for Multiboot2InfoIter<'a> {
         }
 
         let item = match Multiboot2InfoTagType::from_u32(unsafe {(*self.current_ptr).type_})? {
+            Multiboot2InfoTagType::ACPI_1_0_RSDP | Multiboot2InfoTagType::ACPI_2_0_RSDP => {
+                Multiboot2Info::AcpiRsdp(unsafe { &*self.current_ptr.add(1).cast::<Rsdp>() })
+            },
+            Multiboot2InfoTagType::BOOT_CMDLINE => {
+                let s = unsafe { CStr::from_ptr(self.current_ptr.add(1).cast()) };
+                Multiboot2Info::CommandLine(s.to_str().unwrap_or(""))
+            },
+            Multiboot2InfoTagType::IMAGE_LOAD_BASE_PADDR => {
+                let tag = self.current_ptr.cast::<Multiboot2ImageLoadBaseTag>();
+                Multiboot2Info::ImageLoadBase(unsafe { (*tag).load_base_paddr })
+            },
             Multiboot2InfoTagType::MEMORY_MAP => {
                 let tag = self.current_ptr.cast::<Multiboot2MemoryMapTag>();
                 let slice = unsafe {
@@ -0,0 +1,1142 @@
+//! Kernel threads and the scheduler that runs them.
+//!
+//! Every CPU has a run queue of its own, and a thread belongs to one CPU's
+//! queue at a time. [`Policy::Fifo`] threads go first, highest priority
+//! first, and keep the CPU until they block or yield or a higher priority
+//! one is ready. [`Policy::Fair`] threads share what's left in proportion
+//! to their weight: the one with the lowest vruntime, its run time scaled
+//! by its weight, goes next, and the timer takes the CPU back once it's
+//! got far enough ahead of the others. With nothing ready, a CPU switches
+//! to its idle thread, which halts until an interrupt.
+//!
+//! A thread only moves between CPUs by being taken off one queue, with that
+//! queue locked, and put on another after it's unlocked, so no two run
+//! queue locks are ever held at once. In between it's
+//! [`ThreadState::MIGRATING`] and belongs to whoever took it off.
+//! [`balance`] moves threads from busy CPUs to quiet ones, within each
+//! thread's affinity mask.
+//!
+//! Before a CPU starts running threads it's a task of its own: blocking
+//! halts it until it's woken, by a [`ipi::WAKE_VECTOR`] if the waker is on
+//! another CPU.
+
+use core::{
+    hint::spin_loop,
+    ptr::{null, null_mut},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::x86::{
+        context,
+        fpu::FpuState,
+        interrupts, ipi, percpu,
+        smp::{self, CpuMask, MAX_CPUS},
+    },
+    clock,
+    memory::{stack::{KernelStack, StackError}, vma::{self, VmError, VmSpace}},
+    sync::{completion::Completion, spin::{LockGuard, RawSpinLock, SpinLock}, IrqSafe},
+};
+
+pub mod balance;
+pub mod fair;
+pub mod stats;
+
+use stats::{Counters, ThreadStats};
+
+/// Priorities a [`Policy::Fifo`] thread can have, from 0 up.
+pub const FIFO_PRIORITIES: usize = 32;
+const TICK: Duration = Duration::from_nanos(1_000_000_000 / clock::TICK_HZ as u64);
+
+crate::per_cpu! {
+    /// The thread running on this CPU, or null if it doesn't run threads.
+    static CURRENT: *mut Thread = null_mut();
+    /// The thread last switched away from, for the next one to deal with
+    /// if it exited or has to move.
+    static PREVIOUS: *mut Thread = null_mut();
+    /// Preemption is off while this isn't 0, e.g. while holding a spinlock.
+    static PREEMPT_COUNT: u32 = 0;
+    /// The ordered locks the task holds, one bit per
+    /// [`sync::LockLevel`](crate::sync::LockLevel).
+    static HELD_LEVELS: u64 = 0;
+}
+
+/// How a thread is scheduled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Shares the CPU with the other fair threads, more of it the lower
+    /// [`nice`] is, from [`fair::MIN_NICE`] to [`fair::MAX_NICE`].
+    Fair { nice: i8 },
+    /// Runs ahead of every fair thread and of FIFO threads of lower
+    /// [`priority`], below [`FIFO_PRIORITIES`], until it blocks or yields.
+    Fifo { priority: u8 },
+}
+
+impl Policy {
+    pub const DEFAULT: Self = Self::Fair { nice: 0 };
+
+    fn clamped(self) -> Self {
+        match self {
+            Self::Fair { nice } => Self::Fair { nice: nice.clamp(fair::MIN_NICE, fair::MAX_NICE) },
+            Self::Fifo { priority } => Self::Fifo { priority: priority.min(FIFO_PRIORITIES as u8 - 1) },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ThreadState {
+    READY,
+    RUNNING,
+    BLOCKED,
+    /// On no run queue, being moved to one by whoever took it off the last.
+    MIGRATING,
+    /// Exited, and only waiting for whoever's holding it to let go.
+    DEAD,
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    Stack(StackError),
+    Vm(VmError),
+    Affinity(AffinityError),
+}
+
+#[derive(Debug)]
+pub enum AffinityError {
+    /// None of the CPUs in the mask are running threads yet.
+    NoCpus,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Everything about a thread, in memory of its own from [`vma::vmalloc`].
+/// Its scheduling state is covered by the lock of the run queue it's on.
+pub struct Thread {
+    id: u64,
+    name: &'static str,
+    policy: Policy,
+    state: ThreadState,
+    entry: fn(),
+    /// The CPU whose run queue it's on, which only changes while it's
+    /// [`ThreadState::MIGRATING`], with the new queue locked.
+    cpu: AtomicUsize,
+    /// The [`CpuMask`] it's allowed to run on.
+    affinity: AtomicU64,
+    /// Where [`context::switch_stacks`] left the stack while it isn't
+    /// running.
+    rsp: u64,
+    /// [`None`] for threads made from whatever was already running on a
+    /// CPU, whose stacks belong to whoever set them up.
+    stack: Option<KernelStack>,
+    fpu: FpuState,
+    /// The address space it was using when it was last switched out, as
+    /// [`vma::active`] has it.
+    vm_space: *const SpinLock<VmSpace>,
+    /// This CPU's [`percpu::INTERRUPT_DEPTH`] and [`HELD_LEVELS`], which
+    /// belong to the thread while it isn't running.
+    interrupt_depth: u32,
+    held_levels: u64,
+    /// When a blocked thread gives up waiting.
+    deadline: Option<Duration>,
+    /// For fair threads, in nanoseconds. Relative to the old run queue's
+    /// minimum while it's [`ThreadState::MIGRATING`].
+    vruntime: u64,
+    /// When it last became ready, for [`Counters::wait_time`].
+    ready_since: Duration,
+    counters: Counters,
+    /// Next in whichever [`ThreadList`] it's on.
+    next: *mut Thread,
+    /// Next in [`stats`]' list of every thread.
+    all_next: *mut Thread,
+    exited: Completion,
+    /// One for the thread while it hasn't exited, one for its
+    /// [`JoinHandle`]. The last to let go frees it.
+    refs: AtomicU32,
+}
+
+impl Thread {
+    fn alloc(
+        name: &'static str,
+        policy: Policy,
+        affinity: CpuMask,
+        stack: Option<KernelStack>,
+        entry: fn(),
+    ) -> Result<*mut Thread, SpawnError> {
+        let thread = vma::vmalloc(size_of::<Thread>() as u64).map_err(SpawnError::Vm)? as *mut Thread;
+        // writing touches every page, so switching to it never faults
+        unsafe {
+            thread.write(Thread {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                name,
+                policy: policy.clamped(),
+                state: ThreadState::MIGRATING,
+                entry,
+                cpu: AtomicUsize::new(smp::current_cpu()),
+                affinity: AtomicU64::new(affinity.0),
+                rsp: 0,
+                stack,
+                fpu: FpuState::new(),
+                vm_space: null(),
+                interrupt_depth: 0,
+                held_levels: 0,
+                deadline: None,
+                vruntime: 0,
+                ready_since: Duration::ZERO,
+                counters: Counters::new(),
+                next: null_mut(),
+                all_next: null_mut(),
+                exited: Completion::new(),
+                refs: AtomicU32::new(1),
+            })
+        };
+
+        stats::register(thread);
+        Ok(thread)
+    }
+
+    /// Allocates a thread with a stack of its own, set up to start in
+    /// [`thread_main`] the first time it's switched to.
+    fn new(name: &'static str, policy: Policy, affinity: CpuMask, entry: fn()) -> Result<*mut Thread, SpawnError> {
+        let stack = KernelStack::new(name).map_err(SpawnError::Stack)?;
+        let top = stack.top();
+        let thread = Self::alloc(name, policy, affinity, Some(stack), entry)?;
+        unsafe { (*thread).rsp = context::new_stack_frame(top, thread_main, thread as u64) };
+        Ok(thread)
+    }
+
+    fn affinity(&self) -> CpuMask {
+        CpuMask(self.affinity.load(Ordering::Relaxed))
+    }
+}
+
+/// Lets go of one of [`thread`]'s references, freeing it along with its
+/// stack if it was the last.
+///
+/// Safety: the caller must hold a reference, and [`thread`] must not be
+/// running.
+unsafe fn release(thread: *mut Thread) {
+    unsafe {
+        if (*thread).refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            stats::unregister(thread);
+            thread.drop_in_place();
+            vma::vfree(thread as u64).unwrap();
+        }
+    }
+}
+
+/// Threads linked through [`Thread::next`].
+struct ThreadList {
+    head: *mut Thread,
+    tail: *mut Thread,
+}
+
+impl ThreadList {
+    const fn new() -> Self {
+        Self { head: null_mut(), tail: null_mut() }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    fn push_back(&mut self, thread: *mut Thread) {
+        unsafe {
+            (*thread).next = null_mut();
+            if self.tail.is_null() {
+                self.head = thread;
+            } else {
+                (*self.tail).next = thread;
+            }
+        }
+
+        self.tail = thread;
+    }
+
+    fn push_front(&mut self, thread: *mut Thread) {
+        unsafe { (*thread).next = self.head };
+        if self.tail.is_null() {
+            self.tail = thread;
+        }
+
+        self.head = thread;
+    }
+
+    fn pop_front(&mut self) -> Option<*mut Thread> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let head = self.head;
+        self.head = unsafe { (*head).next };
+        if self.head.is_null() {
+            self.tail = null_mut();
+        }
+
+        Some(head)
+    }
+
+    fn remove(&mut self, thread: *mut Thread) {
+        let mut prev: *mut Thread = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() {
+            let next = unsafe { (*cur).next };
+            if cur == thread {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    unsafe { (*prev).next = next };
+                }
+
+                if self.tail == cur {
+                    self.tail = prev;
+                }
+
+                return;
+            }
+
+            prev = cur;
+            cur = next;
+        }
+    }
+
+    /// Steps to the next thread before yielding each one, so the caller
+    /// can take the one it's given off the list.
+    fn iter(&self) -> impl Iterator<Item = *mut Thread> + use<> {
+        let mut cur = self.head;
+        core::iter::from_fn(move || {
+            let thread = cur;
+            if thread.is_null() {
+                return None;
+            }
+
+            cur = unsafe { (*thread).next };
+            Some(thread)
+        })
+    }
+}
+
+struct RunQueue {
+    /// The CPU it belongs to, set once it starts running threads.
+    cpu: usize,
+    /// Ready FIFO threads, by priority.
+    fifo: [ThreadList; FIFO_PRIORITIES],
+    /// Ready fair threads, in no particular order.
+    fair: ThreadList,
+    /// Threads in [`RunQueue::fifo`] and [`RunQueue::fair`].
+    queued: usize,
+    /// Only ever goes up. Fair threads arriving are placed relative to it,
+    /// so one that's been away doesn't get the CPU to itself catching up.
+    min_vruntime: u64,
+    /// Blocked threads with a deadline, in no particular order.
+    sleeping: ThreadList,
+    running: *mut Thread,
+    idle: *mut Thread,
+    /// The running thread should make way at the next chance.
+    need_resched: bool,
+    ticks: u64,
+}
+
+/// The queue only points at threads that are alive.
+unsafe impl Send for RunQueue {}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            cpu: 0,
+            fifo: [const { ThreadList::new() }; FIFO_PRIORITIES],
+            fair: ThreadList::new(),
+            queued: 0,
+            min_vruntime: 0,
+            sleeping: ThreadList::new(),
+            running: null_mut(),
+            idle: null_mut(),
+            need_resched: false,
+            ticks: 0,
+        }
+    }
+
+    fn publish_load(&self) {
+        let running = !self.running.is_null() && self.running != self.idle;
+        LOADS[self.cpu].store(self.queued + running as usize, Ordering::Relaxed);
+    }
+
+    fn list_for(&mut self, thread: *mut Thread) -> &mut ThreadList {
+        match unsafe { (*thread).policy } {
+            Policy::Fair { .. } => &mut self.fair,
+            Policy::Fifo { priority } => &mut self.fifo[priority as usize],
+        }
+    }
+
+    /// Queues [`thread`] to run. [`front`] puts a FIFO thread ahead of the
+    /// others of its priority.
+    fn enqueue(&mut self, thread: *mut Thread, front: bool) {
+        unsafe {
+            (*thread).state = ThreadState::READY;
+            (*thread).ready_since = clock::now();
+        }
+
+        let list = self.list_for(thread);
+        if front {
+            list.push_front(thread);
+        } else {
+            list.push_back(thread);
+        }
+
+        self.queued += 1;
+        self.publish_load();
+    }
+
+    fn dequeue(&mut self, thread: *mut Thread) {
+        self.list_for(thread).remove(thread);
+        self.queued -= 1;
+        self.publish_load();
+    }
+
+    fn min_fair(&self) -> Option<*mut Thread> {
+        self.fair.iter().min_by_key(|&thread| unsafe { (*thread).vruntime })
+    }
+
+    /// Takes the thread that should run next off the queue.
+    fn pick_next(&mut self) -> Option<*mut Thread> {
+        let thread = match self.fifo.iter().rev().find_map(|list| (!list.is_empty()).then_some(list.head)) {
+            Some(thread) => thread,
+            None => self.min_fair()?,
+        };
+
+        self.dequeue(thread);
+        Some(thread)
+    }
+
+    /// A ready thread [`dest`] is allowed to run, for [`balance`] to move.
+    /// Fair threads go first, since moving one costs it the least.
+    fn find_movable(&self, dest: usize) -> Option<*mut Thread> {
+        let allowed = |&thread: &*mut Thread| unsafe { (*thread).affinity() }.contains(dest);
+        self.fair.iter().find(allowed).or_else(|| self.fifo.iter().find_map(|list| list.iter().find(allowed)))
+    }
+
+    fn update_min_vruntime(&mut self) {
+        let running = self.running;
+        let running = (!running.is_null() && running != self.idle && matches!(unsafe { (*running).policy }, Policy::Fair { .. }))
+            .then(|| unsafe { (*running).vruntime });
+        let queued = self.min_fair().map(|thread| unsafe { (*thread).vruntime });
+        let min = match (running, queued) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => match a.or(b) {
+                Some(min) => min,
+                None => return,
+            },
+        };
+
+        self.min_vruntime = self.min_vruntime.max(min);
+    }
+
+    /// Whether [`thread`], just made ready, should take the CPU from the
+    /// running thread.
+    fn should_preempt(&self, thread: *mut Thread) -> bool {
+        let running = self.running;
+        if running.is_null() {
+            return false;
+        }
+
+        if running == self.idle {
+            return true;
+        }
+
+        match unsafe { ((*thread).policy, (*running).policy) } {
+            (Policy::Fifo { priority }, Policy::Fifo { priority: running }) => priority > running,
+            (Policy::Fifo { .. }, Policy::Fair { .. }) => true,
+            (Policy::Fair { .. }, Policy::Fifo { .. }) => false,
+            (Policy::Fair { .. }, Policy::Fair { .. }) => unsafe {
+                (*thread).vruntime + fair::GRANULARITY_NS < (*running).vruntime
+            },
+        }
+    }
+
+    /// Queues a thread that's been blocked or away, asking for the running
+    /// thread to make way if it should go first.
+    fn make_ready(&mut self, thread: *mut Thread) {
+        unsafe { (*thread).vruntime = fair::place((*thread).vruntime, self.min_vruntime) };
+        self.enqueue(thread, false);
+        if self.should_preempt(thread) {
+            self.need_resched = true;
+        }
+    }
+
+    /// Marks [`thread`], already off every list here, as on its way
+    /// elsewhere.
+    fn detach(&mut self, thread: *mut Thread) {
+        unsafe {
+            (*thread).state = ThreadState::MIGRATING;
+            (*thread).vruntime = (*thread).vruntime.saturating_sub(self.min_vruntime);
+        }
+    }
+}
+
+static RUN_QUEUES: [SpinLock<RunQueue>; MAX_CPUS] = [const { SpinLock::new(RunQueue::new()) }; MAX_CPUS];
+/// How many threads each CPU has ready or running, idle aside. Read without
+/// the run queue locks, to decide where to put threads.
+static LOADS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+/// The CPUs running threads.
+static ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+type RunQueueGuard = IrqSafe<LockGuard<'static, RawSpinLock, RunQueue>>;
+
+fn active() -> CpuMask {
+    CpuMask(ACTIVE.load(Ordering::Acquire))
+}
+
+/// Locks the run queue [`thread`] is on. It can move until it's locked, so
+/// this checks again after.
+fn lock_rq_of(thread: *mut Thread) -> RunQueueGuard {
+    loop {
+        let cpu = unsafe { (*thread).cpu.load(Ordering::Acquire) };
+        let rq = RUN_QUEUES[cpu].lock_irq();
+        if unsafe { (*thread).cpu.load(Ordering::Relaxed) } == cpu {
+            return rq;
+        }
+    }
+}
+
+/// Gets CPU [`cpu`] to notice its run queue wants it to switch threads.
+fn kick(cpu: usize) {
+    if cpu == smp::current_cpu() {
+        preempt_if_needed();
+    } else {
+        ipi::send(cpu, ipi::WAKE_VECTOR);
+    }
+}
+
+/// Puts a [`ThreadState::MIGRATING`] thread on the run queue of
+/// [`preferred`], if it's allowed there, or of whichever allowed CPU is
+/// least busy.
+fn attach(thread: *mut Thread, preferred: Option<usize>) {
+    loop {
+        let affinity = unsafe { (*thread).affinity() };
+        let cpu = match preferred.filter(|&cpu| affinity.intersection(active()).contains(cpu)) {
+            Some(cpu) => cpu,
+            None => balance::select_cpu(affinity),
+        };
+
+        let mut rq = RUN_QUEUES[cpu].lock_irq();
+        // the affinity could have changed since it was read
+        if !unsafe { (*thread).affinity() }.contains(cpu) {
+            continue;
+        }
+
+        unsafe {
+            (*thread).cpu.store(cpu, Ordering::Release);
+            (*thread).vruntime += rq.min_vruntime;
+        }
+
+        rq.make_ready(thread);
+        let need_resched = rq.need_resched;
+        drop(rq);
+        if need_resched {
+            kick(cpu);
+        }
+
+        return;
+    }
+}
+
+/// Makes this CPU's run queue [`running`]'s and [`idle`]'s, and starts
+/// switching between threads.
+fn start_cpu(running: *mut Thread, idle: *mut Thread) {
+    let cpu = smp::current_cpu();
+    let mut rq = RUN_QUEUES[cpu].lock_irq();
+    unsafe {
+        (*running).state = ThreadState::RUNNING;
+        (*running).cpu.store(cpu, Ordering::Relaxed);
+    }
+
+    rq.cpu = cpu;
+    rq.idle = idle;
+    rq.running = running;
+    rq.publish_load();
+    CURRENT.set(running);
+    ACTIVE.fetch_or(CpuMask::single(cpu).0, Ordering::Release);
+}
+
+/// Turns whatever is running on the boot CPU into the first thread and
+/// starts its idle thread. Other CPUs start running threads from
+/// [`run_ap`].
+pub fn init() -> Result<(), SpawnError> {
+    let cpu = smp::current_cpu();
+    let main = Thread::alloc("main", Policy::DEFAULT, CpuMask::ALL, None, || {})?;
+    let idle = Thread::new("idle", Policy::DEFAULT, CpuMask::single(cpu), idle)?;
+    start_cpu(main, idle);
+    Ok(())
+}
+
+/// Turns the rest of an AP's life into its idle thread.
+pub fn run_ap() -> ! {
+    let cpu = smp::current_cpu();
+    let thread = Thread::alloc("idle", Policy::DEFAULT, CpuMask::single(cpu), None, idle).unwrap();
+    start_cpu(thread, thread);
+    idle();
+    unreachable!("the idle thread returned");
+}
+
+/// Runs whenever nothing else is ready.
+fn idle() {
+    let cpu = smp::current_cpu();
+    loop {
+        balance::pull(cpu);
+
+        // checked with interrupts off, so that a thread made ready after
+        // the check ends the hlt
+        interrupts::disable();
+        if RUN_QUEUES[cpu].lock().queued > 0 {
+            interrupts::enable();
+            yield_now();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Where a thread starts, on its own stack.
+extern "C" fn thread_main(thread: u64) -> ! {
+    let thread = thread as *mut Thread;
+    // switching here left the run queue locked and interrupts off, as they
+    // would be for a thread returning from the switch
+    let previous = take_previous();
+    unsafe { RUN_QUEUES[smp::current_cpu()].force_unlock() };
+    interrupts::enable();
+    finish_switch(previous);
+
+    unsafe { ((*thread).entry)() };
+    exit();
+}
+
+/// Switches to the next thread to run, putting the current one back in
+/// line if it's still runnable. Returns once the current thread is picked
+/// again, which might be straight away. [`yielding`] sends a FIFO thread to
+/// the back of its priority rather than keeping its place.
+fn switch(mut rq: RunQueueGuard, yielding: bool) {
+    debug_assert!(PREEMPT_COUNT.get() == 1, "switching threads holding a spinlock");
+    let prev = CURRENT.get();
+    let idle = rq.idle;
+    let cpu = rq.cpu;
+    rq.need_resched = false;
+
+    let preempted = unsafe { (*prev).state } == ThreadState::RUNNING && !yielding;
+    unsafe {
+        if (*prev).state == ThreadState::RUNNING {
+            if prev == idle {
+                (*prev).state = ThreadState::READY;
+            } else if !(*prev).affinity().contains(cpu) {
+                // its affinity changed while it ran; it's put on another
+                // CPU once it's off this one
+                (*prev).state = ThreadState::MIGRATING;
+                rq.detach(prev);
+            } else {
+                // a preempted FIFO thread keeps its place
+                let front = preempted && matches!((*prev).policy, Policy::Fifo { .. });
+                rq.enqueue(prev, front);
+            }
+        }
+    }
+
+    let next = rq.pick_next().unwrap_or(idle);
+    rq.running = next;
+    rq.publish_load();
+    unsafe {
+        (*next).state = ThreadState::RUNNING;
+        if next != idle {
+            (*next).counters.wait_time += clock::now().saturating_sub((*next).ready_since);
+        }
+    }
+
+    if next == prev {
+        return;
+    }
+
+    unsafe {
+        if preempted {
+            (*prev).counters.involuntary_switches += 1;
+        } else {
+            (*prev).counters.voluntary_switches += 1;
+        }
+
+        (*prev).fpu.save();
+        (*prev).vm_space = vma::active();
+        if (*next).vm_space != (*prev).vm_space {
+            vma::activate((*next).vm_space);
+        }
+
+        (*prev).interrupt_depth = percpu::INTERRUPT_DEPTH.get();
+        (*prev).held_levels = HELD_LEVELS.get();
+        (*next).fpu.restore();
+        percpu::INTERRUPT_DEPTH.set((*next).interrupt_depth);
+        HELD_LEVELS.set((*next).held_levels);
+        if let Some(stack) = &(*next).stack {
+            (*percpu::TSS.get()).rsp[0] = stack.top();
+        }
+
+        PREVIOUS.set(prev);
+        CURRENT.set(next);
+    }
+
+    // the guard is for this CPU's run queue, and the thread might come back
+    // on another, which whoever switches back to it will have locked
+    let interrupts_were_enabled = IrqSafe::leak(rq);
+    unsafe { context::switch_stacks(&raw mut (*prev).rsp, (*next).rsp) };
+
+    let previous = take_previous();
+    unsafe { RUN_QUEUES[smp::current_cpu()].force_unlock() };
+    if interrupts_were_enabled {
+        interrupts::enable();
+    }
+
+    finish_switch(previous);
+}
+
+/// The thread just switched away from, if it exited or has to move, or
+/// null. Must be called before the run queue is unlocked.
+fn take_previous() -> *mut Thread {
+    let prev = PREVIOUS.get();
+    PREVIOUS.set(null_mut());
+    if prev.is_null() {
+        return prev;
+    }
+
+    match unsafe { (*prev).state } {
+        ThreadState::DEAD | ThreadState::MIGRATING => prev,
+        _ => null_mut(),
+    }
+}
+
+/// Deals with a thread from [`take_previous`] now that it's off its stack
+/// and the run queue is unlocked.
+fn finish_switch(previous: *mut Thread) {
+    if previous.is_null() {
+        return;
+    }
+
+    unsafe {
+        if (*previous).state == ThreadState::DEAD {
+            (*previous).exited.complete();
+            release(previous);
+        } else {
+            attach(previous, None);
+        }
+    }
+}
+
+/// Starts a fair thread running [`entry`] on any CPU. [`name`] shows up in
+/// stack overflow reports and statistics.
+pub fn spawn(name: &'static str, entry: fn()) -> Result<JoinHandle, SpawnError> {
+    spawn_with(name, Policy::DEFAULT, CpuMask::ALL, entry)
+}
+
+/// Starts a thread scheduled by [`policy`] that only runs on the CPUs in
+/// [`affinity`].
+pub fn spawn_with(name: &'static str, policy: Policy, affinity: CpuMask, entry: fn()) -> Result<JoinHandle, SpawnError> {
+    // online CPUs that haven't reached the scheduler yet can't be picked,
+    // and CPUs never stop running threads, so this stays true
+    if affinity.intersection(active()).is_empty() {
+        return Err(SpawnError::Affinity(AffinityError::NoCpus));
+    }
+
+    let thread = Thread::new(name, policy, affinity, entry)?;
+    unsafe { (*thread).refs.store(2, Ordering::Relaxed) };
+    attach(thread, None);
+    Ok(JoinHandle { thread })
+}
+
+/// A thread started by [`spawn`]. Dropping it lets the thread run on
+/// without anyone waiting for it.
+pub struct JoinHandle {
+    thread: *mut Thread,
+}
+
+/// The handle's reference keeps the thread's memory around.
+unsafe impl Send for JoinHandle {}
+unsafe impl Sync for JoinHandle {}
+
+impl JoinHandle {
+    pub fn id(&self) -> u64 {
+        unsafe { (*self.thread).id }
+    }
+
+    pub fn name(&self) -> &'static str {
+        unsafe { (*self.thread).name }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        unsafe { (*self.thread).exited.is_completed() }
+    }
+
+    pub fn stats(&self) -> ThreadStats {
+        unsafe { stats::snapshot(self.thread) }
+    }
+
+    pub fn set_policy(&self, policy: Policy) {
+        set_thread_policy(self.thread, policy);
+    }
+
+    pub fn set_affinity(&self, affinity: CpuMask) -> Result<(), AffinityError> {
+        set_thread_affinity(self.thread, affinity)
+    }
+
+    /// Sleeps until the thread has returned.
+    #[track_caller]
+    pub fn join(self) {
+        unsafe { (*self.thread).exited.wait() };
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        unsafe { release(self.thread) };
+    }
+}
+
+fn current_thread() -> *mut Thread {
+    let thread = CURRENT.get();
+    assert!(!thread.is_null(), "CPU {} doesn't run threads", smp::current_cpu());
+    thread
+}
+
+/// Changes how the current thread is scheduled.
+pub fn set_policy(policy: Policy) {
+    set_thread_policy(current_thread(), policy);
+}
+
+/// Restricts the current thread to the CPUs in [`affinity`], moving it if
+/// it isn't on one of them.
+pub fn set_affinity(affinity: CpuMask) -> Result<(), AffinityError> {
+    set_thread_affinity(current_thread(), affinity)
+}
+
+fn set_thread_policy(thread: *mut Thread, policy: Policy) {
+    let mut rq = lock_rq_of(thread);
+    unsafe {
+        if (*thread).state == ThreadState::READY {
+            rq.dequeue(thread);
+            (*thread).policy = policy.clamped();
+            rq.enqueue(thread, false);
+        } else {
+            (*thread).policy = policy.clamped();
+        }
+    }
+
+    // whatever should run now is worked out again from scratch
+    rq.need_resched = true;
+    let cpu = rq.cpu;
+    drop(rq);
+    kick(cpu);
+}
+
+fn set_thread_affinity(thread: *mut Thread, affinity: CpuMask) -> Result<(), AffinityError> {
+    if affinity.intersection(active()).is_empty() {
+        return Err(AffinityError::NoCpus);
+    }
+
+    unsafe { (*thread).affinity.store(affinity.0, Ordering::Relaxed) };
+    let mut rq = lock_rq_of(thread);
+    let cpu = rq.cpu;
+    if affinity.contains(cpu) {
+        return Ok(());
+    }
+
+    match unsafe { (*thread).state } {
+        ThreadState::READY => {
+            rq.dequeue(thread);
+            rq.detach(thread);
+            drop(rq);
+            attach(thread, None);
+        },
+        ThreadState::RUNNING => {
+            // it moves once it's switched out
+            rq.need_resched = true;
+            drop(rq);
+            kick(cpu);
+        },
+        // blocked threads move when they're woken, and migrating ones check
+        // the new mask when they arrive
+        _ => {},
+    }
+
+    Ok(())
+}
+
+/// Ends the current thread, as returning from its entry point does.
+pub fn exit() -> ! {
+    let thread = current_thread();
+    let rq = lock_rq_of(thread);
+    assert!(thread != rq.idle, "the idle thread can't exit");
+    unsafe { (*thread).state = ThreadState::DEAD };
+    switch(rq, false);
+    unreachable!("an exited thread was switched back to");
+}
+
+/// Lets other ready threads run first: fair threads that are due, or FIFO
+/// threads of the same priority.
+pub fn yield_now() {
+    let thread = CURRENT.get();
+    if thread.is_null() {
+        spin_loop();
+        return;
+    }
+
+    switch(lock_rq_of(thread), true);
+}
+
+/// Sleeps until [`deadline`] on the [`clock`].
+#[track_caller]
+pub fn sleep_until(deadline: Duration) {
+    might_sleep();
+    // nothing sets it, so only the deadline ends the block
+    let never = AtomicBool::new(false);
+    block(&never, Some(deadline));
+}
+
+#[track_caller]
+pub fn sleep(duration: Duration) {
+    sleep_until(clock::now() + duration);
+}
+
+/// Called from each CPU's timer interrupt. Wakes threads whose deadlines
+/// passed, charges the running thread for the tick and takes the CPU back
+/// from it if its turn is over, and every so often looks for work on busier
+/// CPUs.
+pub fn tick() {
+    if CURRENT.get().is_null() {
+        return;
+    }
+
+    let cpu = smp::current_cpu();
+    // woken threads no longer allowed here, moved once the queue's unlocked
+    let mut moving = ThreadList::new();
+    let balance = {
+        let mut rq = RUN_QUEUES[cpu].lock_irq();
+        let now = clock::now();
+        for thread in rq.sleeping.iter() {
+            if unsafe { (*thread).deadline }.is_none_or(|deadline| deadline > now) {
+                continue;
+            }
+
+            rq.sleeping.remove(thread);
+            unsafe { (*thread).deadline = None };
+            if unsafe { (*thread).affinity() }.contains(cpu) {
+                rq.make_ready(thread);
+            } else {
+                rq.detach(thread);
+                moving.push_back(thread);
+            }
+        }
+
+        let running = rq.running;
+        if running != rq.idle {
+            unsafe {
+                (*running).counters.run_time += TICK;
+                if let Policy::Fair { nice } = (*running).policy {
+                    (*running).vruntime += fair::scale(TICK.as_nanos() as u64, nice);
+                }
+            }
+
+            rq.update_min_vruntime();
+            if let Policy::Fair { .. } = unsafe { (*running).policy } {
+                let behind = rq.min_fair().is_some_and(|next| unsafe {
+                    (*next).vruntime + fair::GRANULARITY_NS < (*running).vruntime
+                });
+                if behind {
+                    rq.need_resched = true;
+                }
+            }
+        }
+
+        rq.ticks += 1;
+        rq.ticks.is_multiple_of(balance::INTERVAL_TICKS)
+    };
+
+    while let Some(thread) = moving.pop_front() {
+        attach(thread, None);
+    }
+
+    if balance {
+        balance::pull(cpu);
+    }
+
+    preempt_if_needed();
+}
+
+/// Switches threads if something asked for it, unless preemption is off.
+/// Interrupt handlers that can make threads ready call it once they're
+/// done.
+pub fn preempt_if_needed() {
+    // a handler that interrupted another handler leaves it to the outer one
+    let thread = CURRENT.get();
+    if thread.is_null() || PREEMPT_COUNT.get() != 0 || percpu::INTERRUPT_DEPTH.get() > 1 {
+        return;
+    }
+
+    let rq = lock_rq_of(thread);
+    if rq.need_resched {
+        switch(rq, false);
+    }
+}
+
+pub fn preempt_disable() {
+    if percpu::is_ready() {
+        PREEMPT_COUNT.set(PREEMPT_COUNT.get() + 1);
+    }
+}
+
+/// Undoes a [`preempt_disable`], switching threads if one was asked for
+/// meanwhile and nothing else keeps preemption off.
+pub fn preempt_enable() {
+    if !percpu::is_ready() {
+        return;
+    }
+
+    // one disabled before per-CPU variables worked might be enabled after
+    let count = PREEMPT_COUNT.get().saturating_sub(1);
+    PREEMPT_COUNT.set(count);
+    if count == 0 && interrupts::are_enabled() && !percpu::in_interrupt() {
+        preempt_if_needed();
+    }
+}
+
+pub fn preempt_count() -> u32 {
+    if percpu::is_ready() { PREEMPT_COUNT.get() } else { 0 }
+}
+
+/// Something that can block: a thread, or a whole CPU that doesn't run
+/// threads.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Task {
+    thread: *mut Thread,
+    cpu: usize,
+}
+
+/// Only used to wake the task, which stays alive while it's blocked.
+unsafe impl Send for Task {}
+
+pub fn current() -> Task {
+    Task { thread: CURRENT.get(), cpu: smp::current_cpu() }
+}
+
+/// Panics in debug builds if the caller isn't allowed to sleep: from an
+/// interrupt handler, with interrupts off or holding a spinlock, nothing
+/// would be left to wake it.
+#[track_caller]
+pub fn might_sleep() {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    assert!(!percpu::in_interrupt(), "sleeping in an interrupt handler");
+    assert!(interrupts::are_enabled(), "sleeping with interrupts off");
+    let held = preempt_count();
+    assert!(held == 0, "sleeping holding {held} spinlocks");
+}
+
+/// Blocks the current task until [`woken`] is set, or until [`deadline`] on
+/// the [`clock`] passes. Returns whether it was woken.
+pub fn block(woken: &AtomicBool, deadline: Option<Duration>) -> bool {
+    let thread = CURRENT.get();
+    if thread.is_null() {
+        return halt_until(woken, deadline);
+    }
+
+    loop {
+        // checked with the run queue locked, which a wake takes after
+        // setting the flag
+        let mut rq = lock_rq_of(thread);
+        if woken.load(Ordering::Acquire) {
+            return true;
+        }
+
+        if deadline.is_some_and(|deadline| clock::now() >= deadline) {
+            return false;
+        }
+
+        unsafe {
+            (*thread).state = ThreadState::BLOCKED;
+            (*thread).deadline = deadline;
+        }
+
+        if deadline.is_some() {
+            rq.sleeping.push_back(thread);
+        }
+
+        switch(rq, false);
+    }
+}
+
+/// [`block`] for a CPU that doesn't run threads.
+fn halt_until(woken: &AtomicBool, deadline: Option<Duration>) -> bool {
+    // only the boot CPU takes the clock's interrupt, so the others can't
+    // halt until a deadline
+    let can_halt = deadline.is_none() || smp::current_cpu() == 0;
+    loop {
+        // a wake sent between the check and the hlt stays pending until
+        // enable_and_hlt's sti, and ends the hlt
+        interrupts::disable();
+        if woken.load(Ordering::Acquire) {
+            interrupts::enable();
+            return true;
+        }
+
+        if deadline.is_some_and(|deadline| clock::now() >= deadline) {
+            interrupts::enable();
+            return false;
+        }
+
+        if can_halt {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+            spin_loop();
+        }
+    }
+}
+
+/// Gets [`task`] to notice the flag it's [`block`]ed on has been set.
+pub fn wake(task: Task) {
+    let thread = task.thread;
+    if thread.is_null() {
+        if task.cpu != smp::current_cpu() {
+            ipi::send(task.cpu, ipi::WAKE_VECTOR);
+        }
+
+        return;
+    }
+
+    let mut rq = lock_rq_of(thread);
+    unsafe {
+        if (*thread).state != ThreadState::BLOCKED {
+            return;
+        }
+
+        if (*thread).deadline.take().is_some() {
+            rq.sleeping.remove(thread);
+        }
+    }
+
+    let cpu = rq.cpu;
+    if unsafe { (*thread).affinity() }.contains(cpu) {
+        rq.make_ready(thread);
+        let need_resched = rq.need_resched;
+        drop(rq);
+        if need_resched {
+            kick(cpu);
+        }
+    } else {
+        rq.detach(thread);
+        drop(rq);
+        attach(thread, None);
+    }
+}
+
+pub fn held_levels() -> u64 {
+    HELD_LEVELS.get()
+}
+
+pub fn set_held_levels(levels: u64) {
+    HELD_LEVELS.set(levels);
+}
@@ -0,0 +1,50 @@
+//! Spreading threads over the CPUs: where new and woken threads go, and
+//! moving ready threads from busy CPUs to quieter ones.
+
+use core::sync::atomic::Ordering;
+
+use crate::arch::x86::smp::CpuMask;
+
+use super::{active, attach, LOADS, RUN_QUEUES};
+
+/// Timer ticks between each CPU looking for work on the others.
+pub const INTERVAL_TICKS: u64 = 100;
+
+fn load(cpu: usize) -> usize {
+    LOADS[cpu].load(Ordering::Relaxed)
+}
+
+/// The least busy CPU in [`affinity`] that runs threads. Spawning and
+/// setting affinities check there's one.
+pub fn select_cpu(affinity: CpuMask) -> usize {
+    let allowed = affinity.intersection(active());
+    allowed.iter().min_by_key(|&cpu| load(cpu)).expect("no CPU in the affinity mask runs threads")
+}
+
+/// Moves a ready thread to CPU [`this`] from the busiest CPU, if that's
+/// busier by more than one thread, since moving one then would only swap
+/// which is busier. Returns whether it found one it was allowed to move.
+pub fn pull(this: usize) -> bool {
+    let Some(busiest) = active().without(this).iter().max_by_key(|&cpu| load(cpu)) else {
+        return false;
+    };
+
+    // racy, but only used to decide whether to look
+    if load(busiest) <= load(this) + 1 {
+        return false;
+    }
+
+    let thread = {
+        let mut rq = RUN_QUEUES[busiest].lock_irq();
+        let Some(thread) = rq.find_movable(this) else {
+            return false;
+        };
+
+        rq.dequeue(thread);
+        rq.detach(thread);
+        thread
+    };
+
+    attach(thread, Some(this));
+    true
+}
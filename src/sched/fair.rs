@@ -0,0 +1,40 @@
+//! The arithmetic behind [`Policy::Fair`](super::Policy::Fair): a thread's
+//! vruntime goes up as it runs, more slowly the higher its weight, and the
+//! thread with the lowest vruntime runs next.
+
+/// Weight of a thread at nice 0.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weights for nice -20 to 19, as in Linux: each step is worth about 10%
+/// of CPU time against a thread one step away.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904, 3906, 3121, 2501,
+    1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87, 70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// How far the running thread's vruntime can get ahead of the next one's
+/// before it makes way, so that threads aren't switched every tick.
+pub const GRANULARITY_NS: u64 = 4_000_000;
+
+/// How far below the run queue's minimum vruntime a woken thread is put,
+/// so that one that sleeps a lot gets the CPU quickly when it wakes, but
+/// can't save up a sleep's worth of CPU time.
+pub const SLEEPER_CREDIT_NS: u64 = 6_000_000;
+
+pub fn weight(nice: i8) -> u64 {
+    WEIGHTS[(nice.clamp(MIN_NICE, MAX_NICE) - MIN_NICE) as usize]
+}
+
+/// The vruntime [`ns`] of running costs a thread at [`nice`].
+pub fn scale(ns: u64, nice: i8) -> u64 {
+    ns * NICE_0_WEIGHT / weight(nice)
+}
+
+/// Where a thread arriving on a run queue whose minimum vruntime is
+/// [`min_vruntime`] starts, given the [`vruntime`] it had.
+pub fn place(vruntime: u64, min_vruntime: u64) -> u64 {
+    vruntime.max(min_vruntime.saturating_sub(SLEEPER_CREDIT_NS))
+}
@@ -0,0 +1,138 @@
+//! Per-thread scheduler statistics, for working out where latency comes
+//! from: how long each thread has run, how long it's spent ready but
+//! waiting for a CPU, and how often it's been switched out.
+
+use core::{fmt, ptr::null_mut, time::Duration};
+
+use crate::sync::rw_spin::RwSpinLock;
+
+use super::{lock_rq_of, Policy, Thread};
+
+/// Kept in each [`Thread`], and only changed with its run queue locked.
+pub struct Counters {
+    /// Sampled at each timer tick, so short bursts are only counted on
+    /// average.
+    pub run_time: Duration,
+    pub wait_time: Duration,
+    /// Switched out because it blocked, yielded or exited.
+    pub voluntary_switches: u64,
+    /// Switched out while it still had work to do.
+    pub involuntary_switches: u64,
+}
+
+impl Counters {
+    pub const fn new() -> Self {
+        Self { run_time: Duration::ZERO, wait_time: Duration::ZERO, voluntary_switches: 0, involuntary_switches: 0 }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ThreadStats {
+    pub id: u64,
+    pub name: &'static str,
+    /// The CPU it's running or queued on, or last ran on if it's blocked.
+    pub cpu: usize,
+    pub policy: Policy,
+    pub run_time: Duration,
+    pub wait_time: Duration,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+}
+
+/// Every thread that hasn't been freed, linked through [`Thread::all_next`].
+struct AllThreads {
+    head: *mut Thread,
+}
+
+/// The list only points at threads that are alive.
+unsafe impl Send for AllThreads {}
+unsafe impl Sync for AllThreads {}
+
+/// Only changed when a thread starts or is freed, and otherwise read.
+static ALL_THREADS: RwSpinLock<AllThreads> = RwSpinLock::new(AllThreads { head: null_mut() });
+
+pub fn register(thread: *mut Thread) {
+    let mut all = ALL_THREADS.write_irq();
+    unsafe { (*thread).all_next = all.head };
+    all.head = thread;
+}
+
+/// Takes [`thread`] off the list before it's freed.
+pub fn unregister(thread: *mut Thread) {
+    let mut all = ALL_THREADS.write_irq();
+    let mut link = &raw mut all.head;
+    unsafe {
+        while !(*link).is_null() {
+            if *link == thread {
+                *link = (*thread).all_next;
+                return;
+            }
+
+            link = &raw mut (**link).all_next;
+        }
+    }
+}
+
+/// A snapshot of [`thread`]'s statistics.
+///
+/// Safety: [`thread`] must be alive.
+pub unsafe fn snapshot(thread: *mut Thread) -> ThreadStats {
+    let rq = lock_rq_of(thread);
+    let thread = unsafe { &*thread };
+    let stats = ThreadStats {
+        id: thread.id,
+        name: thread.name,
+        cpu: rq.cpu,
+        policy: thread.policy,
+        run_time: thread.counters.run_time,
+        wait_time: thread.counters.wait_time,
+        voluntary_switches: thread.counters.voluntary_switches,
+        involuntary_switches: thread.counters.involuntary_switches,
+    };
+
+    drop(rq);
+    stats
+}
+
+/// Calls [`f`] with the statistics of every thread, newest first. The
+/// thread list stays locked meanwhile, so [`f`] can't sleep or start or
+/// free threads.
+pub fn for_each(mut f: impl FnMut(&ThreadStats)) {
+    let all = ALL_THREADS.read_irq();
+    let mut thread = all.head;
+    while !thread.is_null() {
+        f(&unsafe { snapshot(thread) });
+        thread = unsafe { (*thread).all_next };
+    }
+}
+
+/// Writes a table of every thread's statistics, one line each.
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(out, "{:>4} {:>3} {:<8} {:>10} {:>10} {:>8} {:>8} NAME", "ID", "CPU", "POLICY", "RUN ms", "WAIT ms", "VOL", "INVOL")?;
+    let mut result = Ok(());
+    for_each(|stats| {
+        // nice for fair threads, priority for FIFO ones
+        let (class, level) = match stats.policy {
+            Policy::Fair { nice } => ("fair", nice as i16),
+            Policy::Fifo { priority } => ("fifo", priority as i16),
+        };
+
+        result = result.and_then(|()| {
+            writeln!(
+                out,
+                "{:>4} {:>3} {:<5}{:>3} {:>10} {:>10} {:>8} {:>8} {}",
+                stats.id,
+                stats.cpu,
+                class,
+                level,
+                stats.run_time.as_millis(),
+                stats.wait_time.as_millis(),
+                stats.voluntary_switches,
+                stats.involuntary_switches,
+                stats.name,
+            )
+        });
+    });
+
+    result
+}
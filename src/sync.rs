@@ -0,0 +1,236 @@
+//! Locks and other ways of waiting for each other.
+//!
+//! The spinning locks in [`spin`] and [`rw_spin`] work anywhere, and keep
+//! the holder from being preempted. Data that an interrupt handler also
+//! touches has to be locked with interrupts off, through the `_irq` methods
+//! and their [`IrqSafe`] guards, or the handler could spin forever on a
+//! lock held by the code it interrupted.
+//!
+//! Everything else sleeps on a [`wait_queue::WaitQueue`] until it can go
+//! on, so it can only be used where sleeping is allowed: see
+//! [`crate::sched::might_sleep`].
+//!
+//! A CPU spinning on a lock with interrupts off still runs the functions
+//! other CPUs call on it, so that one can hold a lock while it waits for a
+//! TLB shootdown.
+//!
+//! Debug builds remember which CPU holds a spinlock and where it was
+//! taken, and panic with both rather than spin on a lock the same CPU
+//! already holds, or on one that's been held for suspiciously long. They
+//! also check that sleeping locks given a [`LockLevel`] are always taken in
+//! increasing order.
+
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{arch::x86::{interrupts, ipi, percpu}, sched};
+
+pub mod completion;
+pub mod condvar;
+pub mod mutex;
+pub mod rw_spin;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spin;
+pub mod wait_queue;
+
+use owner::Owner;
+
+/// Wraps a lock guard, keeping interrupts off from before the lock is
+/// taken until after it's released.
+pub struct IrqSafe<G> {
+    guard: ManuallyDrop<G>,
+    interrupts_were_enabled: bool,
+}
+
+/// Disables interrupts, returning whether they were enabled, for
+/// [`IrqSafe::new`] once the lock is taken.
+fn disable_interrupts() -> bool {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    enabled
+}
+
+impl<G> IrqSafe<G> {
+    fn new(guard: G, interrupts_were_enabled: bool) -> Self {
+        Self { guard: ManuallyDrop::new(guard), interrupts_were_enabled }
+    }
+
+    /// Leaves the lock held and interrupts off, for code that undoes both
+    /// some other way. Returns whether interrupts were enabled before.
+    pub fn leak(this: Self) -> bool {
+        let interrupts_were_enabled = this.interrupts_were_enabled;
+        core::mem::forget(this);
+        interrupts_were_enabled
+    }
+}
+
+/// Called on every spin while waiting for a lock. A CPU with interrupts off
+/// can't take [`ipi::CALL_VECTOR`], so it empties its mailbox itself, in
+/// case whoever holds the lock is waiting for it to.
+fn while_spinning() {
+    if percpu::is_ready() && !interrupts::are_enabled() {
+        ipi::handle_mailbox();
+    }
+}
+
+impl<G: Deref> Deref for IrqSafe<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for IrqSafe<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<G> Drop for IrqSafe<G> {
+    fn drop(&mut self) {
+        // unlock first, so an interrupt can't find it still held
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+mod owner {
+    use core::{
+        panic::Location,
+        ptr::null_mut,
+        sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+    };
+
+    use crate::arch::x86::{percpu, smp};
+
+    /// How many times a CPU spins on a lock before deciding it's
+    /// deadlocked.
+    const SPIN_LIMIT: u64 = 1 << 32;
+    const NO_CPU: usize = usize::MAX;
+
+    fn current_cpu() -> usize {
+        if percpu::is_ready() { smp::current_cpu() } else { 0 }
+    }
+
+    /// Who holds a lock, and since where.
+    pub struct Owner {
+        cpu: AtomicUsize,
+        site: AtomicPtr<Location<'static>>,
+    }
+
+    impl Owner {
+        pub const fn new() -> Self {
+            Self { cpu: AtomicUsize::new(NO_CPU), site: AtomicPtr::new(null_mut()) }
+        }
+
+        fn panic(&self, what: &str) -> ! {
+            let site = self.site.load(Ordering::Relaxed);
+            match unsafe { site.as_ref() } {
+                Some(site) => panic!("{what}: held by CPU {}, taken at {site}", self.cpu.load(Ordering::Relaxed)),
+                None => panic!("{what}"),
+            }
+        }
+
+        /// Called before waiting for the lock.
+        pub fn check(&self) {
+            if self.cpu.load(Ordering::Relaxed) == current_cpu() {
+                self.panic("deadlock: lock taken twice on the same CPU");
+            }
+        }
+
+        /// Called on every spin while waiting, with a count that starts at
+        /// 0.
+        pub fn spin(&self, spins: &mut u64) {
+            *spins += 1;
+            if *spins == SPIN_LIMIT {
+                self.panic("deadlock: gave up waiting for lock");
+            }
+        }
+
+        #[track_caller]
+        pub fn acquired(&self) {
+            self.site.store((Location::caller() as *const Location).cast_mut(), Ordering::Relaxed);
+            self.cpu.store(current_cpu(), Ordering::Relaxed);
+        }
+
+        pub fn released(&self) {
+            self.cpu.store(NO_CPU, Ordering::Relaxed);
+            self.site.store(null_mut(), Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod owner {
+    /// Nothing is tracked outside debug builds.
+    pub struct Owner;
+
+    impl Owner {
+        pub const fn new() -> Self {
+            Self
+        }
+
+        pub fn check(&self) {}
+
+        pub fn spin(&self, _spins: &mut u64) {}
+
+        #[track_caller]
+        pub fn acquired(&self) {}
+
+        pub fn released(&self) {}
+    }
+}
+
+/// Where a sleeping lock sits in the lock order. A task can only wait for
+/// a lock of a higher level than any it holds, which rules out two tasks
+/// each holding the lock the other wants. [`LockLevel::NONE`] opts out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LockLevel(pub u32);
+
+impl LockLevel {
+    pub const NONE: Self = Self(0);
+    /// Levels are bits in the mask of those a task holds.
+    pub const MAX: Self = Self(63);
+
+    const fn bit(self) -> u64 {
+        1 << self.0
+    }
+}
+
+/// Records that the current task holds [`level`], panicking in debug
+/// builds if it's out of order.
+#[track_caller]
+fn enter_level(level: LockLevel) {
+    if !cfg!(debug_assertions) || level == LockLevel::NONE {
+        return;
+    }
+
+    let held = sched::held_levels();
+    if held >= level.bit() {
+        panic!("lock order: taking level {} while holding level {}", level.0, u64::BITS - 1 - held.leading_zeros());
+    }
+
+    sched::set_held_levels(held | level.bit());
+}
+
+/// Like [`enter_level`] without the order check, for locks taken without
+/// waiting, which can't deadlock whatever else the task holds.
+fn try_enter_level(level: LockLevel) {
+    if cfg!(debug_assertions) && level != LockLevel::NONE {
+        sched::set_held_levels(sched::held_levels() | level.bit());
+    }
+}
+
+/// Forgets only [`level`], so guards can be dropped in any order.
+fn exit_level(level: LockLevel) {
+    if cfg!(debug_assertions) && level != LockLevel::NONE {
+        sched::set_held_levels(sched::held_levels() & !level.bit());
+    }
+}
@@ -0,0 +1,44 @@
+//! A one-shot event: once it's completed, everyone waiting for it goes on,
+//! and so does anyone who waits later.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use super::wait_queue::WaitQueue;
+
+pub struct Completion {
+    done: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Completion {
+    pub const fn new() -> Self {
+        Self { done: AtomicBool::new(false), waiters: WaitQueue::new() }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    /// Wakes every waiter. Can be called from an interrupt handler.
+    pub fn complete(&self) {
+        self.done.store(true, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    /// Sleeps until [`Completion::complete`] is called, unless it already
+    /// was.
+    #[track_caller]
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.is_completed());
+    }
+
+    /// Like [`Completion::wait`], giving up after [`timeout`]. Returns
+    /// whether it was completed.
+    #[track_caller]
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.waiters.wait_until_timeout(|| self.is_completed(), timeout)
+    }
+}
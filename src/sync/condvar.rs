@@ -0,0 +1,89 @@
+//! Condition variables, for sleeping on a [`Mutex`](super::mutex::Mutex)
+//! until the data it protects changes.
+
+#![allow(dead_code)]
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::{mutex::MutexGuard, wait_queue::WaitQueue};
+
+pub struct Condvar {
+    /// Bumped by every notify, so a waiter can tell one happened since it
+    /// let go of the mutex.
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { generation: AtomicU64::new(0), waiters: WaitQueue::new() }
+    }
+
+    /// Releases [`guard`]'s mutex, sleeps until notified, and takes the
+    /// mutex again. Wakes can be spurious, so check the condition in a loop
+    /// or use [`Condvar::wait_while`].
+    #[track_caller]
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_timeout_inner(guard, None).0
+    }
+
+    /// Like [`Condvar::wait`], giving up after [`timeout`]. Also returns
+    /// whether it timed out.
+    #[track_caller]
+    pub fn wait_timeout<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, timeout: Duration) -> (MutexGuard<'a, T>, bool) {
+        self.wait_timeout_inner(guard, Some(timeout))
+    }
+
+    /// Waits for as long as [`condition`] holds for the data.
+    #[track_caller]
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    #[track_caller]
+    fn wait_timeout_inner<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex();
+        // read while the mutex is still held, so a notify after it's
+        // released can't be missed
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+
+        let notified = || self.generation.load(Ordering::Acquire) != generation;
+        let timed_out = match timeout {
+            Some(timeout) => !self.waiters.wait_until_timeout(notified, timeout),
+            None => {
+                self.waiters.wait_until(notified);
+                false
+            },
+        };
+
+        (mutex.lock(), timed_out)
+    }
+
+    /// Wakes one waiting task.
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wakes every waiting task.
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
@@ -0,0 +1,119 @@
+//! A lock that sleeps rather than spins while it's held elsewhere.
+
+#![allow(dead_code)]
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::sched;
+
+use super::{enter_level, exit_level, try_enter_level, wait_queue::WaitQueue, LockLevel};
+
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    level: LockLevel,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_level(value, LockLevel::NONE)
+    }
+
+    /// A mutex that's checked against the lock order at [`level`].
+    pub const fn with_level(value: T, level: LockLevel) -> Self {
+        assert!(level.0 <= LockLevel::MAX.0);
+        Self { locked: AtomicBool::new(false), waiters: WaitQueue::new(), level, data: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn try_lock_raw(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    /// Sleeps until the mutex is free and takes it.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // even when it's free, so that callers that can't sleep are caught
+        // before the one time it isn't
+        sched::might_sleep();
+        enter_level(self.level);
+        self.waiters.wait_until(|| self.try_lock_raw());
+        MutexGuard { mutex: self }
+    }
+
+    /// Like [`Mutex::lock`], giving up after [`timeout`].
+    #[track_caller]
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        sched::might_sleep();
+        enter_level(self.level);
+        if !self.waiters.wait_until_timeout(|| self.try_lock_raw(), timeout) {
+            exit_level(self.level);
+            return None;
+        }
+
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// Returns [`None`] if the mutex is held. Never sleeps.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if !self.try_lock_raw() {
+            return None;
+        }
+
+        try_enter_level(self.level);
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The mutex this guard holds, for [`super::condvar::Condvar`] to take
+    /// again after waiting.
+    pub fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        exit_level(self.mutex.level);
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
@@ -0,0 +1,188 @@
+//! A spinning reader-writer lock: any number of readers, or one writer.
+
+#![allow(dead_code)]
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::sched;
+
+use super::{disable_interrupts, while_spinning, IrqSafe, Owner};
+
+const WRITER: u32 = 1 << 0;
+/// A writer is waiting, so no new readers are let in. Without it, a steady
+/// stream of readers would keep writers out forever.
+const WRITER_WAITING: u32 = 1 << 1;
+/// Readers are counted above the two flags.
+const READER: u32 = 1 << 2;
+
+pub struct RwSpinLock<T: ?Sized> {
+    state: AtomicU32,
+    /// Only the writer is tracked; readers are just a count.
+    owner: Owner,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self { state: AtomicU32::new(0), owner: Owner::new(), data: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinLock<T> {
+    fn try_read_raw(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & (WRITER | WRITER_WAITING) == 0
+            && self.state.compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn try_write_raw(&self) -> bool {
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    /// Spins until there's no writer, waiting or holding the lock, and
+    /// takes a read lock.
+    #[track_caller]
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        // readers aren't tracked, so this only catches a held write lock
+        self.owner.check();
+        sched::preempt_disable();
+        let mut spins = 0;
+        while !self.try_read_raw() {
+            self.owner.spin(&mut spins);
+            while_spinning();
+            spin_loop();
+        }
+
+        ReadGuard { lock: self }
+    }
+
+    /// Returns [`None`] if there's a writer, waiting or holding the lock.
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        sched::preempt_disable();
+        if !self.try_read_raw() {
+            sched::preempt_enable();
+            return None;
+        }
+
+        Some(ReadGuard { lock: self })
+    }
+
+    /// Spins until nobody holds the lock and takes it for writing. Readers
+    /// that come along meanwhile have to wait.
+    #[track_caller]
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        self.owner.check();
+        sched::preempt_disable();
+        let mut spins = 0;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & !WRITER_WAITING == 0 {
+                // clears WRITER_WAITING too; any other waiting writer sets it
+                // again on its next spin
+                if self.state.compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    break;
+                }
+            } else if state & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+
+            self.owner.spin(&mut spins);
+            while_spinning();
+            spin_loop();
+        }
+
+        self.owner.acquired();
+        WriteGuard { lock: self }
+    }
+
+    /// Returns [`None`] if anybody holds the lock.
+    #[track_caller]
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        sched::preempt_disable();
+        if !self.try_write_raw() {
+            sched::preempt_enable();
+            return None;
+        }
+
+        self.owner.acquired();
+        Some(WriteGuard { lock: self })
+    }
+
+    /// Like [`RwSpinLock::read`], with interrupts off until the guard is
+    /// dropped.
+    #[track_caller]
+    pub fn read_irq(&self) -> IrqSafe<ReadGuard<'_, T>> {
+        let interrupts_were_enabled = disable_interrupts();
+        IrqSafe::new(self.read(), interrupts_were_enabled)
+    }
+
+    /// Like [`RwSpinLock::write`], with interrupts off until the guard is
+    /// dropped.
+    #[track_caller]
+    pub fn write_irq(&self) -> IrqSafe<WriteGuard<'_, T>> {
+        let interrupts_were_enabled = disable_interrupts();
+        IrqSafe::new(self.write(), interrupts_were_enabled)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct ReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T: ?Sized> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+        sched::preempt_enable();
+    }
+}
+
+pub struct WriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T: ?Sized> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.released();
+        // a writer that started waiting meanwhile keeps its flag
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        sched::preempt_enable();
+    }
+}
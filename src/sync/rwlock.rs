@@ -0,0 +1,221 @@
+//! A reader-writer lock that sleeps rather than spins: any number of
+//! readers, or one writer.
+
+#![allow(dead_code)]
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+use crate::sched;
+
+use super::{enter_level, exit_level, spin::SpinLock, try_enter_level, wait_queue::WaitQueue, LockLevel};
+
+struct RwState {
+    readers: usize,
+    writer: bool,
+    /// While any writer waits, no new readers are let in, so a steady
+    /// stream of them can't keep writers out forever.
+    writers_waiting: usize,
+}
+
+pub struct RwLock<T: ?Sized> {
+    state: SpinLock<RwState>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    level: LockLevel,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_level(value, LockLevel::NONE)
+    }
+
+    /// A lock that's checked against the lock order at [`level`], for
+    /// readers and writers alike.
+    pub const fn with_level(value: T, level: LockLevel) -> Self {
+        assert!(level.0 <= LockLevel::MAX.0);
+        Self {
+            state: SpinLock::new(RwState { readers: 0, writer: false, writers_waiting: 0 }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            level,
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn try_read_raw(&self) -> bool {
+        let mut state = self.state.lock_irq();
+        if state.writer || state.writers_waiting > 0 {
+            return false;
+        }
+
+        state.readers += 1;
+        true
+    }
+
+    fn try_write_raw(&self) -> bool {
+        let mut state = self.state.lock_irq();
+        if state.writer || state.readers > 0 {
+            return false;
+        }
+
+        state.writer = true;
+        true
+    }
+
+    /// Sleeps until there's no writer, waiting or holding the lock, and
+    /// takes a read lock.
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        sched::might_sleep();
+        enter_level(self.level);
+        self.readers.wait_until(|| self.try_read_raw());
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Like [`RwLock::read`], giving up after [`timeout`].
+    #[track_caller]
+    pub fn read_timeout(&self, timeout: Duration) -> Option<RwLockReadGuard<'_, T>> {
+        sched::might_sleep();
+        enter_level(self.level);
+        if !self.readers.wait_until_timeout(|| self.try_read_raw(), timeout) {
+            exit_level(self.level);
+            return None;
+        }
+
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// Returns [`None`] if there's a writer, waiting or holding the lock.
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if !self.try_read_raw() {
+            return None;
+        }
+
+        try_enter_level(self.level);
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// Sleeps until nobody holds the lock and takes it for writing.
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        sched::might_sleep();
+        enter_level(self.level);
+        self.state.lock_irq().writers_waiting += 1;
+        self.writers.wait_until(|| self.try_write_raw());
+        self.state.lock_irq().writers_waiting -= 1;
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Like [`RwLock::write`], giving up after [`timeout`].
+    #[track_caller]
+    pub fn write_timeout(&self, timeout: Duration) -> Option<RwLockWriteGuard<'_, T>> {
+        sched::might_sleep();
+        enter_level(self.level);
+        self.state.lock_irq().writers_waiting += 1;
+        let taken = self.writers.wait_until_timeout(|| self.try_write_raw(), timeout);
+        self.state.lock_irq().writers_waiting -= 1;
+        if !taken {
+            exit_level(self.level);
+            // readers held back for this writer can come in now
+            self.wake_next();
+            return None;
+        }
+
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Returns [`None`] if anybody holds the lock.
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if !self.try_write_raw() {
+            return None;
+        }
+
+        try_enter_level(self.level);
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Lets the next waiters in once the lock is free: a writer if there's
+    /// one waiting, otherwise every reader.
+    fn wake_next(&self) {
+        let writers_waiting = {
+            let state = self.state.lock_irq();
+            if state.writer || state.readers > 0 {
+                return;
+            }
+
+            state.writers_waiting > 0
+        };
+
+        if writers_waiting {
+            self.writers.wake_one();
+        } else {
+            self.readers.wake_all();
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        exit_level(self.lock.level);
+        self.lock.state.lock_irq().readers -= 1;
+        self.lock.wake_next();
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        exit_level(self.lock.level);
+        self.lock.state.lock_irq().writer = false;
+        self.lock.wake_next();
+    }
+}
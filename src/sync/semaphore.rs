@@ -0,0 +1,51 @@
+//! A counting semaphore, for handing out a limited number of something.
+
+#![allow(dead_code)]
+
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use super::wait_queue::WaitQueue;
+
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self { count: AtomicUsize::new(count), waiters: WaitQueue::new() }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Takes one if there are any left, without sleeping.
+    pub fn try_down(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Sleeps until there's one left and takes it.
+    #[track_caller]
+    pub fn down(&self) {
+        self.waiters.wait_until(|| self.try_down());
+    }
+
+    /// Like [`Semaphore::down`], giving up after [`timeout`]. Returns
+    /// whether one was taken.
+    #[track_caller]
+    pub fn down_timeout(&self, timeout: Duration) -> bool {
+        self.waiters.wait_until_timeout(|| self.try_down(), timeout)
+    }
+
+    /// Gives one back, waking a task waiting for it.
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
@@ -0,0 +1,188 @@
+//! Mutual exclusion by spinning: [`SpinLock`], which whoever gets there
+//! first takes, and [`TicketLock`], which is taken in the order CPUs asked
+//! for it. Either keeps the holder from being preempted until it's
+//! released.
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use crate::sched;
+
+use super::{disable_interrupts, while_spinning, IrqSafe, Owner};
+
+/// The bare lock under a [`Lock`], without data.
+pub trait RawLock {
+    const INIT: Self;
+
+    /// Takes the lock, calling [`wait`] on every spin.
+    fn lock(&self, wait: impl FnMut());
+    fn try_lock(&self) -> bool;
+
+    /// Safety: the lock must be held by the caller.
+    unsafe fn unlock(&self);
+}
+
+pub struct RawSpinLock {
+    locked: AtomicBool,
+}
+
+impl RawLock for RawSpinLock {
+    const INIT: Self = Self { locked: AtomicBool::new(false) };
+
+    fn lock(&self, mut wait: impl FnMut()) {
+        while !self.try_lock() {
+            // spin on a plain load, so the cache line isn't bounced around
+            // by writes until it looks free
+            while self.locked.load(Ordering::Relaxed) {
+                wait();
+                spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Hands out tickets and serves them in order, so nobody waits forever
+/// while others keep cutting in.
+#[allow(dead_code)]
+pub struct RawTicketLock {
+    next: AtomicU32,
+    serving: AtomicU32,
+}
+
+impl RawLock for RawTicketLock {
+    const INIT: Self = Self { next: AtomicU32::new(0), serving: AtomicU32::new(0) };
+
+    fn lock(&self, mut wait: impl FnMut()) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            wait();
+            spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        // only the holder writes this
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.serving.store(serving.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// Data behind a spinning lock of kind [`R`].
+pub struct Lock<R: RawLock, T: ?Sized> {
+    raw: R,
+    owner: Owner,
+    data: UnsafeCell<T>,
+}
+
+pub type SpinLock<T> = Lock<RawSpinLock, T>;
+#[allow(dead_code)]
+pub type TicketLock<T> = Lock<RawTicketLock, T>;
+
+unsafe impl<R: RawLock, T: ?Sized + Send> Send for Lock<R, T> {}
+unsafe impl<R: RawLock, T: ?Sized + Send> Sync for Lock<R, T> {}
+
+impl<R: RawLock, T> Lock<R, T> {
+    pub const fn new(value: T) -> Self {
+        Self { raw: R::INIT, owner: Owner::new(), data: UnsafeCell::new(value) }
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<R: RawLock, T: ?Sized> Lock<R, T> {
+    /// Spins until the lock is free and takes it.
+    #[track_caller]
+    pub fn lock(&self) -> LockGuard<'_, R, T> {
+        self.owner.check();
+        sched::preempt_disable();
+        let mut spins = 0;
+        self.raw.lock(|| {
+            self.owner.spin(&mut spins);
+            while_spinning();
+        });
+        self.owner.acquired();
+        LockGuard { lock: self }
+    }
+
+    /// Returns [`None`] if the lock is held.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<LockGuard<'_, R, T>> {
+        sched::preempt_disable();
+        if !self.raw.try_lock() {
+            sched::preempt_enable();
+            return None;
+        }
+
+        self.owner.acquired();
+        Some(LockGuard { lock: self })
+    }
+
+    /// Like [`Lock::lock`], with interrupts off until the guard is dropped.
+    #[track_caller]
+    pub fn lock_irq(&self) -> IrqSafe<LockGuard<'_, R, T>> {
+        let interrupts_were_enabled = disable_interrupts();
+        IrqSafe::new(self.lock(), interrupts_were_enabled)
+    }
+
+    /// Unlocks without a guard, for when the code that took the lock can't
+    /// hand its guard over, like across a thread switch.
+    ///
+    /// Safety: the lock must be held, and its guard forgotten.
+    pub unsafe fn force_unlock(&self) {
+        self.owner.released();
+        unsafe { self.raw.unlock() };
+        sched::preempt_enable();
+    }
+
+    /// No locking needed, since nobody else can have a reference.
+    #[allow(dead_code)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct LockGuard<'a, R: RawLock, T: ?Sized> {
+    lock: &'a Lock<R, T>,
+}
+
+impl<R: RawLock, T: ?Sized> Deref for LockGuard<'_, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<R: RawLock, T: ?Sized> DerefMut for LockGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<R: RawLock, T: ?Sized> Drop for LockGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock() };
+    }
+}
@@ -0,0 +1,169 @@
+//! Queues of tasks waiting for something to happen, which everything else
+//! that sleeps is built on.
+
+use core::{
+    cell::Cell,
+    ptr::null,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{clock, sched::{self, Task}};
+
+use super::spin::SpinLock;
+
+/// A blocked task, on its own stack while it waits. Only touched through
+/// the queue's lock, which it's removed under before it's gone.
+struct Waiter {
+    task: Task,
+    woken: AtomicBool,
+    next: Cell<*const Waiter>,
+}
+
+/// Waiters in the order they arrived.
+struct WaitList {
+    head: *const Waiter,
+    tail: *const Waiter,
+}
+
+/// The list only points at waiters that are blocked on it.
+unsafe impl Send for WaitList {}
+
+impl WaitList {
+    fn push(&mut self, waiter: &Waiter) {
+        waiter.next.set(null());
+        match unsafe { self.tail.as_ref() } {
+            Some(tail) => tail.next.set(waiter),
+            None => self.head = waiter,
+        }
+
+        self.tail = waiter;
+    }
+
+    fn pop(&mut self) -> Option<&Waiter> {
+        let head = unsafe { self.head.as_ref() }?;
+        self.head = head.next.get();
+        if self.head.is_null() {
+            self.tail = null();
+        }
+
+        Some(head)
+    }
+
+    /// Removes [`waiter`] if it's still queued.
+    fn remove(&mut self, waiter: &Waiter) {
+        let mut prev: *const Waiter = null();
+        let mut cur = self.head;
+        while let Some(w) = unsafe { cur.as_ref() } {
+            if core::ptr::eq(w, waiter) {
+                match unsafe { prev.as_ref() } {
+                    Some(prev) => prev.next.set(w.next.get()),
+                    None => self.head = w.next.get(),
+                }
+
+                if core::ptr::eq(self.tail, w) {
+                    self.tail = prev;
+                }
+
+                return;
+            }
+
+            prev = cur;
+            cur = w.next.get();
+        }
+    }
+}
+
+pub struct WaitQueue {
+    waiters: SpinLock<WaitList>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { waiters: SpinLock::new(WaitList { head: null(), tail: null() }) }
+    }
+
+    /// Sleeps until [`condition`] returns true, checking it once first and
+    /// again each time the task is woken.
+    #[track_caller]
+    pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+        self.wait_until_deadline(condition, None);
+    }
+
+    /// Like [`WaitQueue::wait_until`], giving up after [`timeout`]. Returns
+    /// whether [`condition`] came true.
+    #[track_caller]
+    pub fn wait_until_timeout(&self, condition: impl FnMut() -> bool, timeout: Duration) -> bool {
+        self.wait_until_deadline(condition, Some(clock::now() + timeout))
+    }
+
+    #[track_caller]
+    fn wait_until_deadline(&self, mut condition: impl FnMut() -> bool, deadline: Option<Duration>) -> bool {
+        sched::might_sleep();
+        loop {
+            if condition() {
+                return true;
+            }
+
+            let waiter = Waiter { task: sched::current(), woken: AtomicBool::new(false), next: Cell::new(null()) };
+            self.waiters.lock_irq().push(&waiter);
+
+            // checked again once queued, so that a wake between the first
+            // check and queueing isn't lost
+            if condition() {
+                self.waiters.lock_irq().remove(&waiter);
+                return true;
+            }
+
+            let woken = sched::block(&waiter.woken, deadline);
+            self.waiters.lock_irq().remove(&waiter);
+            if !woken {
+                if condition() {
+                    return true;
+                }
+
+                // a waker could have picked this waiter after it timed out,
+                // so pass the wake on rather than lose it
+                if waiter.woken.load(Ordering::Acquire) {
+                    self.wake_one();
+                }
+
+                return false;
+            }
+        }
+    }
+
+    /// Wakes the longest waiting task. Returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock_irq();
+        match waiters.pop() {
+            Some(waiter) => {
+                wake(waiter);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Wakes every task waiting now, but not ones that queue up again
+    /// meanwhile. Returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock_irq();
+        let mut count = 0;
+        while let Some(waiter) = waiters.pop() {
+            wake(waiter);
+            count += 1;
+        }
+
+        count
+    }
+}
+
+/// Wakes a waiter just taken off its queue, with the queue still locked:
+/// once woken it can return and take itself away, so it can't be touched
+/// after the lock is dropped.
+fn wake(waiter: &Waiter) {
+    let task = waiter.task;
+    waiter.woken.store(true, Ordering::Release);
+    sched::wake(task);
+}
@@ -0,0 +1,423 @@
+//! Terminal line discipline, sitting between raw console input and whoever
+//! reads it, in the spirit of POSIX termios.
+
+use core::fmt;
+
+use bitflags::bitflags;
+
+use crate::{
+    arch::x86::{serial::Com, vga::{VgaColor, VgaWriter}},
+    ringbuf::RingBuffer,
+    sync::spin::SpinLock,
+};
+
+const LINE_MAX: usize = 256;
+const READY_MAX: usize = 1024;
+const LINES_MAX: usize = 32;
+const TAB_STOP: usize = 8;
+
+/// Where a [`LineDiscipline`] sends echo and output.
+pub trait TtyOutput {
+    fn put_char(&mut self, c: char);
+}
+
+impl TtyOutput for VgaWriter {
+    fn put_char(&mut self, c: char) {
+        let mut buf = [0u8; 4];
+        self.puts(&*c.encode_utf8(&mut buf), VgaColor::WHITE);
+    }
+}
+
+impl<const BASE: u16> TtyOutput for Com<BASE> {
+    fn put_char(&mut self, c: char) {
+        let mut buf = [0u8; 4];
+        for b in c.encode_utf8(&mut buf).bytes() {
+            self.putc(b);
+        }
+    }
+}
+
+/// Takes the lock for each character, so other output can get in between.
+impl<T: TtyOutput> TtyOutput for &SpinLock<T> {
+    fn put_char(&mut self, c: char) {
+        self.lock_irq().put_char(c);
+    }
+}
+
+/// Mirrors output to two devices, e.g. the VGA console and a serial port.
+impl<A: TtyOutput, B: TtyOutput> TtyOutput for (A, B) {
+    fn put_char(&mut self, c: char) {
+        self.0.put_char(c);
+        self.1.put_char(c);
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct TtyFlags: u16 {
+        /// Hand input over a line at a time, with editing (`ICANON`).
+        const CANONICAL = 1 << 0;
+        const ECHO = 1 << 1;
+        /// Echo control characters as `^X` (`ECHOCTL`).
+        const ECHO_CONTROL = 1 << 2;
+        /// Rub out erased characters on screen rather than echoing the
+        /// erase character (`ECHOE`).
+        const ECHO_ERASE = 1 << 3;
+        /// Turn the interrupt and suspend characters into signals (`ISIG`).
+        const SIGNALS = 1 << 4;
+        /// Translate `\r` to `\n` on input (`ICRNL`).
+        const CR_TO_NL = 1 << 5;
+        /// Translate `\n` to `\r\n` on output (`ONLCR`).
+        const NL_TO_CRNL = 1 << 6;
+
+        const COOKED = Self::CANONICAL.bits() | Self::ECHO.bits() | Self::ECHO_CONTROL.bits()
+            | Self::ECHO_ERASE.bits() | Self::SIGNALS.bits() | Self::CR_TO_NL.bits()
+            | Self::NL_TO_CRNL.bits();
+        const RAW = Self::NL_TO_CRNL.bits();
+    }
+}
+
+/// Characters with special meaning on input. [`None`] disables one.
+#[derive(Copy, Clone, Debug)]
+pub struct SpecialChars {
+    /// Erase the previous character (`VERASE`).
+    pub erase: Option<char>,
+    /// Erase the previous word (`VWERASE`).
+    pub erase_word: Option<char>,
+    /// Erase the whole line (`VKILL`).
+    pub kill: Option<char>,
+    /// End of file, or push the line without a newline (`VEOF`).
+    pub eof: Option<char>,
+    pub interrupt: Option<char>,
+    pub suspend: Option<char>,
+}
+
+impl SpecialChars {
+    pub const DEFAULT: Self = Self {
+        erase: Some('\x08'),
+        erase_word: Some('\x17'),
+        kill: Some('\x15'),
+        eof: Some('\x04'),
+        interrupt: Some('\x03'),
+        suspend: Some('\x1A'),
+    };
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Signal {
+    INTERRUPT,
+    SUSPEND,
+}
+
+pub struct LineDiscipline {
+    flags: TtyFlags,
+    chars: SpecialChars,
+    /// Line being edited in canonical mode.
+    line: [char; LINE_MAX],
+    /// How many columns each character in [`line`] took up when echoed.
+    widths: [u8; LINE_MAX],
+    len: usize,
+    /// Output column, for tab stops and erasing tabs.
+    column: usize,
+    /// Input ready to be read, UTF-8 encoded.
+    ready: RingBuffer<u8, READY_MAX>,
+    /// Lengths of the lines in [`ready`], in canonical mode. A zero-length
+    /// line is end of file.
+    lines: RingBuffer<u16, LINES_MAX>,
+    /// What's left of the line currently being read.
+    reading: Option<u16>,
+    signal: Option<Signal>,
+}
+
+impl LineDiscipline {
+    pub const fn new() -> Self {
+        Self {
+            flags: TtyFlags::COOKED,
+            chars: SpecialChars::DEFAULT,
+            line: ['\0'; LINE_MAX],
+            widths: [0; LINE_MAX],
+            len: 0,
+            column: 0,
+            ready: RingBuffer::new(),
+            lines: RingBuffer::new(),
+            reading: None,
+            signal: None,
+        }
+    }
+
+    pub fn flags(&self) -> TtyFlags {
+        self.flags
+    }
+
+    /// Switching out of canonical mode hands over the line being edited
+    /// as-is, and switching into it turns unread raw input into a line.
+    pub fn set_flags(&mut self, flags: TtyFlags) {
+        let was_canonical = self.flags.contains(TtyFlags::CANONICAL);
+        let canonical = flags.contains(TtyFlags::CANONICAL);
+
+        if was_canonical && !canonical {
+            self.commit();
+            self.lines = RingBuffer::new();
+            self.reading = None;
+        } else if !was_canonical && canonical && !self.ready.is_empty() {
+            // emptied on the way out of canonical mode, so there's room
+            let pushed = self.lines.push(self.ready.len() as u16);
+            debug_assert!(pushed);
+        }
+
+        self.flags = flags;
+    }
+
+    pub fn special_chars(&self) -> SpecialChars {
+        self.chars
+    }
+
+    pub fn set_special_chars(&mut self, chars: SpecialChars) {
+        self.chars = chars;
+    }
+
+    /// Returns the last signal generated by input, if any, and clears it.
+    pub fn take_signal(&mut self) -> Option<Signal> {
+        self.signal.take()
+    }
+
+    /// Writes [`s`] with output processing applied.
+    pub fn write(&mut self, s: &str, out: &mut impl TtyOutput) {
+        for c in s.chars() {
+            self.output(c, out);
+        }
+    }
+
+    /// Something to [`write!`] to, with output processing applied.
+    pub fn writer<'a, T: TtyOutput>(&'a mut self, out: &'a mut T) -> TtyWriter<'a, T> {
+        TtyWriter { tty: self, out }
+    }
+
+    fn output(&mut self, c: char, out: &mut impl TtyOutput) {
+        match c {
+            '\n' => {
+                if self.flags.contains(TtyFlags::NL_TO_CRNL) {
+                    out.put_char('\r');
+                }
+
+                self.column = 0;
+            },
+            '\r' => self.column = 0,
+            '\x08' => self.column = self.column.saturating_sub(1),
+            _ if c.is_control() => {},
+            _ => self.column += 1,
+        }
+
+        out.put_char(c);
+    }
+
+    /// Echoes [`c`] and returns how many columns it took up.
+    fn echo(&mut self, c: char, out: &mut impl TtyOutput) -> u8 {
+        if !self.flags.contains(TtyFlags::ECHO) {
+            return 0;
+        }
+
+        let start = self.column;
+        match c {
+            '\t' => {
+                // tabs are expanded here so that erasing one knows its width
+                for _ in 0..TAB_STOP - self.column % TAB_STOP {
+                    self.output(' ', out);
+                }
+            },
+            '\n' => self.output('\n', out),
+            _ if c.is_ascii_control() && self.flags.contains(TtyFlags::ECHO_CONTROL) => {
+                self.output('^', out);
+                self.output((c as u8 ^ 0x40) as char, out);
+            },
+            _ => self.output(c, out),
+        }
+
+        self.column.saturating_sub(start) as u8
+    }
+
+    fn rub_out(&mut self, width: u8, out: &mut impl TtyOutput) {
+        if !self.flags.contains(TtyFlags::ECHO) {
+            return;
+        }
+
+        if !self.flags.contains(TtyFlags::ECHO_ERASE) {
+            if let Some(erase) = self.chars.erase {
+                self.echo(erase, out);
+            }
+
+            return;
+        }
+
+        for _ in 0..width {
+            self.output('\x08', out);
+            self.output(' ', out);
+            self.output('\x08', out);
+        }
+    }
+
+    fn erase_char(&mut self, out: &mut impl TtyOutput) -> Option<char> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        self.rub_out(self.widths[self.len], out);
+        Some(self.line[self.len])
+    }
+
+    fn erase_word(&mut self, out: &mut impl TtyOutput) {
+        while self.len > 0 && self.line[self.len - 1].is_whitespace() {
+            self.erase_char(out);
+        }
+
+        while self.len > 0 && !self.line[self.len - 1].is_whitespace() {
+            self.erase_char(out);
+        }
+    }
+
+    /// Moves the edited line to [`ready`]. Input that doesn't fit is lost,
+    /// though a cut-short line keeps its newline. In canonical mode a line
+    /// is dropped whole if there's no room to record where it ends, or if
+    /// none of it fits.
+    fn commit(&mut self) {
+        let canonical = self.flags.contains(TtyFlags::CANONICAL);
+        if canonical && self.lines.len() == LINES_MAX {
+            self.len = 0;
+            return;
+        }
+
+        let newline = self.len > 0 && self.line[self.len - 1] == '\n';
+        let limit = READY_MAX - usize::from(newline);
+        let mut buf = [0u8; 4];
+        let mut committed = 0;
+        for &c in &self.line[..self.len - usize::from(newline)] {
+            let bytes = c.encode_utf8(&mut buf).as_bytes();
+            if self.ready.len() + bytes.len() > limit {
+                break;
+            }
+
+            for &b in bytes {
+                self.ready.push(b);
+            }
+
+            committed += bytes.len();
+        }
+
+        if newline && self.ready.len() < READY_MAX {
+            self.ready.push(b'\n');
+            committed += 1;
+        }
+
+        // an empty line is end of file, so one that didn't fit at all is
+        // left out rather than recorded as empty
+        if canonical && (committed > 0 || self.len == 0) {
+            self.lines.push(committed as u16);
+        }
+
+        self.len = 0;
+    }
+
+    /// Processes one character of input, echoing to [`out`].
+    pub fn receive(&mut self, c: char, out: &mut impl TtyOutput) {
+        let c = if c == '\r' && self.flags.contains(TtyFlags::CR_TO_NL) { '\n' } else { c };
+        let is = |special: Option<char>| special == Some(c);
+
+        if self.flags.contains(TtyFlags::SIGNALS) && (is(self.chars.interrupt) || is(self.chars.suspend)) {
+            self.signal = Some(if is(self.chars.interrupt) { Signal::INTERRUPT } else { Signal::SUSPEND });
+            self.len = 0;
+            self.echo(c, out);
+            self.echo('\n', out);
+            return;
+        }
+
+        if !self.flags.contains(TtyFlags::CANONICAL) {
+            let mut buf = [0u8; 4];
+            let bytes = c.encode_utf8(&mut buf).as_bytes();
+            if self.ready.len() + bytes.len() <= READY_MAX {
+                for &b in bytes {
+                    self.ready.push(b);
+                }
+            }
+
+            self.echo(c, out);
+            return;
+        }
+
+        if is(self.chars.erase) {
+            self.erase_char(out);
+        } else if is(self.chars.erase_word) {
+            self.erase_word(out);
+        } else if is(self.chars.kill) {
+            while self.erase_char(out).is_some() {}
+        } else if is(self.chars.eof) {
+            // on an empty line this commits nothing, which reads as EOF
+            self.commit();
+        } else if c == '\n' {
+            self.line[self.len] = c;
+            self.len += 1;
+            self.echo(c, out);
+            self.commit();
+        } else if self.len < LINE_MAX - 1 {
+            // the last slot is kept for the newline
+            self.line[self.len] = c;
+            self.widths[self.len] = self.echo(c, out);
+            self.len += 1;
+        }
+    }
+
+    /// Copies input into [`buf`]. In canonical mode only complete lines are
+    /// returned, and never more than one line per call. Returns [`None`] if
+    /// there's nothing to read yet, or `Some(0)` at end of file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let limit = if self.flags.contains(TtyFlags::CANONICAL) {
+            let remaining = match self.reading {
+                Some(r) => r,
+                None => self.lines.pop()?,
+            };
+
+            if remaining == 0 {
+                self.reading = None;
+                return Some(0);
+            }
+
+            remaining as usize
+        } else {
+            if self.ready.is_empty() {
+                return None;
+            }
+
+            usize::MAX
+        };
+
+        let mut n = 0;
+        while n < buf.len().min(limit) {
+            let Some(b) = self.ready.pop() else {
+                break;
+            };
+
+            buf[n] = b;
+            n += 1;
+        }
+
+        if self.flags.contains(TtyFlags::CANONICAL) {
+            let left = limit - n;
+            self.reading = if left > 0 { Some(left as u16) } else { None };
+        }
+
+        Some(n)
+    }
+}
+
+/// Formatted output through a [`LineDiscipline`], from [`LineDiscipline::writer`].
+pub struct TtyWriter<'a, T: TtyOutput> {
+    tty: &'a mut LineDiscipline,
+    out: &'a mut T,
+}
+
+impl<T: TtyOutput> fmt::Write for TtyWriter<'_, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.tty.write(s, self.out);
+        Ok(())
+    }
+}